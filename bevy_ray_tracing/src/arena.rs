@@ -0,0 +1,317 @@
+use crate::displacement::DisplacementKey;
+
+use bevy::{
+    asset::UntypedAssetId,
+    ecs::{entity::Entity, system::Resource},
+    render::{
+        render_resource::{
+            encase::{self, internal::WriteInto},
+            BindingResource, Buffer, BufferDescriptor, BufferUsages, CommandEncoderDescriptor,
+            ShaderSize, ShaderType,
+        },
+        renderer::{RenderDevice, RenderQueue},
+    },
+    utils::{HashMap, HashSet},
+};
+use std::{marker::PhantomData, ops::Range};
+
+/// A storage buffer kept across frames, written a slice at a time
+///
+/// Writes are queued until [`write_buffer`](Self::write_buffer), which grows
+/// the buffer first when they reach past its end, copying what it held.
+pub struct GpuArena<T> {
+    label: &'static str,
+    buffer: Option<Buffer>,
+    /// Elements the buffer needs to hold every write so far
+    len: u64,
+    writes: Vec<(u64, Vec<u8>)>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ShaderType + ShaderSize + WriteInto> GpuArena<T> {
+    pub fn new(label: &'static str) -> Self {
+        Self {
+            label,
+            buffer: None,
+            len: 0,
+            writes: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Queues `data` to be written from element `offset` on
+    pub fn write(&mut self, offset: u32, data: &[T]) {
+        if data.is_empty() {
+            return;
+        }
+
+        let mut bytes = encase::StorageBuffer::new(Vec::new());
+        bytes.write(data).unwrap();
+        self.writes
+            .push((offset as u64 * T::SHADER_SIZE.get(), bytes.into_inner()));
+        self.len = self.len.max(offset as u64 + data.len() as u64);
+    }
+
+    pub fn write_buffer(&mut self, device: &RenderDevice, queue: &RenderQueue) {
+        // Never empty, so there's always something to bind
+        let size = self.len.max(1) * T::SHADER_SIZE.get();
+        let capacity = self.buffer.as_ref().map_or(0, |buffer| buffer.size());
+        if capacity < size {
+            let buffer = device.create_buffer(&BufferDescriptor {
+                label: Some(self.label),
+                size: size.max(capacity * 2),
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            });
+
+            // Submitted now so the copy lands before this frame's writes
+            if let Some(old) = &self.buffer {
+                let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+                    label: Some(self.label),
+                });
+                encoder.copy_buffer_to_buffer(old, 0, &buffer, 0, capacity);
+                queue.submit([encoder.finish()]);
+            }
+            self.buffer = Some(buffer);
+        }
+
+        let buffer = self.buffer.as_ref().unwrap();
+        for (offset, bytes) in self.writes.drain(..) {
+            queue.write_buffer(buffer, offset, &bytes);
+        }
+    }
+
+    pub fn binding(&self) -> Option<BindingResource<'_>> {
+        self.buffer
+            .as_ref()
+            .map(|buffer| buffer.as_entire_binding())
+    }
+}
+
+/// Hands out ranges of an arena, reusing freed ones first
+#[derive(Default)]
+pub struct RangeAllocator {
+    /// Sorted, with no two ranges touching
+    free: Vec<Range<u32>>,
+    end: u32,
+}
+
+impl RangeAllocator {
+    pub fn alloc(&mut self, len: u32) -> u32 {
+        if len == 0 {
+            return 0;
+        }
+
+        let Some(i) = self.free.iter().position(|range| range.len() as u32 >= len) else {
+            self.end += len;
+            return self.end - len;
+        };
+
+        let start = self.free[i].start;
+        self.free[i].start += len;
+        if self.free[i].is_empty() {
+            self.free.remove(i);
+        }
+        start
+    }
+
+    pub fn free(&mut self, start: u32, len: u32) {
+        if len == 0 {
+            return;
+        }
+
+        let mut range = start..start + len;
+        let i = self.free.partition_point(|free| free.end < range.start);
+        let mut j = i;
+        while j < self.free.len() && self.free[j].start <= range.end {
+            range = range.start.min(self.free[j].start)..range.end.max(self.free[j].end);
+            j += 1;
+        }
+
+        // The end is handed out again by growing, not from the free list
+        if range.end == self.end {
+            self.end = range.start;
+            self.free.drain(i..j);
+        } else {
+            self.free.splice(i..j, [range]);
+        }
+    }
+}
+
+/// What a region of the BLAS buffers was uploaded from
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlasKey {
+    Mesh(UntypedAssetId),
+    Displaced(DisplacementKey),
+    /// The skinning pass deforms each entity's own copy of its mesh
+    Skinned(Entity, UntypedAssetId),
+    Instances(Entity),
+    Curves(Entity),
+    PointCloud(Entity),
+    /// A heightfield's image, which has no nodes
+    Heightfield(UntypedAssetId),
+}
+
+impl BlasKey {
+    fn uses(&self, id: UntypedAssetId) -> bool {
+        match self {
+            BlasKey::Mesh(mesh) | BlasKey::Skinned(_, mesh) | BlasKey::Heightfield(mesh) => {
+                *mesh == id
+            }
+            BlasKey::Displaced(key) => key.mesh == Some(id) || key.depth_map == id,
+            _ => false,
+        }
+    }
+}
+
+/// Where a BLAS's nodes and primitives start, both relative to these
+#[derive(Clone, Copy, Default)]
+pub struct BlasRegion {
+    pub first_node: u32,
+    pub node_count: u32,
+    pub first_primitive: u32,
+    pub primitive_count: u32,
+}
+
+/// Regions of the long-lived BLAS buffers, so cached BLASes are only uploaded
+/// when they're built
+///
+/// Regions not used for a frame are freed at its end.
+#[derive(Resource, Default)]
+pub struct BlasRegions {
+    nodes: RangeAllocator,
+    triangles: RangeAllocator,
+    instances: RangeAllocator,
+    curve_segments: RangeAllocator,
+    points: RangeAllocator,
+    heights: RangeAllocator,
+    map: HashMap<BlasKey, BlasRegion>,
+    used: HashSet<BlasKey>,
+}
+
+impl BlasRegions {
+    pub fn get(&mut self, key: BlasKey) -> Option<BlasRegion> {
+        let region = *self.map.get(&key)?;
+        self.used.insert(key);
+        Some(region)
+    }
+
+    /// A new region for `key`, which its caller uploads to, replacing its old one
+    pub fn insert(&mut self, key: BlasKey, node_count: u32, primitive_count: u32) -> BlasRegion {
+        self.remove(key);
+
+        let region = BlasRegion {
+            first_node: self.nodes.alloc(node_count),
+            node_count,
+            first_primitive: self.primitives(key).alloc(primitive_count),
+            primitive_count,
+        };
+        self.map.insert(key, region);
+        self.used.insert(key);
+        region
+    }
+
+    /// The key's region, or a new one handed to `upload` when it has none or
+    /// its BLAS was `built` again
+    pub fn get_or_upload(
+        &mut self,
+        key: BlasKey,
+        built: bool,
+        node_count: usize,
+        primitive_count: usize,
+        upload: impl FnOnce(BlasRegion),
+    ) -> BlasRegion {
+        if let Some(region) = self.get(key).filter(|_| !built) {
+            return region;
+        }
+
+        let region = self.insert(key, node_count as u32, primitive_count as u32);
+        upload(region);
+        region
+    }
+
+    pub fn remove(&mut self, key: BlasKey) {
+        if let Some(region) = self.map.remove(&key) {
+            self.nodes.free(region.first_node, region.node_count);
+            self.primitives(key)
+                .free(region.first_primitive, region.primitive_count);
+        }
+    }
+
+    /// Removes every region built from the asset
+    pub fn remove_asset(&mut self, id: UntypedAssetId) {
+        let keys: Vec<BlasKey> = self
+            .map
+            .keys()
+            .filter(|key| key.uses(id))
+            .copied()
+            .collect();
+        for key in keys {
+            self.remove(key);
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        let unused: Vec<BlasKey> = self
+            .map
+            .keys()
+            .filter(|key| !self.used.contains(*key))
+            .copied()
+            .collect();
+        for key in unused {
+            self.remove(key);
+        }
+        self.used.clear();
+    }
+
+    fn primitives(&mut self, key: BlasKey) -> &mut RangeAllocator {
+        match key {
+            BlasKey::Mesh(_) | BlasKey::Displaced(_) | BlasKey::Skinned(..) => &mut self.triangles,
+            BlasKey::Instances(_) => &mut self.instances,
+            BlasKey::Curves(_) => &mut self.curve_segments,
+            BlasKey::PointCloud(_) => &mut self.points,
+            BlasKey::Heightfield(_) => &mut self.heights,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_ranges_are_reused_and_merged() {
+        let mut allocator = RangeAllocator::default();
+        let a = allocator.alloc(4);
+        let b = allocator.alloc(4);
+        let c = allocator.alloc(4);
+        assert_eq!((a, b, c), (0, 4, 8));
+
+        // Neighbouring frees merge into one range big enough for both
+        allocator.free(a, 4);
+        allocator.free(b, 4);
+        assert_eq!(allocator.alloc(6), 0);
+        assert_eq!(allocator.alloc(2), 6);
+        assert_eq!(allocator.alloc(1), 12);
+
+        // Freeing the tail shrinks the end instead, which growing reuses
+        allocator.free(12, 1);
+        allocator.free(c, 4);
+        assert_eq!(allocator.alloc(8), 8);
+    }
+
+    #[test]
+    fn unused_regions_are_freed_at_the_end_of_a_frame() {
+        let mut regions = BlasRegions::default();
+        let kept = BlasKey::Instances(Entity::from_raw(0));
+        let dropped = BlasKey::Instances(Entity::from_raw(1));
+        regions.insert(kept, 3, 0);
+        regions.insert(dropped, 5, 0);
+        regions.end_frame();
+
+        assert_eq!(regions.get(kept).unwrap().first_node, 0);
+        regions.end_frame();
+        assert!(regions.get(dropped).is_none());
+        assert_eq!(regions.insert(dropped, 5, 0).first_node, 3);
+    }
+}
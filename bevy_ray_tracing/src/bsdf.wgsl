@@ -0,0 +1,210 @@
+#define_import_path bevy_ray_tracing::bsdf
+
+#import bevy_ray_tracing::types::{Material, BsdfSample, BsdfLobes, PI, EPSILON, LOBE_DIFFUSE, LOBE_DIFFUSE_TRANSMISSION, LOBE_SPECULAR, LOBE_SPECULAR_TRANSMISSION}
+
+// A surface scatters light through one of four lobes: diffuse reflection and
+// transmission, GGX specular reflection, and smooth specular transmission.
+// `v` points away from the surface towards where the light goes, `n` is on
+// its side and `eta` is the ratio of indices of refraction across the surface.
+
+// How much each lobe scatters and how often to pick it, Fresnel reflection
+// takes its share first and the dielectric lobes split what's left
+fn bsdf_lobes(material: Material, base_color: vec3<f32>, n_dot_v: f32) -> BsdfLobes {
+    var lobes: BsdfLobes;
+    lobes.specular_color = specular_f0(material, base_color);
+    let fresnel = fresnel_schlick(lobes.specular_color, max(n_dot_v, 0.0));
+    let dielectric = (1.0 - material.metallic) * (vec3<f32>(1.0) - fresnel) * base_color;
+    lobes.specular_transmission = dielectric * material.specular_transmission;
+    let diffuse = dielectric * (1.0 - material.specular_transmission);
+    lobes.diffuse = diffuse * (1.0 - material.diffuse_transmission);
+    lobes.diffuse_transmission = diffuse * material.diffuse_transmission;
+
+    let weights = vec4<f32>(
+        luminance(lobes.diffuse),
+        luminance(lobes.diffuse_transmission),
+        luminance(fresnel),
+        luminance(lobes.specular_transmission),
+    );
+    let total = weights.x + weights.y + weights.z + weights.w;
+    lobes.probabilities = select(vec4<f32>(0.0, 0.0, 1.0, 0.0), weights / total, total > EPSILON);
+    return lobes;
+}
+
+// Picks a lobe with `u.x` and a direction from it with `u.yz`
+//
+// Smooth transmission can only be sampled, the other lobes' value and pdf are
+// those of every lobe that could have chosen the direction.
+fn sample_bsdf(material: Material, base_color: vec3<f32>, n: vec3<f32>, v: vec3<f32>, eta: f32, u: vec3<f32>) -> BsdfSample {
+    let lobes = bsdf_lobes(material, base_color, dot(n, v));
+    let p = lobes.probabilities;
+
+    var scattered: BsdfSample;
+    if u.x < p.w {
+        scattered.dir = refract(-v, n, eta);
+        if dot(scattered.dir, scattered.dir) < EPSILON {
+            // Total internal reflection
+            scattered.dir = reflect(-v, n);
+        }
+        scattered.value = lobes.specular_transmission;
+        scattered.pdf = p.w;
+        scattered.lobe = LOBE_SPECULAR_TRANSMISSION;
+        return scattered;
+    }
+
+    let frame = tangent_frame(n);
+    if u.x < p.w + p.x {
+        scattered.dir = frame * cosine_hemisphere(u.yz);
+        scattered.lobe = LOBE_DIFFUSE;
+    } else if u.x < p.w + p.x + p.y {
+        scattered.dir = -(frame * cosine_hemisphere(u.yz));
+        scattered.lobe = LOBE_DIFFUSE_TRANSMISSION;
+    } else {
+        let h = frame * sample_ggx_vndf(v * frame, ggx_alpha(material.roughness), u.yz);
+        scattered.dir = reflect(-v, h);
+        scattered.lobe = LOBE_SPECULAR;
+    }
+
+    scattered.value = evaluate_lobes(lobes, material, n, v, scattered.dir);
+    scattered.pdf = lobes_pdf(lobes, material, n, v, scattered.dir);
+    return scattered;
+}
+
+// BSDF times cosine towards `l`, for light sampling
+fn evaluate_bsdf(material: Material, base_color: vec3<f32>, n: vec3<f32>, v: vec3<f32>, l: vec3<f32>) -> vec3<f32> {
+    return evaluate_lobes(bsdf_lobes(material, base_color, dot(n, v)), material, n, v, l);
+}
+
+// Density of `sample_bsdf` choosing `l`, over solid angle
+fn bsdf_pdf(material: Material, base_color: vec3<f32>, n: vec3<f32>, v: vec3<f32>, l: vec3<f32>) -> f32 {
+    return lobes_pdf(bsdf_lobes(material, base_color, dot(n, v)), material, n, v, l);
+}
+
+fn evaluate_lobes(lobes: BsdfLobes, material: Material, n: vec3<f32>, v: vec3<f32>, l: vec3<f32>) -> vec3<f32> {
+    let n_dot_l = dot(n, l);
+    if n_dot_l < 0.0 {
+        return lobes.diffuse_transmission / PI * -n_dot_l;
+    }
+
+    return (lobes.diffuse / PI + specular_ggx(lobes.specular_color, n, v, l, material.roughness)) * n_dot_l;
+}
+
+fn lobes_pdf(lobes: BsdfLobes, material: Material, n: vec3<f32>, v: vec3<f32>, l: vec3<f32>) -> f32 {
+    let n_dot_l = dot(n, l);
+    let p = lobes.probabilities;
+    if n_dot_l < 0.0 {
+        return p.y * -n_dot_l / PI;
+    }
+
+    return p.x * n_dot_l / PI + p.z * ggx_pdf(n, v, l, material.roughness);
+}
+
+// ---- GGX ----
+// Cook-Torrance with the GGX distribution and height correlated Smith masking,
+// matching Bevy's `StandardMaterial`. `alpha` is the perceptual roughness squared.
+fn ggx_alpha(roughness: f32) -> f32 {
+    let perceptual = clamp(roughness, 0.089, 1.0);
+    return perceptual * perceptual;
+}
+
+fn ggx_distribution(n_dot_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+fn smith_lambda_term(n_dot_x: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    return sqrt(a2 + (1.0 - a2) * n_dot_x * n_dot_x);
+}
+
+fn smith_g1(n_dot_v: f32, alpha: f32) -> f32 {
+    return 2.0 * n_dot_v / (n_dot_v + smith_lambda_term(n_dot_v, alpha));
+}
+
+fn smith_g2(n_dot_v: f32, n_dot_l: f32, alpha: f32) -> f32 {
+    return 2.0 * n_dot_v * n_dot_l / (n_dot_v * smith_lambda_term(n_dot_l, alpha) + n_dot_l * smith_lambda_term(n_dot_v, alpha));
+}
+
+fn fresnel_schlick(f0: vec3<f32>, cos_theta: f32) -> vec3<f32> {
+    return f0 + (vec3<f32>(1.0) - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Dielectrics reflect `0.16 reflectance²` head on, metals their base color
+fn specular_f0(material: Material, base_color: vec3<f32>) -> vec3<f32> {
+    let dielectric = 0.16 * material.reflectance * material.reflectance;
+    return mix(vec3<f32>(dielectric), base_color, material.metallic);
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn specular_ggx(f0: vec3<f32>, n: vec3<f32>, v: vec3<f32>, l: vec3<f32>, roughness: f32) -> vec3<f32> {
+    let n_dot_v = dot(n, v);
+    let n_dot_l = dot(n, l);
+    if n_dot_v <= 0.0 || n_dot_l <= 0.0 {
+        return vec3<f32>(0.0);
+    }
+
+    let alpha = ggx_alpha(roughness);
+    let h = normalize(v + l);
+    let d = ggx_distribution(max(dot(n, h), 0.0), alpha);
+    let g = smith_g2(n_dot_v, n_dot_l, alpha);
+    let f = fresnel_schlick(f0, max(dot(v, h), 0.0));
+    return d * g * f / (4.0 * n_dot_v * n_dot_l);
+}
+
+// Reflections of `v` off visible normals, over solid angle
+fn ggx_pdf(n: vec3<f32>, v: vec3<f32>, l: vec3<f32>, roughness: f32) -> f32 {
+    let n_dot_v = dot(n, v);
+    if n_dot_v <= 0.0 || dot(n, l) <= 0.0 {
+        return 0.0;
+    }
+
+    let alpha = ggx_alpha(roughness);
+    let h = normalize(v + l);
+    return ggx_distribution(max(dot(n, h), 0.0), alpha) * smith_g1(n_dot_v, alpha) / (4.0 * n_dot_v);
+}
+
+// A microfacet normal from the distribution of normals visible from `v`, in
+// tangent space, as in Heitz's "Sampling the GGX Distribution of Visible Normals"
+fn sample_ggx_vndf(v: vec3<f32>, alpha: f32, u: vec2<f32>) -> vec3<f32> {
+    // Stretch to the hemisphere configuration, sample the projected disk, then unstretch
+    let v_h = normalize(vec3<f32>(alpha * v.x, alpha * v.y, max(v.z, 0.0)));
+    let len_sq = v_h.x * v_h.x + v_h.y * v_h.y;
+    let t1 = select(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(-v_h.y, v_h.x, 0.0) * inverseSqrt(len_sq), len_sq > 0.0);
+    let t2 = cross(v_h, t1);
+    let r = sqrt(u.x);
+    let phi = 2.0 * PI * u.y;
+    let p1 = r * cos(phi);
+    let s = 0.5 * (1.0 + v_h.z);
+    let p2 = (1.0 - s) * sqrt(max(1.0 - p1 * p1, 0.0)) + s * r * sin(phi);
+    let n_h = p1 * t1 + p2 * t2 + sqrt(max(1.0 - p1 * p1 - p2 * p2, 0.0)) * v_h;
+    return normalize(vec3<f32>(alpha * n_h.x, alpha * n_h.y, max(n_h.z, 0.0)));
+}
+
+// ---- Sampling ----
+// Columns are a tangent, bitangent and `n`
+fn tangent_frame(n: vec3<f32>) -> mat3x3<f32> {
+    // Hugues-Möller
+    let a = abs(n);
+    var t = vec3<f32>(0);
+    if a.x <= a.y && a.x <= a.z {
+        t = vec3<f32>(0, -n.z, n.y);
+    } else if a.y <= a.x && a.y <= a.z {
+        t = vec3<f32>(-n.z, 0, n.x);
+    } else {
+        t = vec3<f32>(-n.y, n.x, 0);
+    }
+    t = normalize(t);
+    let b = normalize(cross(n, t));
+
+    return mat3x3<f32>(t, b, n);
+}
+
+fn cosine_hemisphere(u: vec2<f32>) -> vec3<f32> {
+    let phi = 2 * PI * u.x;
+    let sin_theta = sqrt(u.y);
+    let cos_theta = sqrt(1 - u.y);
+    return vec3<f32>(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+}
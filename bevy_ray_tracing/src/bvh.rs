@@ -0,0 +1,550 @@
+use bevy::math::{Affine3A, Vec3};
+
+/// Deepest a node can be, the shader's traversal stack is sized to match
+pub const MAX_DEPTH: usize = 32;
+
+const BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub const EMPTY: Self = Self {
+        min: Vec3::INFINITY,
+        max: Vec3::NEG_INFINITY,
+    };
+
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+
+    pub fn from_points(points: impl IntoIterator<Item = Vec3>) -> Self {
+        points.into_iter().fold(Self::EMPTY, Self::grow)
+    }
+
+    pub fn grow(self, point: Vec3) -> Self {
+        Self {
+            min: self.min.min(point),
+            max: self.max.max(point),
+        }
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self {
+            min: self.min.max(other.min),
+            max: self.max.min(other.max),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.cmpgt(self.max).any()
+    }
+
+    /// Bounds of the eight transformed corners
+    pub fn transformed(&self, transform: &Affine3A) -> Self {
+        if self.is_empty() {
+            return *self;
+        }
+
+        Self::from_points((0..8).map(|i| {
+            let corner = Vec3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            transform.transform_point3(corner)
+        }))
+    }
+
+    /// Bounds over a linear motion from `from` to `to`
+    pub fn swept(&self, from: &Affine3A, to: &Affine3A) -> Self {
+        self.transformed(from).union(self.transformed(to))
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+}
+
+/// A flattened BVH node
+///
+/// Leaves have a `count` above zero and cover `first..first + count` of
+/// [`Bvh::indices`], interior nodes have their children at `first` and `first + 1`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BvhNode {
+    pub bounds: Aabb,
+    pub first: u32,
+    pub count: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    /// Primitive indices in leaf order
+    pub indices: Vec<u32>,
+}
+
+impl Bvh {
+    /// Builds a BVH over `bounds` using binned SAH splits
+    ///
+    /// An empty input gives a single empty leaf so the root always exists.
+    pub fn build(bounds: &[Aabb]) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(bounds.len().max(1) * 2),
+            indices: (0..bounds.len() as u32).collect(),
+        };
+
+        let centers: Vec<Vec3> = bounds.iter().map(Aabb::center).collect();
+        bvh.nodes.push(BvhNode {
+            bounds: Aabb::EMPTY,
+            first: 0,
+            count: bounds.len() as u32,
+        });
+        bvh.subdivide(0, 0, bounds, &centers);
+
+        bvh
+    }
+
+    fn subdivide(&mut self, node_index: usize, depth: usize, bounds: &[Aabb], centers: &[Vec3]) {
+        let node = self.nodes[node_index];
+        let range = node.first as usize..(node.first + node.count) as usize;
+
+        let node_bounds = self.indices[range.clone()]
+            .iter()
+            .fold(Aabb::EMPTY, |b, &i| b.union(bounds[i as usize]));
+        self.nodes[node_index].bounds = node_bounds;
+
+        if range.len() <= MAX_LEAF_SIZE || depth + 1 >= MAX_DEPTH {
+            return;
+        }
+
+        let Some((axis, split, split_cost)) = self.find_split(range.clone(), bounds, centers)
+        else {
+            return;
+        };
+
+        let leaf_cost = range.len() as f32 * INTERSECTION_COST;
+        if split_cost >= leaf_cost {
+            return;
+        }
+
+        // Partition
+        let mut i = range.start;
+        let mut j = range.end;
+        while i < j {
+            if centers[self.indices[i] as usize][axis] < split {
+                i += 1;
+            } else {
+                j -= 1;
+                self.indices.swap(i, j);
+            }
+        }
+
+        let left_count = i - range.start;
+        if left_count == 0 || left_count == range.len() {
+            return;
+        }
+
+        let left = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds: Aabb::EMPTY,
+            first: range.start as u32,
+            count: left_count as u32,
+        });
+        self.nodes.push(BvhNode {
+            bounds: Aabb::EMPTY,
+            first: i as u32,
+            count: (range.len() - left_count) as u32,
+        });
+        self.nodes[node_index].first = left as u32;
+        self.nodes[node_index].count = 0;
+
+        self.subdivide(left, depth + 1, bounds, centers);
+        self.subdivide(left + 1, depth + 1, bounds, centers);
+    }
+
+    /// Each node's parent, the root is its own
+    pub fn parents(&self) -> Vec<u32> {
+        let mut parents = vec![0; self.nodes.len()];
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.count == 0 && self.nodes.len() > 1 {
+                parents[node.first as usize] = i as u32;
+                parents[node.first as usize + 1] = i as u32;
+            }
+        }
+        parents
+    }
+
+    /// The leaf holding each primitive
+    pub fn leaves(&self) -> Vec<u32> {
+        let mut leaves = vec![0; self.indices.len()];
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.count > 0 {
+                for &primitive in
+                    &self.indices[node.first as usize..(node.first + node.count) as usize]
+                {
+                    leaves[primitive as usize] = i as u32;
+                }
+            }
+        }
+        leaves
+    }
+
+    /// Recomputes the bounds of the leaves holding `primitives` and every node
+    /// above them, keeping the tree's shape
+    pub fn refit(
+        &mut self,
+        bounds: &[Aabb],
+        primitives: impl IntoIterator<Item = u32>,
+        leaves: &[u32],
+        parents: &[u32],
+    ) {
+        for primitive in primitives {
+            let mut index = leaves[primitive as usize] as usize;
+            let leaf = self.nodes[index];
+            self.nodes[index].bounds = self.indices
+                [leaf.first as usize..(leaf.first + leaf.count) as usize]
+                .iter()
+                .fold(Aabb::EMPTY, |b, &i| b.union(bounds[i as usize]));
+
+            while index != 0 {
+                index = parents[index] as usize;
+                let first = self.nodes[index].first as usize;
+                let node_bounds = self.nodes[first].bounds.union(self.nodes[first + 1].bounds);
+
+                // Nothing above changes either
+                if node_bounds == self.nodes[index].bounds {
+                    break;
+                }
+                self.nodes[index].bounds = node_bounds;
+            }
+        }
+    }
+
+    /// Expected cost of tracing a ray through the tree by the surface area
+    /// heuristic, relative to its root's area
+    pub fn cost(&self) -> f32 {
+        let root_area = self.nodes[0].bounds.surface_area();
+        if root_area <= f32::EPSILON {
+            return 0.0;
+        }
+
+        self.nodes
+            .iter()
+            .map(|node| {
+                let area = node.bounds.surface_area();
+                if node.count > 0 {
+                    area * node.count as f32 * INTERSECTION_COST
+                } else {
+                    area * TRAVERSAL_COST
+                }
+            })
+            .sum::<f32>()
+            / root_area
+    }
+
+    /// Returns the best `(axis, position, cost)` split of the range, relative to its surface area
+    fn find_split(
+        &self,
+        range: std::ops::Range<usize>,
+        bounds: &[Aabb],
+        centers: &[Vec3],
+    ) -> Option<(usize, f32, f32)> {
+        let primitives = &self.indices[range];
+        let center_bounds = Aabb::from_points(primitives.iter().map(|&i| centers[i as usize]));
+        let node_area = primitives
+            .iter()
+            .fold(Aabb::EMPTY, |b, &i| b.union(bounds[i as usize]))
+            .surface_area();
+
+        let mut best = None;
+        let axes = center_bounds
+            .min
+            .to_array()
+            .into_iter()
+            .zip(center_bounds.max.to_array());
+        for (axis, (min, max)) in axes.enumerate() {
+            let extent = max - min;
+            if extent <= f32::EPSILON {
+                continue;
+            }
+
+            let mut bins = [(Aabb::EMPTY, 0usize); BINS];
+            let scale = BINS as f32 / extent;
+            for &i in primitives {
+                let bin = (((centers[i as usize][axis] - min) * scale) as usize).min(BINS - 1);
+                bins[bin].0 = bins[bin].0.union(bounds[i as usize]);
+                bins[bin].1 += 1;
+            }
+
+            // Sweep from the right so each plane knows the area and count on both sides
+            let mut right = [(0.0, 0usize); BINS - 1];
+            let mut right_bounds = Aabb::EMPTY;
+            let mut right_count = 0;
+            for b in (1..BINS).rev() {
+                right_bounds = right_bounds.union(bins[b].0);
+                right_count += bins[b].1;
+                right[b - 1] = (right_bounds.surface_area(), right_count);
+            }
+
+            let mut left_bounds = Aabb::EMPTY;
+            let mut left_count = 0;
+            for b in 0..BINS - 1 {
+                left_bounds = left_bounds.union(bins[b].0);
+                left_count += bins[b].1;
+                if left_count == 0 || right[b].1 == 0 {
+                    continue;
+                }
+
+                let cost = TRAVERSAL_COST
+                    + INTERSECTION_COST
+                        * (left_bounds.surface_area() * left_count as f32
+                            + right[b].0 * right[b].1 as f32)
+                        / node_area.max(f32::EPSILON);
+
+                if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, min + (b + 1) as f32 / scale, cost));
+                }
+            }
+        }
+
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_rng::Rng;
+
+    fn contains(outer: &Aabb, inner: &Aabb) -> bool {
+        outer.min.cmple(inner.min).all() && outer.max.cmpge(inner.max).all()
+    }
+
+    fn random_spheres(count: usize) -> Vec<(Vec3, f32)> {
+        let mut rng = Rng(0x9e3779b9);
+        (0..count)
+            .map(|_| (rng.vec3() * 20.0 - 10.0, 0.05 + rng.f32() * 0.5))
+            .collect()
+    }
+
+    fn sphere_bounds(spheres: &[(Vec3, f32)]) -> Vec<Aabb> {
+        spheres
+            .iter()
+            .map(|&(c, r)| Aabb::new(c - r, c + r))
+            .collect()
+    }
+
+    fn hit_sphere(origin: Vec3, dir: Vec3, (center, radius): (Vec3, f32)) -> Option<f32> {
+        let oc = origin - center;
+        let a = dir.dot(dir);
+        let half_b = oc.dot(dir);
+        let c = oc.dot(oc) - radius * radius;
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+
+        let t = (-half_b - discriminant.sqrt()) / a;
+        (t > 0.0).then_some(t)
+    }
+
+    fn hit_aabb(origin: Vec3, inv_dir: Vec3, aabb: &Aabb, t_max: f32) -> bool {
+        let t0 = (aabb.min - origin) * inv_dir;
+        let t1 = (aabb.max - origin) * inv_dir;
+        let near = t0.min(t1).max_element();
+        let far = t0.max(t1).min_element();
+        near <= far && far >= 0.0 && near < t_max
+    }
+
+    /// Mirrors the stack traversal in raytrace.wgsl
+    fn traverse(bvh: &Bvh, spheres: &[(Vec3, f32)], origin: Vec3, dir: Vec3) -> Option<u32> {
+        let inv_dir = dir.recip();
+        let mut closest = (f32::INFINITY, None);
+        let mut stack = vec![0u32];
+        while let Some(index) = stack.pop() {
+            let node = bvh.nodes[index as usize];
+            if !hit_aabb(origin, inv_dir, &node.bounds, closest.0) {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &i in &bvh.indices[node.first as usize..(node.first + node.count) as usize] {
+                    if let Some(t) = hit_sphere(origin, dir, spheres[i as usize]) {
+                        if t < closest.0 {
+                            closest = (t, Some(i));
+                        }
+                    }
+                }
+            } else {
+                assert!(stack.len() + 2 <= MAX_DEPTH);
+                stack.push(node.first + 1);
+                stack.push(node.first);
+            }
+        }
+
+        closest.1
+    }
+
+    fn brute_force(spheres: &[(Vec3, f32)], origin: Vec3, dir: Vec3) -> Option<u32> {
+        spheres
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &s)| hit_sphere(origin, dir, s).map(|t| (t, i as u32)))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, i)| i)
+    }
+
+    #[test]
+    fn empty_has_root() {
+        let bvh = Bvh::build(&[]);
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.nodes[0].count, 0);
+        assert!(bvh.indices.is_empty());
+    }
+
+    #[test]
+    fn nodes_contain_children() {
+        let bounds = sphere_bounds(&random_spheres(500));
+        let bvh = Bvh::build(&bounds);
+
+        for node in &bvh.nodes {
+            if node.count > 0 {
+                for &i in &bvh.indices[node.first as usize..(node.first + node.count) as usize] {
+                    assert!(contains(&node.bounds, &bounds[i as usize]));
+                }
+            } else {
+                let first = node.first as usize;
+                assert!(contains(&node.bounds, &bvh.nodes[first].bounds));
+                assert!(contains(&node.bounds, &bvh.nodes[first + 1].bounds));
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_cover_every_primitive_once() {
+        let bounds = sphere_bounds(&random_spheres(500));
+        let bvh = Bvh::build(&bounds);
+
+        let mut seen = vec![0; bounds.len()];
+        let mut stack = vec![0usize];
+        while let Some(index) = stack.pop() {
+            let node = bvh.nodes[index];
+            if node.count > 0 {
+                for &i in &bvh.indices[node.first as usize..(node.first + node.count) as usize] {
+                    seen[i as usize] += 1;
+                }
+            } else {
+                stack.push(node.first as usize);
+                stack.push(node.first as usize + 1);
+            }
+        }
+
+        assert!(seen.iter().all(|&count| count == 1));
+    }
+
+    #[test]
+    fn identical_centers_become_a_leaf() {
+        let bounds = vec![Aabb::new(Vec3::ZERO, Vec3::ONE); 64];
+        let bvh = Bvh::build(&bounds);
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.nodes[0].count, 64);
+    }
+
+    #[test]
+    fn traversal_matches_brute_force() {
+        let spheres = random_spheres(1000);
+        let bvh = Bvh::build(&sphere_bounds(&spheres));
+
+        let mut rng = Rng(0x2545f491);
+        for _ in 0..2000 {
+            let origin = rng.vec3() * 30.0 - 15.0;
+            let dir = rng.vec3() * 2.0 - 1.0;
+            assert_eq!(
+                traverse(&bvh, &spheres, origin, dir),
+                brute_force(&spheres, origin, dir)
+            );
+        }
+    }
+
+    #[test]
+    fn refit_bounds_moved_primitives() {
+        let mut spheres = random_spheres(500);
+        let mut bvh = Bvh::build(&sphere_bounds(&spheres));
+        let (leaves, parents) = (bvh.leaves(), bvh.parents());
+
+        let mut rng = Rng(0x1234_5678);
+        let moved: Vec<u32> = (0..spheres.len() as u32).step_by(7).collect();
+        for &i in &moved {
+            spheres[i as usize].0 += rng.vec3() * 4.0 - 2.0;
+        }
+        let bounds = sphere_bounds(&spheres);
+        bvh.refit(&bounds, moved, &leaves, &parents);
+
+        for node in &bvh.nodes {
+            if node.count > 0 {
+                let range = node.first as usize..(node.first + node.count) as usize;
+                let leaf_bounds = bvh.indices[range]
+                    .iter()
+                    .fold(Aabb::EMPTY, |b, &i| b.union(bounds[i as usize]));
+                assert_eq!(node.bounds, leaf_bounds);
+            } else {
+                let first = node.first as usize;
+                let children = bvh.nodes[first].bounds.union(bvh.nodes[first + 1].bounds);
+                assert_eq!(node.bounds, children);
+            }
+        }
+
+        let mut rng = Rng(0x2545f491);
+        for _ in 0..500 {
+            let origin = rng.vec3() * 30.0 - 15.0;
+            let dir = rng.vec3() * 2.0 - 1.0;
+            assert_eq!(
+                traverse(&bvh, &spheres, origin, dir),
+                brute_force(&spheres, origin, dir)
+            );
+        }
+    }
+
+    #[test]
+    fn scattering_raises_cost() {
+        let mut spheres = random_spheres(500);
+        let mut bvh = Bvh::build(&sphere_bounds(&spheres));
+        let (leaves, parents) = (bvh.leaves(), bvh.parents());
+        let built = bvh.cost();
+
+        // Swap every other sphere with its mirror image, so leaves span the scene
+        let count = spheres.len();
+        for i in (0..count / 2).step_by(2) {
+            let (a, b) = (spheres[i].0, spheres[count - 1 - i].0);
+            spheres[i].0 = b;
+            spheres[count - 1 - i].0 = a;
+        }
+        bvh.refit(&sphere_bounds(&spheres), 0..count as u32, &leaves, &parents);
+
+        assert!(bvh.cost() > 2.0 * built);
+        assert!(Bvh::build(&sphere_bounds(&spheres)).cost() < bvh.cost());
+    }
+}
@@ -0,0 +1,118 @@
+use crate::{
+    bvh::{Aabb, Bvh, BvhNode},
+    types::RayTraceCurveSegment,
+    CurveBasis, RTCurves,
+};
+
+use bevy::{
+    ecs::{entity::Entity, system::Resource},
+    math::{Vec3, Vec4},
+    utils::HashMap,
+};
+
+/// Linear pieces each cubic Bézier is split into
+const BEZIER_SUBDIVISIONS: usize = 8;
+
+/// A set of strands as linear segments, ordered by their BVH
+pub struct CurveBlas {
+    pub nodes: Vec<BvhNode>,
+    pub segments: Vec<RayTraceCurveSegment>,
+}
+
+impl CurveBlas {
+    pub fn new(curves: &RTCurves) -> Self {
+        let mut segments = Vec::new();
+        for strand in &curves.strands {
+            let points: Vec<Vec4> = strand
+                .iter()
+                .map(|point| point.position.extend(point.radius))
+                .collect();
+
+            match curves.basis {
+                CurveBasis::Linear => {
+                    segments.extend(points.windows(2).map(|pair| RayTraceCurveSegment {
+                        a: pair[0],
+                        b: pair[1],
+                    }));
+                }
+                CurveBasis::Bezier => {
+                    for control in points.windows(4).step_by(3) {
+                        segments.extend((0..BEZIER_SUBDIVISIONS).map(|i| {
+                            let t0 = i as f32 / BEZIER_SUBDIVISIONS as f32;
+                            let t1 = (i + 1) as f32 / BEZIER_SUBDIVISIONS as f32;
+                            RayTraceCurveSegment {
+                                a: bezier(control, t0),
+                                b: bezier(control, t1),
+                            }
+                        }));
+                    }
+                }
+            }
+        }
+
+        let bounds: Vec<Aabb> = segments
+            .iter()
+            .map(|segment| {
+                let a = Aabb::new(
+                    segment.a.truncate() - Vec3::splat(segment.a.w),
+                    segment.a.truncate() + Vec3::splat(segment.a.w),
+                );
+                let b = Aabb::new(
+                    segment.b.truncate() - Vec3::splat(segment.b.w),
+                    segment.b.truncate() + Vec3::splat(segment.b.w),
+                );
+                a.union(b)
+            })
+            .collect();
+        let bvh = Bvh::build(&bounds);
+
+        Self {
+            nodes: bvh.nodes,
+            segments: bvh.indices.iter().map(|&i| segments[i as usize]).collect(),
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes[0].bounds
+    }
+}
+
+/// Radius is interpolated with the same basis as the position
+fn bezier(control: &[Vec4], t: f32) -> Vec4 {
+    let s = 1.0 - t;
+    control[0] * (s * s * s)
+        + control[1] * (3.0 * s * s * t)
+        + control[2] * (3.0 * s * t * t)
+        + control[3] * (t * t * t)
+}
+
+/// Curve BVHs kept across frames, keyed by the main world entity
+#[derive(Resource, Default)]
+pub struct CurveBlasCache {
+    map: HashMap<Entity, CurveBlas>,
+}
+
+impl CurveBlasCache {
+    /// The entity's BLAS, and whether it was built just now
+    pub fn get_or_build(
+        &mut self,
+        entity: Entity,
+        changed: bool,
+        curves: &RTCurves,
+    ) -> (&CurveBlas, bool) {
+        if changed {
+            self.map.remove(&entity);
+        }
+
+        let built = !self.map.contains_key(&entity);
+        let blas = self
+            .map
+            .entry(entity)
+            .or_insert_with(|| CurveBlas::new(curves));
+        (blas, built)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(Entity) -> bool) {
+        self.map.retain(|entity, _| keep(*entity));
+    }
+}
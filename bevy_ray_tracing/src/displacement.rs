@@ -0,0 +1,461 @@
+use crate::{
+    heightfield::Heightfield,
+    mesh::MeshBlas,
+    types::{RayTraceTriangle, RayTraceVertex},
+    RTDisplacement,
+};
+
+use bevy::{
+    asset::UntypedAssetId,
+    ecs::system::Resource,
+    math::{UVec3, Vec2, Vec3, Vec4},
+    utils::HashMap,
+};
+
+/// What a displaced BLAS was built from
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DisplacementKey {
+    /// The mesh, `None` for a quad
+    pub mesh: Option<UntypedAssetId>,
+    pub depth_map: UntypedAssetId,
+    scale: u32,
+    max_subdivisions: u32,
+    max_triangles: u32,
+}
+
+impl DisplacementKey {
+    pub fn new(
+        mesh: Option<UntypedAssetId>,
+        depth_map: UntypedAssetId,
+        displacement: &RTDisplacement,
+    ) -> Self {
+        Self {
+            mesh,
+            depth_map,
+            scale: displacement.scale.to_bits(),
+            max_subdivisions: displacement.max_subdivisions,
+            max_triangles: displacement.max_triangles,
+        }
+    }
+}
+
+/// The unit quad [`RTQuad`](crate::RTQuad) traces, with the UVs `hit_quad` gives it
+pub fn quad_blas() -> MeshBlas {
+    let corner = |x: f32, z: f32| RayTraceVertex {
+        normal: Vec3::Y,
+        uv: Vec2::new(z + 0.5, x + 0.5),
+        tangent: Vec4::new(0.0, 0.0, 1.0, 1.0),
+        color: Vec4::ONE,
+    };
+    let position = |x: f32, z: f32| Vec3::new(x, 0.0, z);
+
+    MeshBlas::new(
+        &[
+            RayTraceTriangle {
+                a: position(-0.5, -0.5),
+                b: position(-0.5, 0.5),
+                c: position(0.5, 0.5),
+            },
+            RayTraceTriangle {
+                a: position(-0.5, -0.5),
+                b: position(0.5, 0.5),
+                c: position(0.5, -0.5),
+            },
+        ],
+        &[
+            [corner(-0.5, -0.5), corner(-0.5, 0.5), corner(0.5, 0.5)],
+            [corner(-0.5, -0.5), corner(0.5, 0.5), corner(0.5, -0.5)],
+        ],
+        &[0, 1, 2, 0, 2, 3],
+    )
+}
+
+/// Splits every triangle of `source` into smaller ones and pushes their
+/// vertices in along the interpolated normal by `depth`
+///
+/// Each edge is split into about a texel of the depth map per piece, once for
+/// both triangles either side, which snap the sides of their grids to its
+/// points so shared edges stay closed. Splits are scaled down together until
+/// the result fits in `max_triangles`. Normals are then recomputed from the
+/// displaced triangles around each vertex, across the source's edges too.
+pub fn displace(source: &MeshBlas, depth: &Heightfield, displacement: &RTDisplacement) -> MeshBlas {
+    let size = Vec2::new(depth.width as f32, depth.depth as f32);
+    let max_subdivisions = displacement.max_subdivisions.max(1);
+    let triangle_ids = || {
+        source
+            .corners
+            .chunks_exact(3)
+            .map(|ids| [ids[0], ids[1], ids[2]])
+    };
+
+    // Texels along each edge, the most either side sees when their UVs differ
+    let mut texels: HashMap<EdgeKey, f32> = HashMap::new();
+    for (corner, ids) in source.vertices.chunks_exact(3).zip(triangle_ids()) {
+        for (k, l) in EDGES {
+            let length = ((corner[l].uv - corner[k].uv).abs() * size).max_element();
+            let texels = texels.entry(edge_key(ids[k], ids[l])).or_insert(0.0);
+            *texels = texels.max(length);
+        }
+    }
+
+    let splits_at = |scale: f32| -> HashMap<EdgeKey, u32> {
+        texels
+            .iter()
+            .map(|(&key, &texels)| {
+                let splits = (texels * scale).ceil() as u32;
+                (key, splits.clamp(1, max_subdivisions))
+            })
+            .collect()
+    };
+    // A triangle's grid has as many rows as its most split edge
+    let rows = |splits: &HashMap<EdgeKey, u32>, ids: [u32; 3]| {
+        EDGES
+            .map(|(k, l)| splits[&edge_key(ids[k], ids[l])])
+            .into_iter()
+            .max()
+            .unwrap()
+    };
+    let budget = displacement.max_triangles as usize;
+    let mut scale = 1.0;
+    let mut splits = splits_at(scale);
+    loop {
+        let count: usize = triangle_ids()
+            .map(|ids| rows(&splits, ids).pow(2) as usize)
+            .sum();
+        if count <= budget || count <= source.triangles.len() {
+            break;
+        }
+        scale *= (budget as f32 / count as f32).sqrt().min(0.9);
+        splits = splits_at(scale);
+    }
+
+    // Vertices shared between triangles are only displaced once, so they
+    // land in the same place and gather the normals of every face around them
+    let mut positions = Vec::new();
+    let mut grid = Vec::new();
+    let mut push = |(position, vertex): (Vec3, RayTraceVertex)| {
+        positions.push(position);
+        grid.push(vertex);
+        positions.len() - 1
+    };
+    let displaced = |points: [Vec3; 3], corner: [&RayTraceVertex; 3], weights: Vec3| {
+        let [a, b, c] = weights.to_array();
+        let normal = (corner[0].normal * a + corner[1].normal * b + corner[2].normal * c)
+            .normalize_or_zero();
+        let uv = corner[0].uv * a + corner[1].uv * b + corner[2].uv * c;
+        let tangent = corner[0].tangent * a + corner[1].tangent * b + corner[2].tangent * c;
+        let position = points[0] * a + points[1] * b + points[2] * c
+            - normal * depth.sample(uv) * displacement.scale;
+        let vertex = RayTraceVertex {
+            normal,
+            uv,
+            tangent: tangent
+                .truncate()
+                .normalize_or_zero()
+                .extend(corner[0].tangent.w),
+            color: corner[0].color * a + corner[1].color * b + corner[2].color * c,
+        };
+        (position, vertex)
+    };
+
+    let mut source_vertices: HashMap<u32, usize> = HashMap::new();
+    let mut edge_vertices: HashMap<EdgeKey, Vec<usize>> = HashMap::new();
+    let mut faces = Vec::new();
+    for ((triangle, corner), ids) in source
+        .triangles
+        .iter()
+        .zip(source.vertices.chunks_exact(3))
+        .zip(triangle_ids())
+    {
+        let points = [triangle.a, triangle.b, triangle.c];
+        let corner = [&corner[0], &corner[1], &corner[2]];
+        let ends = [0, 1, 2].map(|k| {
+            *source_vertices
+                .entry(ids[k])
+                .or_insert_with(|| push(displaced(points, corner, Vec3::AXES[k])))
+        });
+
+        for (k, l) in EDGES {
+            let key = edge_key(ids[k], ids[l]);
+            let (low, high) = if ids[k] <= ids[l] { (k, l) } else { (l, k) };
+            edge_vertices.entry(key).or_insert_with(|| {
+                let splits = splits[&key];
+                (1..splits)
+                    .map(|i| {
+                        let t = i as f32 / splits as f32;
+                        push(displaced(
+                            [points[low], points[high], points[low]],
+                            [corner[low], corner[high], corner[low]],
+                            Vec3::new(1.0 - t, t, 0.0),
+                        ))
+                    })
+                    .collect()
+            });
+        }
+        // The point `step` of `steps` from end `k` towards end `l`, snapped
+        // to the edge's own splits, which run from its lower id
+        let edge_point = |k: usize, l: usize, step: u32, steps: u32| {
+            let key = edge_key(ids[k], ids[l]);
+            let splits = splits[&key];
+            let (low, high) = if ids[k] <= ids[l] { (k, l) } else { (l, k) };
+            let mut split = (step * splits + steps / 2) / steps;
+            if low != k {
+                split = splits - split;
+            }
+            match split {
+                0 => ends[low],
+                split if split == splits => ends[high],
+                split => edge_vertices[&key][split as usize - 1],
+            }
+        };
+
+        // Row `i` of the grid has `n + 1 - i` points, `j` along it
+        let n = rows(&splits, ids);
+        let mut indices = Vec::new();
+        for i in 0..=n {
+            for j in 0..=n - i {
+                let index = if i == 0 {
+                    edge_point(0, 2, j, n)
+                } else if j == 0 {
+                    edge_point(0, 1, i, n)
+                } else if i + j == n {
+                    edge_point(1, 2, j, n)
+                } else {
+                    let weights = UVec3::new(n - i - j, i, j).as_vec3() / n as f32;
+                    push(displaced(points, corner, weights))
+                };
+                indices.push(index);
+            }
+        }
+
+        let index = |i: u32, j: u32| indices[(i * (2 * n + 3 - i) / 2 + j) as usize];
+        for i in 0..n {
+            for j in 0..n - i {
+                faces.push([index(i, j), index(i + 1, j), index(i, j + 1)]);
+                if j + 1 < n - i {
+                    faces.push([index(i + 1, j), index(i + 1, j + 1), index(i, j + 1)]);
+                }
+            }
+        }
+    }
+    // Snapping collapses some of the triangles along coarser edges
+    faces.retain(|&[a, b, c]| a != b && b != c && c != a);
+
+    // Area weighted, kept on the side the surface faced before
+    let mut normals = vec![Vec3::ZERO; grid.len()];
+    for face in &faces {
+        let [a, b, c] = face.map(|i| positions[i]);
+        let face_normal = (b - a).cross(c - a);
+        for &i in face {
+            normals[i] += face_normal;
+        }
+    }
+    for (vertex, normal) in grid.iter_mut().zip(normals) {
+        let normal = normal.normalize_or_zero();
+        if normal != Vec3::ZERO {
+            vertex.normal = if normal.dot(vertex.normal) < 0.0 {
+                -normal
+            } else {
+                normal
+            };
+        }
+    }
+
+    let triangles: Vec<RayTraceTriangle> = faces
+        .iter()
+        .map(|face| RayTraceTriangle {
+            a: positions[face[0]],
+            b: positions[face[1]],
+            c: positions[face[2]],
+        })
+        .collect();
+    let vertices: Vec<[RayTraceVertex; 3]> =
+        faces.iter().map(|face| face.map(|i| grid[i])).collect();
+    let corners: Vec<u32> = faces.iter().flatten().map(|&i| i as u32).collect();
+    MeshBlas::new(&triangles, &vertices, &corners)
+}
+
+/// A triangle's edges, as pairs of its corners
+const EDGES: [(usize, usize); 3] = [(0, 1), (1, 2), (2, 0)];
+
+/// The mesh vertices at an edge's ends, lowest first
+type EdgeKey = (u32, u32);
+
+fn edge_key(a: u32, b: u32) -> EdgeKey {
+    (a.min(b), a.max(b))
+}
+
+/// Displaced BLASes kept across frames, until their mesh or depth map changes
+#[derive(Resource, Default)]
+pub struct DisplacementCache {
+    map: HashMap<DisplacementKey, MeshBlas>,
+}
+
+impl DisplacementCache {
+    pub fn contains(&self, key: &DisplacementKey) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn get_or_build(
+        &mut self,
+        key: DisplacementKey,
+        build: impl FnOnce() -> Option<MeshBlas>,
+    ) -> Option<&MeshBlas> {
+        if !self.map.contains_key(&key) {
+            self.map.insert(key, build()?);
+        }
+
+        self.map.get(&key)
+    }
+
+    /// Drops everything built from the mesh or image `id`
+    pub fn remove(&mut self, id: UntypedAssetId) {
+        self.map
+            .retain(|key, _| key.mesh != Some(id) && key.depth_map != id);
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&DisplacementKey) -> bool) {
+        self.map.retain(|key, _| keep(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn depth_map(width: u32, heights: Vec<f32>) -> Heightfield {
+        Heightfield {
+            width,
+            depth: heights.len() as u32 / width,
+            min: heights.iter().copied().fold(f32::INFINITY, f32::min),
+            max: heights.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+            heights,
+        }
+    }
+
+    #[test]
+    fn flat_depth_sinks_the_quad() {
+        let displacement = RTDisplacement {
+            scale: 0.25,
+            max_subdivisions: 64,
+            ..Default::default()
+        };
+        let blas = displace(&quad_blas(), &depth_map(4, vec![1.0; 16]), &displacement);
+
+        // A texel per edge
+        assert_eq!(blas.triangles.len(), 2 * 4 * 4);
+        for triangle in &blas.triangles {
+            for p in [triangle.a, triangle.b, triangle.c] {
+                assert!((p.y + 0.25).abs() < 1e-6);
+            }
+        }
+        for vertex in &blas.vertices {
+            assert!(vertex.normal.distance(Vec3::Y) < 1e-5);
+        }
+    }
+
+    #[test]
+    fn subdivisions_are_capped() {
+        let displacement = RTDisplacement {
+            scale: 0.1,
+            max_subdivisions: 8,
+            ..Default::default()
+        };
+        let blas = displace(
+            &quad_blas(),
+            &depth_map(64, vec![0.0; 64 * 64]),
+            &displacement,
+        );
+        assert_eq!(blas.triangles.len(), 2 * 8 * 8);
+    }
+
+    #[test]
+    fn depth_pushes_in_along_normals() {
+        // Deeper towards +u, which runs along the quad's z
+        let heights = (0..16 * 16).map(|i| (i % 16) as f32 / 15.0).collect();
+        let displacement = RTDisplacement {
+            scale: 0.5,
+            max_subdivisions: 16,
+            ..Default::default()
+        };
+        let blas = displace(&quad_blas(), &depth_map(16, heights), &displacement);
+        let bounds = blas.bounds();
+        assert!(bounds.max.y <= 1e-6 && bounds.min.y < -0.4);
+
+        // The surface slopes down towards +z, so its normals tilt that way
+        let mean = blas
+            .vertices
+            .iter()
+            .fold(Vec3::ZERO, |sum, vertex| sum + vertex.normal);
+        assert!(mean.z > 0.0 && mean.y > 0.0);
+    }
+
+    #[test]
+    fn edges_are_split_once_for_both_sides() {
+        // Eight texels along u but two along v, so the diagonal is split
+        // more finely than the sides running along v
+        let heights = (0..8 * 2).map(|i| (i % 3) as f32 / 2.0).collect();
+        let displacement = RTDisplacement {
+            scale: 0.2,
+            max_subdivisions: 64,
+            ..Default::default()
+        };
+        let blas = displace(&quad_blas(), &depth_map(8, heights), &displacement);
+
+        // Closed edges are used by two triangles, the rest are the quad's
+        let mut uses: HashMap<(u32, u32), u32> = HashMap::new();
+        for ids in blas.corners.chunks_exact(3) {
+            for (k, l) in EDGES {
+                *uses.entry(edge_key(ids[k], ids[l])).or_default() += 1;
+            }
+        }
+        let mut positions = HashMap::new();
+        for (triangle, ids) in blas.triangles.iter().zip(blas.corners.chunks_exact(3)) {
+            for (id, p) in ids.iter().zip([triangle.a, triangle.b, triangle.c]) {
+                positions.insert(*id, p);
+            }
+        }
+        let on_side = |p: Vec3| (p.x.abs() - 0.5).abs() < 1e-6 || (p.z.abs() - 0.5).abs() < 1e-6;
+        for ((a, b), count) in uses {
+            assert!(count <= 2);
+            if count == 1 {
+                assert!(on_side(positions[&a]) && on_side(positions[&b]));
+            }
+        }
+    }
+
+    #[test]
+    fn normals_are_shared_across_source_edges() {
+        let heights = (0..16 * 16)
+            .map(|i| ((i % 16) as f32 * 0.7).sin())
+            .collect();
+        let displacement = RTDisplacement {
+            scale: 0.3,
+            max_subdivisions: 16,
+            ..Default::default()
+        };
+        let blas = displace(&quad_blas(), &depth_map(16, heights), &displacement);
+
+        let mut normals = HashMap::new();
+        for (vertex, id) in blas.vertices.iter().zip(&blas.corners) {
+            let normal = *normals.entry(*id).or_insert(vertex.normal);
+            assert_eq!(normal, vertex.normal);
+        }
+    }
+
+    #[test]
+    fn triangles_fit_the_budget() {
+        let displacement = RTDisplacement {
+            scale: 0.1,
+            max_subdivisions: 64,
+            max_triangles: 500,
+        };
+        let blas = displace(
+            &quad_blas(),
+            &depth_map(64, vec![0.0; 64 * 64]),
+            &displacement,
+        );
+        assert!(blas.triangles.len() <= 500);
+        assert!(blas.triangles.len() > 250);
+    }
+}
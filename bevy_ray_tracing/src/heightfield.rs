@@ -0,0 +1,100 @@
+use bevy::{
+    asset::{Assets, UntypedAssetId},
+    ecs::system::Resource,
+    math::Vec2,
+    render::{render_resource::TextureFormat, texture::Image},
+    utils::HashMap,
+};
+
+/// An image's first channel as heights in `[0, 1]`, row by row
+pub struct Heightfield {
+    pub width: u32,
+    pub depth: u32,
+    pub heights: Vec<f32>,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Heightfield {
+    /// Returns `None` for formats without a single channel to read or images
+    /// smaller than one cell
+    pub fn from_image(image: &Image) -> Option<Self> {
+        let size = image.texture_descriptor.size;
+        if size.width < 2 || size.height < 2 {
+            return None;
+        }
+
+        let data = &image.data;
+        let heights: Vec<f32> = match image.texture_descriptor.format {
+            TextureFormat::R8Unorm => data.iter().map(|&h| h as f32 / 255.0).collect(),
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => data
+                .chunks_exact(4)
+                .map(|texel| texel[0] as f32 / 255.0)
+                .collect(),
+            TextureFormat::R16Unorm => data
+                .chunks_exact(2)
+                .map(|h| u16::from_le_bytes([h[0], h[1]]) as f32 / 65535.0)
+                .collect(),
+            TextureFormat::Rgba16Unorm => data
+                .chunks_exact(8)
+                .map(|texel| u16::from_le_bytes([texel[0], texel[1]]) as f32 / 65535.0)
+                .collect(),
+            TextureFormat::R32Float => data
+                .chunks_exact(4)
+                .map(|h| f32::from_le_bytes([h[0], h[1], h[2], h[3]]))
+                .collect(),
+            _ => return None,
+        };
+
+        let heights = heights.get(..(size.width * size.height) as usize)?.to_vec();
+        Some(Self {
+            width: size.width,
+            depth: size.height,
+            min: heights.iter().copied().fold(f32::INFINITY, f32::min),
+            max: heights.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+            heights,
+        })
+    }
+
+    /// Bilinearly filtered height at `uv`, repeating past the edges
+    pub fn sample(&self, uv: Vec2) -> f32 {
+        let size = Vec2::new(self.width as f32, self.depth as f32);
+        let texel = uv * size - 0.5;
+        let base = texel.floor();
+        let f = texel - base;
+
+        let height = |dx: f32, dy: f32| {
+            let x = (base.x + dx).rem_euclid(size.x) as usize;
+            let y = (base.y + dy).rem_euclid(size.y) as usize;
+            self.heights[y * self.width as usize + x]
+        };
+        let top = height(0.0, 0.0) * (1.0 - f.x) + height(1.0, 0.0) * f.x;
+        let bottom = height(0.0, 1.0) * (1.0 - f.x) + height(1.0, 1.0) * f.x;
+        top * (1.0 - f.y) + bottom * f.y
+    }
+}
+
+/// Heights read back from images, kept across frames like mesh BLASes
+#[derive(Resource, Default)]
+pub struct HeightfieldCache {
+    map: HashMap<UntypedAssetId, Heightfield>,
+}
+
+impl HeightfieldCache {
+    pub fn get_or_build(
+        &mut self,
+        id: UntypedAssetId,
+        images: &Assets<Image>,
+    ) -> Option<&Heightfield> {
+        if !self.map.contains_key(&id) {
+            let heightfield = Heightfield::from_image(images.get(id.typed::<Image>())?)?;
+            self.map.insert(id, heightfield);
+        }
+
+        self.map.get(&id)
+    }
+
+    pub fn remove(&mut self, id: UntypedAssetId) {
+        self.map.remove(&id);
+    }
+}
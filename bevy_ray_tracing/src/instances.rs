@@ -0,0 +1,88 @@
+use crate::{
+    bvh::{Aabb, Bvh, BvhNode},
+    types::RayTraceInstance,
+};
+
+use bevy::{
+    ecs::{entity::Entity, system::Resource},
+    math::Mat3,
+    transform::components::Transform,
+    utils::{hashbrown::hash_map::Entry, HashMap},
+};
+
+/// An instance set's transforms, ordered by its BVH
+pub struct InstanceBlas {
+    pub nodes: Vec<BvhNode>,
+    pub instances: Vec<RayTraceInstance>,
+    shape_bounds: Aabb,
+}
+
+impl InstanceBlas {
+    pub fn new(transforms: &[Transform], shape_bounds: Aabb) -> Self {
+        let affines: Vec<_> = transforms.iter().map(Transform::compute_affine).collect();
+        let bounds: Vec<Aabb> = affines
+            .iter()
+            .map(|affine| shape_bounds.transformed(affine))
+            .collect();
+        let bvh = Bvh::build(&bounds);
+
+        Self {
+            nodes: bvh.nodes,
+            instances: bvh
+                .indices
+                .iter()
+                .map(|&i| {
+                    let affine = affines[i as usize];
+                    RayTraceInstance {
+                        inverse_model: Mat3::from(affine.matrix3).inverse(),
+                        position: affine.translation.into(),
+                    }
+                })
+                .collect(),
+            shape_bounds,
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes[0].bounds
+    }
+}
+
+/// Instance BVHs kept across frames, keyed by the main world entity
+///
+/// Large instance sets are usually static, so they're only rebuilt when their
+/// component changes or their shape's bounds do.
+#[derive(Resource, Default)]
+pub struct InstanceBlasCache {
+    map: HashMap<Entity, InstanceBlas>,
+}
+
+impl InstanceBlasCache {
+    /// The entity's BLAS, and whether it was built just now
+    pub fn get_or_build(
+        &mut self,
+        entity: Entity,
+        changed: bool,
+        transforms: &[Transform],
+        shape_bounds: Aabb,
+    ) -> (&InstanceBlas, bool) {
+        match self.map.entry(entity) {
+            Entry::Occupied(entry) => {
+                let blas = entry.into_mut();
+                let built = changed || blas.shape_bounds != shape_bounds;
+                if built {
+                    *blas = InstanceBlas::new(transforms, shape_bounds);
+                }
+                (blas, built)
+            }
+            Entry::Vacant(entry) => (
+                entry.insert(InstanceBlas::new(transforms, shape_bounds)),
+                true,
+            ),
+        }
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(Entity) -> bool) {
+        self.map.retain(|entity, _| keep(*entity));
+    }
+}
@@ -0,0 +1,563 @@
+//! A CPU port of the linear BVH builder in `lbvh.wgsl`, kept in step with the
+//! shader so the trees it emits can be checked in tests, and built in its
+//! place while its pipelines compile
+
+use crate::{
+    bvh::{Aabb, Bvh, BvhNode},
+    types::{LBVH_RADIX_BITS, LBVH_RADIX_SIZE, LBVH_WORKGROUP_SIZE},
+};
+
+use bevy::math::Vec3;
+
+/// Spreads the low 10 bits of `v` out to every third bit
+fn expand_bits(v: u32) -> u32 {
+    let mut x = v & 0x3ff;
+    x = (x | (x << 16)) & 0x0300_00ff;
+    x = (x | (x << 8)) & 0x0300_f00f;
+    x = (x | (x << 4)) & 0x030c_30c3;
+    x = (x | (x << 2)) & 0x0924_9249;
+    x
+}
+
+/// 30 bit Morton code of a point in the unit cube
+fn morton_code(p: Vec3) -> u32 {
+    let q = (p * 1024.0)
+        .clamp(Vec3::ZERO, Vec3::splat(1023.0))
+        .as_uvec3();
+    (expand_bits(q.x) << 2) | (expand_bits(q.y) << 1) | expand_bits(q.z)
+}
+
+/// Codes of each object's center within `centroids`, as `morton`
+fn morton_codes(bounds: &[Aabb], centroids: Aabb) -> Vec<u32> {
+    let extent = centroids.max - centroids.min;
+    bounds
+        .iter()
+        .map(|b| {
+            morton_code(Vec3::select(
+                extent.cmpgt(Vec3::ZERO),
+                (b.center() - centroids.min) / extent,
+                Vec3::ZERO,
+            ))
+        })
+        .collect()
+}
+
+/// Stable LSD radix sort of the codes, returning each with the object it came
+/// from, as `count_digits`, `scan_digits` and `scatter_digits` over blocks of
+/// a workgroup's keys
+fn radix_sort(codes: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let block_size = LBVH_WORKGROUP_SIZE as usize;
+    let blocks = codes.len().div_ceil(block_size);
+
+    let mut keys = codes.to_vec();
+    let mut values: Vec<u32> = (0..codes.len() as u32).collect();
+    for shift in (0..32).step_by(LBVH_RADIX_BITS as usize) {
+        // Digit major, so each digit's counts follow the previous digit's
+        let slot = |i: usize, key: u32| {
+            let digit = (key >> shift) & (LBVH_RADIX_SIZE - 1);
+            digit as usize * blocks + i / block_size
+        };
+
+        let mut block_counts = vec![0; LBVH_RADIX_SIZE as usize * blocks];
+        for (i, &key) in keys.iter().enumerate() {
+            block_counts[slot(i, key)] += 1;
+        }
+        let mut sum = 0;
+        for offset in &mut block_counts {
+            let count = *offset;
+            *offset = sum;
+            sum += count;
+        }
+
+        let mut sorted_keys = vec![0; keys.len()];
+        let mut sorted_values = vec![0; values.len()];
+        for (i, (&key, &value)) in keys.iter().zip(&values).enumerate() {
+            let offset = &mut block_counts[slot(i, key)];
+            sorted_keys[*offset] = key;
+            sorted_values[*offset] = value;
+            *offset += 1;
+        }
+        keys = sorted_keys;
+        values = sorted_values;
+    }
+    (keys, values)
+}
+
+/// Common prefix length of sorted keys `i` and `j`, -1 past either end
+fn delta(keys: &[u32], i: i32, j: i32) -> i32 {
+    if j < 0 || j >= keys.len() as i32 {
+        return -1;
+    }
+
+    let (a, b) = (keys[i as usize], keys[j as usize]);
+    if a == b {
+        return 32 + (i as u32 ^ j as u32).leading_zeros() as i32;
+    }
+    (a ^ b).leading_zeros() as i32
+}
+
+/// The first and last leaf internal node `i` covers and the last leaf on its
+/// left, as `emit`
+fn internal_node(keys: &[u32], i: i32) -> (i32, i32, i32) {
+    let d = if delta(keys, i, i + 1) > delta(keys, i, i - 1) {
+        1
+    } else {
+        -1
+    };
+
+    let delta_min = delta(keys, i, i - d);
+    let mut l_max = 2;
+    while delta(keys, i, i + l_max * d) > delta_min {
+        l_max *= 2;
+    }
+    let mut l = 0;
+    let mut t = l_max / 2;
+    while t > 0 {
+        if delta(keys, i, i + (l + t) * d) > delta_min {
+            l += t;
+        }
+        t /= 2;
+    }
+    let j = i + l * d;
+
+    let delta_node = delta(keys, i, j);
+    let mut s = 0;
+    let mut t = l;
+    loop {
+        t = (t + 1) / 2;
+        if delta(keys, i, i + (s + t) * d) > delta_node {
+            s += t;
+        }
+        if t == 1 {
+            break;
+        }
+    }
+
+    (i.min(j), i.max(j), i + s * d + d.min(0))
+}
+
+/// Builds the tree the GPU does, each leaf holds one object by its index
+/// rather than its place in [`Bvh::indices`], so the objects never move
+///
+/// Like the extraction, fewer than two objects fall back to [`Bvh::build`].
+pub fn build(bounds: &[Aabb]) -> Bvh {
+    let n = bounds.len();
+    if n < 2 {
+        return Bvh::build(bounds);
+    }
+
+    let centroids = Aabb::from_points(bounds.iter().filter(|b| !b.is_empty()).map(Aabb::center));
+    let (keys, values) = radix_sort(&morton_codes(bounds, centroids));
+
+    // Internal nodes then leaves, as `tree`
+    let node_count = 2 * n - 1;
+    let mut parents = vec![0; node_count];
+    let mut slots = vec![0; node_count];
+    for i in 0..n - 1 {
+        let (first, last, gamma) = internal_node(&keys, i as i32);
+        let gamma = gamma as usize;
+        let left = if first as usize == gamma {
+            n - 1 + gamma
+        } else {
+            gamma
+        };
+        let right = if last as usize == gamma + 1 {
+            n + gamma
+        } else {
+            gamma + 1
+        };
+        parents[left] = i;
+        parents[right] = i;
+        slots[left] = 2 * i + 1;
+        slots[right] = 2 * i + 2;
+    }
+
+    let mut nodes = vec![
+        BvhNode {
+            bounds: Aabb::EMPTY,
+            first: 0,
+            count: 0,
+        };
+        node_count
+    ];
+    let mut internal_bounds = vec![Aabb::EMPTY; n - 1];
+    for (k, &object) in values.iter().enumerate() {
+        let leaf = n - 1 + k;
+        let leaf_bounds = bounds[object as usize];
+        nodes[slots[leaf]] = BvhNode {
+            bounds: leaf_bounds,
+            first: object,
+            count: 1,
+        };
+
+        let mut parent = parents[leaf];
+        loop {
+            internal_bounds[parent] = internal_bounds[parent].union(leaf_bounds);
+            if parent == 0 {
+                break;
+            }
+            parent = parents[parent];
+        }
+    }
+
+    for (i, node_bounds) in internal_bounds.into_iter().enumerate() {
+        nodes[slots[i]] = BvhNode {
+            bounds: node_bounds,
+            first: 2 * i as u32 + 1,
+            count: 0,
+        };
+    }
+
+    Bvh {
+        nodes,
+        indices: (0..n as u32).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        shader::{dispatch_lbvh, LbvhBuffers, LbvhPipeline, LbvhStage},
+        test_rng::Rng,
+        types::{RayTraceBvhNode, RayTraceBvhNodes, RayTraceLbvh},
+    };
+
+    use bevy::render::{
+        render_resource::{encase, *},
+        renderer::{RenderDevice, RenderQueue},
+    };
+    use std::sync::Arc;
+
+    /// `TOP_LEVEL_STACK_SIZE` in `types.wgsl`
+    const TOP_LEVEL_STACK_SIZE: usize = 64;
+
+    fn random_bounds(rng: &mut Rng, count: usize) -> Vec<Aabb> {
+        (0..count)
+            .map(|_| {
+                let center = rng.centered_vec3(50.0);
+                let half_size = rng.vec3() + 0.01;
+                Aabb::new(center - half_size, center + half_size)
+            })
+            .collect()
+    }
+
+    /// Checks the tree below `slot` covers sorted leaves `first..=last`,
+    /// splitting them where the highest bit of their keys, extended with their
+    /// index, first differs
+    ///
+    /// This is the top down definition of the tree `emit` builds a node at a time.
+    fn check_topology(
+        bvh: &Bvh,
+        keys: &[u32],
+        values: &[u32],
+        slot: usize,
+        first: usize,
+        last: usize,
+    ) {
+        let node = bvh.nodes[slot];
+        if first == last {
+            assert_eq!(node.count, 1, "leaf {first} isn't a leaf");
+            assert_eq!(node.first, values[first]);
+            return;
+        }
+        assert_eq!(node.count, 0, "{first}..={last} should be split");
+
+        let extended = |k: usize| (keys[k] as u64) << 32 | k as u64;
+        let range_prefix = (extended(first) ^ extended(last)).leading_zeros();
+        let split = (first..last)
+            .take_while(|&k| (extended(first) ^ extended(k)).leading_zeros() > range_prefix)
+            .last()
+            .unwrap_or(first);
+
+        check_topology(bvh, keys, values, node.first as usize, first, split);
+        check_topology(bvh, keys, values, node.first as usize + 1, split + 1, last);
+    }
+
+    /// Every object is in exactly one leaf and every node bounds its children
+    fn check_valid(bvh: &Bvh, bounds: &[Aabb]) {
+        assert_eq!(bvh.nodes.len(), 2 * bounds.len() - 1);
+
+        let mut seen = vec![false; bounds.len()];
+        let mut stack = vec![0];
+        let mut visited = 0;
+        while let Some(slot) = stack.pop() {
+            visited += 1;
+            let node = bvh.nodes[slot];
+            if node.count > 0 {
+                assert_eq!(node.count, 1);
+                let object = node.first as usize;
+                assert!(!seen[object], "object {object} is in two leaves");
+                seen[object] = true;
+                assert_eq!(node.bounds, bounds[object]);
+                continue;
+            }
+
+            for child in [node.first as usize, node.first as usize + 1] {
+                let child_bounds = bvh.nodes[child].bounds;
+                assert_eq!(node.bounds.union(child_bounds), node.bounds);
+                stack.push(child);
+            }
+        }
+        assert_eq!(visited, bvh.nodes.len());
+        assert!(seen.into_iter().all(|seen| seen));
+    }
+
+    #[test]
+    fn morton_code_interleaves_axes() {
+        assert_eq!(morton_code(Vec3::ZERO), 0);
+        assert_eq!(morton_code(Vec3::X), 0x2492_4924);
+        assert_eq!(morton_code(Vec3::Y), 0x1249_2492);
+        assert_eq!(morton_code(Vec3::Z), 0x0924_9249);
+        assert_eq!(morton_code(Vec3::ONE), 0x3fff_ffff);
+        // The lowest cell's neighbours along each axis
+        let step = 1.0 / 1024.0;
+        assert_eq!(morton_code(Vec3::new(step, 0.0, 0.0)), 4);
+        assert_eq!(morton_code(Vec3::new(0.0, step, 0.0)), 2);
+        assert_eq!(morton_code(Vec3::new(0.0, 0.0, step)), 1);
+    }
+
+    #[test]
+    fn radix_sort_is_stable() {
+        let mut rng = Rng(0x2545_f491);
+        // Few distinct codes so most of them repeat
+        let codes: Vec<u32> = (0..1000)
+            .map(|_| (rng.f32() * 64.0) as u32 * 0x0101_0101)
+            .collect();
+
+        let mut expected: Vec<u32> = (0..codes.len() as u32).collect();
+        expected.sort_by_key(|&i| codes[i as usize]);
+
+        let (keys, values) = radix_sort(&codes);
+        assert_eq!(values, expected);
+        assert!(keys
+            .iter()
+            .zip(&values)
+            .all(|(&k, &v)| k == codes[v as usize]));
+    }
+
+    #[test]
+    fn matches_top_down_split() {
+        let mut rng = Rng(0x9e37_79b9);
+        for count in [2, 3, 5, 17, 100, 1000] {
+            let bounds = random_bounds(&mut rng, count);
+            let bvh = build(&bounds);
+
+            let centroids = Aabb::from_points(bounds.iter().map(Aabb::center));
+            let (keys, values) = radix_sort(&morton_codes(&bounds, centroids));
+            check_topology(&bvh, &keys, &values, 0, 0, count - 1);
+            check_valid(&bvh, &bounds);
+        }
+    }
+
+    #[test]
+    fn handles_coincident_objects() {
+        // Every code is equal, so the tree splits on the objects' order alone
+        let bounds = vec![Aabb::new(Vec3::ZERO, Vec3::ONE); 64];
+        let bvh = build(&bounds);
+        check_valid(&bvh, &bounds);
+        check_topology(&bvh, &[0; 64], &(0..64).collect::<Vec<_>>(), 0, 0, 63);
+    }
+
+    #[test]
+    fn handles_flat_scenes() {
+        // A row along X, the other axes have no extent to quantize
+        let bounds: Vec<Aabb> = (0..50)
+            .map(|i| {
+                Aabb::new(
+                    Vec3::new(i as f32, 0.0, 0.0),
+                    Vec3::new(i as f32 + 0.5, 0.0, 0.0),
+                )
+            })
+            .collect();
+        let bvh = build(&bounds);
+        check_valid(&bvh, &bounds);
+
+        // The root splits the row in two
+        let root = bvh.nodes[0];
+        let left = bvh.nodes[root.first as usize];
+        let right = bvh.nodes[root.first as usize + 1];
+        assert!(left.bounds.max.x < right.bounds.min.x);
+    }
+
+    /// Objects a walk with a stack of `stack_size` entries reaches, skipping
+    /// children once it's full as `hit` does
+    fn reachable(bvh: &Bvh, stack_size: usize) -> Vec<bool> {
+        let mut reached = vec![false; bvh.indices.len()];
+        let mut stack = vec![0];
+        while let Some(slot) = stack.pop() {
+            let node = bvh.nodes[slot];
+            if node.count > 0 {
+                reached[node.first as usize] = true;
+            } else if stack.len() + 2 <= stack_size {
+                stack.push(node.first as usize + 1);
+                stack.push(node.first as usize);
+            }
+        }
+        reached
+    }
+
+    #[test]
+    fn nested_clusters_fit_the_stack() {
+        // Clusters within clusters in the lowest corner, halving in size down
+        // to far below what the codes can tell apart. Each one nests deeper in
+        // the left of the tree, so the walk down to it keeps every right child.
+        let mut rng = Rng(0x68e3_1da4);
+        let cluster = Vec3::splat(-5000.0);
+        let mut bounds: Vec<Aabb> = (0..200)
+            .map(|_| Aabb::from_points([rng.centered_vec3(5000.0)]))
+            .collect();
+        for level in 0..24 {
+            let extent = 1000.0 * 0.5f32.powi(level);
+            let count = if level < 23 { 50 } else { 3000 };
+            bounds.extend((0..count).map(|_| Aabb::from_points([cluster + rng.vec3() * extent])));
+        }
+        let bvh = build(&bounds);
+        check_valid(&bvh, &bounds);
+
+        // Deeper than the stacks of the lower level BVHs reach
+        assert!(reachable(&bvh, 32).contains(&false));
+
+        assert!(!reachable(&bvh, TOP_LEVEL_STACK_SIZE).contains(&false));
+    }
+
+    /// The first adapter found, if any
+    fn gpu() -> Option<(RenderDevice, RenderQueue)> {
+        let instance = wgpu::Instance::default();
+        let adapter = bevy::tasks::block_on(
+            instance.request_adapter(&wgpu::RequestAdapterOptions::default()),
+        )?;
+        let (device, queue) = bevy::tasks::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                required_features: wgpu::Features::empty(),
+                required_limits: adapter.limits(),
+            },
+            None,
+        ))
+        .ok()?;
+        Some((RenderDevice::from(device), RenderQueue(Arc::new(queue))))
+    }
+
+    /// Runs `lbvh.wgsl` over `bounds` and reads back the tree it writes
+    fn gpu_build(device: &RenderDevice, queue: &RenderQueue, bounds: &[Aabb]) -> Vec<BvhNode> {
+        // The shader without `naga_oil`, its one import pasted in front of it
+        let source = [include_str!("types.wgsl"), include_str!("lbvh.wgsl")]
+            .map(|file| {
+                file.lines()
+                    .filter(|line| !line.starts_with('#'))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })
+            .join("\n");
+        let module = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("lbvh"),
+            source: ShaderSource::Wgsl(source.into()),
+        });
+        let layout = device
+            .create_bind_group_layout("lbvh_bind_group_layout", &LbvhPipeline::layout_entries());
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipelines = LbvhStage::ALL.map(|stage| {
+            device.create_compute_pipeline(&RawComputePipelineDescriptor {
+                label: Some(stage.entry_point()),
+                layout: Some(&pipeline_layout),
+                module: &module,
+                entry_point: stage.entry_point(),
+            })
+        });
+
+        let count = bounds.len() as u32;
+        let centroids = Aabb::from_points(bounds.iter().map(Aabb::center));
+        let mut lbvh = StorageBuffer::from(RayTraceLbvh {
+            centroid_min: centroids.min,
+            count,
+            centroid_max: centroids.max,
+        });
+        let mut leaves = StorageBuffer::from(RayTraceBvhNodes {
+            data: bounds
+                .iter()
+                .enumerate()
+                .map(|(i, bounds)| {
+                    RayTraceBvhNode::from(&BvhNode {
+                        bounds: *bounds,
+                        first: i as u32,
+                        count: 1,
+                    })
+                })
+                .collect(),
+        });
+        let mut bvh = StorageBuffer::from(RayTraceBvhNodes {
+            data: vec![RayTraceBvhNode::default(); 2 * bounds.len() - 1],
+        });
+        bvh.add_usages(BufferUsages::COPY_SRC);
+        lbvh.write_buffer(device, queue);
+        leaves.write_buffer(device, queue);
+        bvh.write_buffer(device, queue);
+
+        let buffers = LbvhBuffers::new(device, count);
+        let bind_group = buffers
+            .bind_group(device, &layout, &lbvh, &leaves, &bvh)
+            .unwrap();
+        let bvh_buffer = bvh.buffer().unwrap();
+        let readback = device.create_buffer(&BufferDescriptor {
+            label: Some("lbvh_readback"),
+            size: bvh_buffer.size(),
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            let pipelines: Vec<_> = pipelines.iter().collect();
+            dispatch_lbvh(&mut pass, &pipelines, &bind_group, count);
+        }
+        encoder.copy_buffer_to_buffer(bvh_buffer, 0, &readback, 0, bvh_buffer.size());
+        queue.submit([encoder.finish()]);
+
+        let slice = readback.slice(..);
+        slice.map_async(MapMode::Read, |result| result.unwrap());
+        device.wgpu_device().poll(Maintain::Wait);
+        let nodes: RayTraceBvhNodes = encase::StorageBuffer::new(&*slice.get_mapped_range())
+            .create()
+            .unwrap();
+        nodes
+            .data
+            .iter()
+            .map(|node| BvhNode {
+                bounds: Aabb::new(node.min, node.max),
+                first: node.first,
+                count: node.count,
+            })
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "needs a GPU, run with `cargo test -- --ignored`"]
+    fn gpu_matches_cpu() {
+        let (device, queue) = gpu().expect("no adapter to run the shader on");
+
+        let mut rng = Rng(0x6a09_e667);
+        // Enough objects for several blocks of keys per digit
+        for count in [2, 3, 100, 256, 257, 1000, 5000] {
+            // Centers in the middle of their Morton cells, so rounding on the
+            // GPU can't move them into another
+            let mut bounds = vec![
+                Aabb::new(Vec3::splat(-1.0), Vec3::splat(1.0)),
+                Aabb::new(Vec3::splat(1023.0), Vec3::splat(1025.0)),
+            ];
+            bounds.extend((2..count).map(|_| {
+                let center = (rng.vec3() * 1023.0).floor() + 0.5;
+                let half_size = Vec3::splat(0.25) * rng.f32();
+                Aabb::new(center - half_size, center + half_size)
+            }));
+
+            let nodes = gpu_build(&device, &queue, &bounds);
+            assert_eq!(nodes, build(&bounds).nodes, "{count} objects");
+        }
+    }
+}
@@ -0,0 +1,288 @@
+#import bevy_ray_tracing::types::{BvhNode, Lbvh, LBVH_WORKGROUP_SIZE, LBVH_RADIX_BITS, LBVH_RADIX_SIZE}
+
+@group(0) @binding(0) var<storage, read> lbvh: Lbvh;
+@group(0) @binding(1) var<storage, read> leaves: array<BvhNode>;
+// Two halves the sort ping-pongs between, sorted back into the first
+@group(0) @binding(2) var<storage, read_write> keys: array<u32>;
+@group(0) @binding(3) var<storage, read_write> values: array<u32>;
+// Each node's parent then its slot in `bvh`, internal nodes first then leaves
+@group(0) @binding(4) var<storage, read_write> tree: array<u32>;
+// Min then max of each internal node, as order preserving bits
+@group(0) @binding(5) var<storage, read_write> bounds: array<atomic<u32>>;
+@group(0) @binding(6) var<storage, read_write> bvh: array<BvhNode>;
+// The digit being sorted by and where each block's keys go for each digit
+@group(0) @binding(7) var<storage, read_write> radix: RadixSort;
+
+var<workgroup> histogram: array<atomic<u32>, LBVH_RADIX_SIZE>;
+var<workgroup> run_sums: array<u32, LBVH_WORKGROUP_SIZE>;
+var<workgroup> digits: array<u32, LBVH_WORKGROUP_SIZE>;
+
+struct RadixSort {
+    pass_index: u32,
+    block_counts: array<u32>,
+}
+
+// Spreads the low 10 bits of `v` out to every third bit
+fn expand_bits(v: u32) -> u32 {
+    var x = v & 0x3ffu;
+    x = (x | (x << 16u)) & 0x030000ffu;
+    x = (x | (x << 8u)) & 0x0300f00fu;
+    x = (x | (x << 4u)) & 0x030c30c3u;
+    x = (x | (x << 2u)) & 0x09249249u;
+    return x;
+}
+
+// 30 bit Morton code of a point in the unit cube
+fn morton_code(p: vec3<f32>) -> u32 {
+    let q = vec3<u32>(clamp(p * 1024.0, vec3<f32>(0.0), vec3<f32>(1023.0)));
+    return (expand_bits(q.x) << 2u) | (expand_bits(q.y) << 1u) | expand_bits(q.z);
+}
+
+// Floats as bits that compare in the same order, so bounds can grow with atomics
+fn ordered_bits(f: f32) -> u32 {
+    let b = bitcast<u32>(f);
+    return select(b | 0x80000000u, ~b, (b & 0x80000000u) != 0u);
+}
+
+fn from_ordered_bits(u: u32) -> f32 {
+    return bitcast<f32>(select(~u, u & 0x7fffffffu, (u & 0x80000000u) != 0u));
+}
+
+// Common prefix length of sorted keys `i` and `j`, -1 past either end.
+// Equal keys are told apart by their index so every split is unique.
+fn delta(i: i32, j: i32) -> i32 {
+    if j < 0 || j >= i32(lbvh.count) {
+        return -1;
+    }
+
+    let a = keys[i];
+    let b = keys[j];
+    if a == b {
+        return 32 + i32(countLeadingZeros(u32(i) ^ u32(j)));
+    }
+    return i32(countLeadingZeros(a ^ b));
+}
+
+// One invocation per object
+@compute @workgroup_size(LBVH_WORKGROUP_SIZE)
+fn morton(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    if i >= lbvh.count {
+        return;
+    }
+
+    let leaf = leaves[i];
+    let extent = lbvh.centroid_max - lbvh.centroid_min;
+    let center = (leaf.min + leaf.max) * 0.5;
+    keys[i] = morton_code(select(vec3<f32>(0.0), (center - lbvh.centroid_min) / extent, extent > vec3<f32>(0.0)));
+    values[i] = i;
+    if i == 0u {
+        radix.pass_index = 0u;
+    }
+
+    // Reset the bounds `grow` shrinks and grows
+    if i + 1u < lbvh.count {
+        for (var axis = 0u; axis < 3u; axis++) {
+            atomicStore(&bounds[i * 6u + axis], 0xffffffffu);
+            atomicStore(&bounds[i * 6u + 3u + axis], 0u);
+        }
+    }
+}
+
+// A stable LSD radix sort of the codes, a digit per pass. Each pass counts
+// the digits in every workgroup's block of keys, scans the counts into where
+// each block's keys with each digit start, then scatters the keys there.
+fn sort_digit(key: u32) -> u32 {
+    return (key >> (radix.pass_index * LBVH_RADIX_BITS)) & (LBVH_RADIX_SIZE - 1u);
+}
+
+fn sort_blocks() -> u32 {
+    return (lbvh.count + LBVH_WORKGROUP_SIZE - 1u) / LBVH_WORKGROUP_SIZE;
+}
+
+// One invocation per key, counts are stored digit major so the scan runs over
+// every block's count of a digit before the next digit's
+@compute @workgroup_size(LBVH_WORKGROUP_SIZE)
+fn count_digits(@builtin(local_invocation_index) thread: u32, @builtin(workgroup_id) block: vec3<u32>) {
+    if thread < LBVH_RADIX_SIZE {
+        atomicStore(&histogram[thread], 0u);
+    }
+    workgroupBarrier();
+
+    let i = block.x * LBVH_WORKGROUP_SIZE + thread;
+    if i < lbvh.count {
+        let src = (radix.pass_index & 1u) * lbvh.count;
+        atomicAdd(&histogram[sort_digit(keys[src + i])], 1u);
+    }
+    workgroupBarrier();
+
+    if thread < LBVH_RADIX_SIZE {
+        radix.block_counts[thread * sort_blocks() + block.x] = atomicLoad(&histogram[thread]);
+    }
+}
+
+// One workgroup, each invocation summing a run of the counts before they're
+// offset by the runs before them
+@compute @workgroup_size(LBVH_WORKGROUP_SIZE)
+fn scan_digits(@builtin(local_invocation_index) thread: u32) {
+    let total = LBVH_RADIX_SIZE * sort_blocks();
+    let run = (total + LBVH_WORKGROUP_SIZE - 1u) / LBVH_WORKGROUP_SIZE;
+    let first = min(thread * run, total);
+    let last = min(first + run, total);
+
+    var sum = 0u;
+    for (var i = first; i < last; i++) {
+        sum += radix.block_counts[i];
+    }
+    run_sums[thread] = sum;
+    workgroupBarrier();
+
+    if thread == 0u {
+        var offset = 0u;
+        for (var i = 0u; i < LBVH_WORKGROUP_SIZE; i++) {
+            let run_sum = run_sums[i];
+            run_sums[i] = offset;
+            offset += run_sum;
+        }
+    }
+    workgroupBarrier();
+
+    var offset = run_sums[thread];
+    for (var i = first; i < last; i++) {
+        let count = radix.block_counts[i];
+        radix.block_counts[i] = offset;
+        offset += count;
+    }
+}
+
+// One invocation per key, each landing after the keys with its digit in
+// earlier blocks and earlier invocations of its own
+@compute @workgroup_size(LBVH_WORKGROUP_SIZE)
+fn scatter_digits(@builtin(local_invocation_index) thread: u32, @builtin(workgroup_id) block: vec3<u32>) {
+    let n = lbvh.count;
+    let src = (radix.pass_index & 1u) * n;
+    let dst = n - src;
+
+    let i = block.x * LBVH_WORKGROUP_SIZE + thread;
+    var digit = LBVH_RADIX_SIZE;
+    if i < n {
+        digit = sort_digit(keys[src + i]);
+    }
+    digits[thread] = digit;
+    workgroupBarrier();
+
+    if i < n {
+        var rank = radix.block_counts[digit * sort_blocks() + block.x];
+        for (var j = 0u; j < thread; j++) {
+            rank += u32(digits[j] == digit);
+        }
+        keys[dst + rank] = keys[src + i];
+        values[dst + rank] = values[src + i];
+    }
+}
+
+// One invocation, moving the sort on to the next digit
+@compute @workgroup_size(1)
+fn next_digit() {
+    radix.pass_index += 1u;
+}
+
+// One invocation per internal node, finding the leaves it covers and where
+// they split as in Karras's "Maximizing Parallelism in the Construction of BVHs"
+//
+// Internal node `i`'s children go in slots `2i + 1` and `2i + 2`, so the root
+// is in slot 0 and every node's children are next to each other.
+@compute @workgroup_size(LBVH_WORKGROUP_SIZE)
+fn emit(@builtin(global_invocation_id) id: vec3<u32>) {
+    let n = lbvh.count;
+    if id.x + 1u >= n {
+        return;
+    }
+
+    let i = i32(id.x);
+    let d = select(-1, 1, delta(i, i + 1) > delta(i, i - 1));
+
+    // Bound the other end of the range, then binary search for it
+    let delta_min = delta(i, i - d);
+    var l_max = 2;
+    while delta(i, i + l_max * d) > delta_min {
+        l_max *= 2;
+    }
+    var l = 0;
+    for (var t = l_max / 2; t > 0; t /= 2) {
+        if delta(i, i + (l + t) * d) > delta_min {
+            l += t;
+        }
+    }
+    let j = i + l * d;
+
+    // The split is the last key sharing more than the range's common prefix with `i`
+    let delta_node = delta(i, j);
+    var s = 0;
+    var t = l;
+    loop {
+        t = (t + 1) / 2;
+        if delta(i, i + (s + t) * d) > delta_node {
+            s += t;
+        }
+        if t == 1 {
+            break;
+        }
+    }
+    let gamma = i + s * d + min(d, 0);
+
+    let left = select(u32(gamma), n - 1u + u32(gamma), min(i, j) == gamma);
+    let right = select(u32(gamma) + 1u, n + u32(gamma), max(i, j) == gamma + 1);
+    let node_count = 2u * n - 1u;
+    tree[left] = id.x;
+    tree[right] = id.x;
+    tree[node_count + left] = 2u * id.x + 1u;
+    tree[node_count + right] = 2u * id.x + 2u;
+    if id.x == 0u {
+        tree[node_count] = 0u;
+    }
+}
+
+// One invocation per leaf, placing it and growing every node above it
+@compute @workgroup_size(LBVH_WORKGROUP_SIZE)
+fn grow(@builtin(global_invocation_id) id: vec3<u32>) {
+    let n = lbvh.count;
+    if id.x >= n {
+        return;
+    }
+
+    let node_count = 2u * n - 1u;
+    let leaf = n - 1u + id.x;
+    let node = leaves[values[id.x]];
+    bvh[tree[node_count + leaf]] = node;
+
+    var parent = tree[leaf];
+    loop {
+        for (var axis = 0u; axis < 3u; axis++) {
+            atomicMin(&bounds[parent * 6u + axis], ordered_bits(node.min[axis]));
+            atomicMax(&bounds[parent * 6u + 3u + axis], ordered_bits(node.max[axis]));
+        }
+
+        if parent == 0u {
+            break;
+        }
+        parent = tree[parent];
+    }
+}
+
+// One invocation per internal node, once every leaf has grown it
+@compute @workgroup_size(LBVH_WORKGROUP_SIZE)
+fn finalize(@builtin(global_invocation_id) id: vec3<u32>) {
+    let n = lbvh.count;
+    if id.x + 1u >= n {
+        return;
+    }
+
+    var node_min: vec3<f32>;
+    var node_max: vec3<f32>;
+    for (var axis = 0u; axis < 3u; axis++) {
+        node_min[axis] = from_ordered_bits(atomicLoad(&bounds[id.x * 6u + axis]));
+        node_max[axis] = from_ordered_bits(atomicLoad(&bounds[id.x * 6u + 3u + axis]));
+    }
+    bvh[tree[2u * n - 1u + id.x]] = BvhNode(node_min, 2u * id.x + 1u, node_max, 0u);
+}
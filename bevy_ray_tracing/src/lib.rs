@@ -1,20 +1,49 @@
+mod arena;
+mod bvh;
+mod curves;
+mod displacement;
+mod heightfield;
+mod instances;
+mod lbvh;
+mod mesh;
+mod motion;
+mod points;
+mod scene;
+mod sdf;
 mod shader;
+mod skin;
+#[cfg(test)]
+mod test_rng;
+mod textures;
+#[cfg(test)]
+mod torus;
 mod types;
 
-use crate::types::GlobalRayTraceMeta;
+pub use sdf::SdfFunction;
+
+use crate::{
+    arena::BlasRegions, curves::CurveBlasCache, displacement::DisplacementCache,
+    heightfield::HeightfieldCache, instances::InstanceBlasCache, mesh::MeshBlasCache,
+    motion::PreviousTransforms, points::PointCloudBlasCache, scene::SceneBvh,
+    skin::SkinSourceCache, textures::TextureCache, types::GlobalRayTraceMeta,
+};
 use shader::{
-    extract_ray_trace, prepare_ray_trace, prepare_rt_pipelines, RayTraceLabel, RayTraceNode,
-    RayTracePipeline,
+    extract_ray_trace, prepare_lbvh, prepare_ray_trace, prepare_rt_pipelines, prepare_textures,
+    LbvhBuffers, LbvhLabel, LbvhNode, LbvhPipeline, RayTraceLabel, RayTraceNode, RayTracePipeline,
+    RayTraceTextures, SkinLabel, SkinNode, SkinPipeline,
 };
 
 use bevy::{
     asset::load_internal_asset,
     core_pipeline::core_3d::graph::{Core3d, Node3d},
+    math::Affine2,
     prelude::*,
     render::{
         extract_component::{ExtractComponent, ExtractComponentPlugin, UniformComponentPlugin},
-        render_graph::{RenderGraphApp, ViewNodeRunner},
+        graph::CameraDriverLabel,
+        render_graph::{RenderGraph, RenderGraphApp, ViewNodeRunner},
         render_resource::*,
+        renderer::RenderDevice,
         Render, RenderApp, RenderSet,
     },
 };
@@ -24,6 +53,11 @@ pub struct RayTracingSettings {
     pub bounces: u32,
     pub samples: u32,
     pub sky: Vec3,
+    /// Fraction of the frame the shutter is open for, blurring anything that moved
+    pub shutter: f32,
+    /// How much material occlusion textures darken light bounced off a surface,
+    /// a cheap stand-in for the fine occlusion few bounces miss
+    pub occlusion: f32,
 }
 
 #[derive(Component, Clone, Copy, ExtractComponent)]
@@ -34,18 +68,418 @@ pub struct RTSphere {
 #[derive(Component, Clone, Copy, ExtractComponent)]
 pub struct RTQuad;
 
+#[derive(Component, Clone, Copy, ExtractComponent)]
+pub struct RTCuboid {
+    pub half_size: Vec3,
+}
+
+#[derive(Component, Clone, Copy, ExtractComponent)]
+pub struct RTDisk {
+    pub radius: f32,
+}
+
+#[derive(Component, Clone, Copy, ExtractComponent)]
+pub struct RTTriangle {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub c: Vec3,
+}
+
+/// An infinite plane facing up
+#[derive(Component, Clone, Copy, ExtractComponent)]
+pub struct RTPlane;
+
+/// A cylinder along the Y axis, mirroring [`Cylinder`]
+#[derive(Component, Clone, Copy, ExtractComponent)]
+pub struct RTCylinder {
+    pub radius: f32,
+    pub half_height: f32,
+    pub capped: bool,
+}
+
+impl From<Cylinder> for RTCylinder {
+    fn from(cylinder: Cylinder) -> Self {
+        Self {
+            radius: cylinder.radius,
+            half_height: cylinder.half_height,
+            capped: true,
+        }
+    }
+}
+
+/// A cone along the Y axis with its tip at `height / 2`, mirroring [`Cone`]
+#[derive(Component, Clone, Copy, ExtractComponent)]
+pub struct RTCone {
+    pub radius: f32,
+    pub height: f32,
+    pub capped: bool,
+}
+
+impl From<Cone> for RTCone {
+    fn from(cone: Cone) -> Self {
+        Self {
+            radius: cone.radius,
+            height: cone.height,
+            capped: true,
+        }
+    }
+}
+
+/// A capsule along the Y axis, mirroring [`Capsule3d`]
+#[derive(Component, Clone, Copy, ExtractComponent)]
+pub struct RTCapsule {
+    pub radius: f32,
+    pub half_length: f32,
+}
+
+impl From<Capsule3d> for RTCapsule {
+    fn from(capsule: Capsule3d) -> Self {
+        Self {
+            radius: capsule.radius,
+            half_length: capsule.half_length,
+        }
+    }
+}
+
+/// A torus around the Y axis, mirroring [`Torus`]
+#[derive(Component, Clone, Copy, ExtractComponent)]
+pub struct RTTorus {
+    pub major_radius: f32,
+    pub minor_radius: f32,
+}
+
+impl From<Torus> for RTTorus {
+    fn from(torus: Torus) -> Self {
+        Self {
+            major_radius: torus.major_radius,
+            minor_radius: torus.minor_radius,
+        }
+    }
+}
+
+/// Sphere traced inside a box of `half_size`, using one of [`RayTracingPlugin::sdf_functions`]
+#[derive(Component, Clone, Copy, ExtractComponent)]
+pub struct RTSdf {
+    pub function: u32,
+    pub half_size: Vec3,
+    /// Passed to the distance function as `params`
+    pub params: [Vec4; 4],
+}
+
+/// Boolean operation applied by a [`CsgShape`] to the shapes before it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+/// Closed convex solids [`RTCsg`] can combine
+#[derive(Clone, Copy)]
+pub enum CsgPrimitive {
+    Sphere(RTSphere),
+    Cuboid(RTCuboid),
+    /// Always capped, CSG needs a closed solid
+    Cylinder(RTCylinder),
+}
+
+impl From<RTSphere> for CsgPrimitive {
+    fn from(sphere: RTSphere) -> Self {
+        Self::Sphere(sphere)
+    }
+}
+
+impl From<RTCuboid> for CsgPrimitive {
+    fn from(cuboid: RTCuboid) -> Self {
+        Self::Cuboid(cuboid)
+    }
+}
+
+impl From<RTCylinder> for CsgPrimitive {
+    fn from(cylinder: RTCylinder) -> Self {
+        Self::Cylinder(cylinder)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct CsgShape {
+    pub operation: CsgOperation,
+    pub primitive: CsgPrimitive,
+    /// Relative to the [`RTCsg`] entity
+    pub transform: Transform,
+}
+
+/// Folds up to [`RTCsg::MAX_SHAPES`] shapes together from first to last
+///
+/// The whole solid shares the entity's material.
+/// ```ignore
+/// // A lens
+/// RTCsg::new(RTSphere { radius: 1.0 }, Transform::from_xyz(0.0, -0.8, 0.0))
+///     .intersection(RTSphere { radius: 1.0 }, Transform::from_xyz(0.0, 0.8, 0.0))
+/// ```
+#[derive(Component, Clone, ExtractComponent)]
+pub struct RTCsg {
+    pub shapes: Vec<CsgShape>,
+}
+
+impl RTCsg {
+    pub const MAX_SHAPES: usize = 8;
+
+    pub fn new(primitive: impl Into<CsgPrimitive>, transform: Transform) -> Self {
+        Self { shapes: Vec::new() }.with(CsgOperation::Union, primitive, transform)
+    }
+
+    pub fn union(self, primitive: impl Into<CsgPrimitive>, transform: Transform) -> Self {
+        self.with(CsgOperation::Union, primitive, transform)
+    }
+
+    pub fn intersection(self, primitive: impl Into<CsgPrimitive>, transform: Transform) -> Self {
+        self.with(CsgOperation::Intersection, primitive, transform)
+    }
+
+    pub fn difference(self, primitive: impl Into<CsgPrimitive>, transform: Transform) -> Self {
+        self.with(CsgOperation::Difference, primitive, transform)
+    }
+
+    fn with(
+        mut self,
+        operation: CsgOperation,
+        primitive: impl Into<CsgPrimitive>,
+        transform: Transform,
+    ) -> Self {
+        self.shapes.push(CsgShape {
+            operation,
+            primitive: primitive.into(),
+            transform,
+        });
+        self
+    }
+}
+
+/// Terrain from the first channel of `image`, spanning `size.x` by `size.z` around the
+/// entity and rising up to `size.y`
+#[derive(Component, Clone, ExtractComponent)]
+pub struct RTHeightfield {
+    pub image: Handle<Image>,
+    pub size: Vec3,
+}
+
+#[derive(Clone, Copy)]
+pub struct CurvePoint {
+    pub position: Vec3,
+    pub radius: f32,
+}
+
+/// How a strand's points are joined
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CurveBasis {
+    /// A segment between each pair of points
+    Linear,
+    /// Cubic Béziers sharing end points, strands need `1 + 3n` points
+    Bezier,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CurveStyle {
+    /// Flat strips facing the ray, cheapest for thin fibres like hair
+    Ribbon,
+    /// Round tubes, for strands wide enough to see their shape
+    Tube,
+}
+
+/// Thin strands shaded with a hair BSDF, tinted by the material's base color
+///
+/// The material's roughness widens the highlights along the strands.
+#[derive(Component, Clone)]
+pub struct RTCurves {
+    pub strands: Vec<Vec<CurvePoint>>,
+    pub basis: CurveBasis,
+    pub style: CurveStyle,
+}
+
+#[derive(Clone, Copy)]
+pub struct CloudPoint {
+    pub position: Vec3,
+    pub radius: f32,
+    pub color: Color,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PointStyle {
+    Sphere,
+    /// Flat discs facing the ray, cheaper for dense clouds seen from afar
+    Disc,
+}
+
+/// Points traced through their own BVH, each point's color replaces the
+/// material's base color
+#[derive(Component, Clone)]
+pub struct RTPointCloud {
+    pub points: Vec<CloudPoint>,
+    pub style: PointStyle,
+}
+
+/// Traces the triangles of the entity's `Handle<Mesh>`
+#[derive(Component, Clone, Copy, ExtractComponent)]
+pub struct RTMesh;
+
+/// Tessellates an [`RTMesh`] or [`RTQuad`] and pushes it in along its normals by
+/// the depth in its material's `depth_map`, once the map is loaded
+///
+/// Each edge is split into about a texel of the depth map per piece, up to
+/// `max_subdivisions` pieces, fewer when the mesh would have more than
+/// `max_triangles`.
+#[derive(Component, Clone, Copy)]
+pub struct RTDisplacement {
+    /// How far full depth displaces the surface, in object space
+    pub scale: f32,
+    pub max_subdivisions: u32,
+    pub max_triangles: u32,
+}
+
+impl Default for RTDisplacement {
+    fn default() -> Self {
+        Self {
+            scale: 0.1,
+            max_subdivisions: 64,
+            max_triangles: 1 << 18,
+        }
+    }
+}
+
+/// Transforms the UVs an entity's material textures are sampled at, standing in
+/// for `StandardMaterial::uv_transform`, which this version of Bevy lacks
+#[derive(Component, Clone, Copy, Default)]
+pub struct RTUvTransform(pub Affine2);
+
+/// Shapes [`RTInstances`] can copy
+#[derive(Clone)]
+pub enum InstanceShape {
+    Sphere(RTSphere),
+    Cuboid(RTCuboid),
+    Mesh(Handle<Mesh>),
+}
+
+impl From<RTSphere> for InstanceShape {
+    fn from(sphere: RTSphere) -> Self {
+        Self::Sphere(sphere)
+    }
+}
+
+impl From<RTCuboid> for InstanceShape {
+    fn from(cuboid: RTCuboid) -> Self {
+        Self::Cuboid(cuboid)
+    }
+}
+
+impl From<Handle<Mesh>> for InstanceShape {
+    fn from(mesh: Handle<Mesh>) -> Self {
+        Self::Mesh(mesh)
+    }
+}
+
+/// Copies of one shape sharing the entity's material, traced through their own BVH
+///
+/// The BVH is only rebuilt when this component changes, so a large static set
+/// costs about as much as a single entity each frame.
+#[derive(Component, Clone)]
+pub struct RTInstances {
+    pub shape: InstanceShape,
+    /// Relative to the entity
+    pub transforms: Vec<Transform>,
+}
+
+impl RTInstances {
+    pub fn new(shape: impl Into<InstanceShape>, transforms: Vec<Transform>) -> Self {
+        Self {
+            shape: shape.into(),
+            transforms,
+        }
+    }
+}
+
 // ---- Plugin ----
 pub const RT_TYPES_HANDLE: Handle<Shader> = Handle::weak_from_u128(9475836894214873755);
 pub const RT_HIT_HANDLE: Handle<Shader> = Handle::weak_from_u128(5959859852532537293);
 pub const RT_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(5832768451236749832);
+pub const RT_SDF_HANDLE: Handle<Shader> = Handle::weak_from_u128(2361986730284178214);
+pub const RT_SKIN_HANDLE: Handle<Shader> = Handle::weak_from_u128(7193864203751942816);
+pub const RT_LBVH_HANDLE: Handle<Shader> = Handle::weak_from_u128(3868297130584412967);
+pub const RT_BSDF_HANDLE: Handle<Shader> = Handle::weak_from_u128(6120457218839503391);
 
-pub struct RayTracingPlugin;
+/// Where the BVH over the scene's objects is built each frame
+#[derive(Resource, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum BvhBuilder {
+    /// Binned SAH splits, the fastest tree to trace, refit while objects only move
+    #[default]
+    Cpu,
+    /// A linear BVH sorted by Morton code in compute shaders, quicker to build
+    /// for large scenes where everything moves but slower to trace
+    ///
+    /// The same tree is built on the CPU while its pipelines compile.
+    Gpu,
+}
+
+pub struct RayTracingPlugin {
+    /// Distance functions for [`RTSdf`], indexed by [`RTSdf::function`]
+    pub sdf_functions: Vec<SdfFunction>,
+    pub bvh_builder: BvhBuilder,
+    /// How much worse refitting the [`BvhBuilder::Cpu`] tree around moving
+    /// objects can make it before it's rebuilt, as a ratio of its traversal cost
+    pub bvh_rebuild_ratio: f32,
+    /// Width and height every material texture is resampled to in the texture
+    /// array, which holds as many as the device's `max_texture_array_layers`
+    pub texture_size: u32,
+}
+
+impl Default for RayTracingPlugin {
+    fn default() -> Self {
+        Self {
+            sdf_functions: Vec::new(),
+            bvh_builder: BvhBuilder::default(),
+            bvh_rebuild_ratio: 1.5,
+            texture_size: 1024,
+        }
+    }
+}
+
+impl RayTracingPlugin {
+    pub fn with_sdf(mut self, function: SdfFunction) -> Self {
+        self.sdf_functions.push(function);
+        self
+    }
+
+    pub fn with_bvh_builder(mut self, bvh_builder: BvhBuilder) -> Self {
+        self.bvh_builder = bvh_builder;
+        self
+    }
+
+    pub fn with_bvh_rebuild_ratio(mut self, ratio: f32) -> Self {
+        self.bvh_rebuild_ratio = ratio;
+        self
+    }
+
+    pub fn with_texture_size(mut self, size: u32) -> Self {
+        self.texture_size = size;
+        self
+    }
+}
 
 impl Plugin for RayTracingPlugin {
     fn build(&self, app: &mut App) {
         load_internal_asset!(app, RT_TYPES_HANDLE, "types.wgsl", Shader::from_wgsl);
+        load_internal_asset!(app, RT_BSDF_HANDLE, "bsdf.wgsl", Shader::from_wgsl);
         // load_internal_asset!(app, RT_HIT_HANDLE, "hit.wgsl", Shader::from_wgsl);
         load_internal_asset!(app, RT_SHADER_HANDLE, "raytrace.wgsl", Shader::from_wgsl);
+        load_internal_asset!(app, RT_SKIN_HANDLE, "skin.wgsl", Shader::from_wgsl);
+        load_internal_asset!(app, RT_LBVH_HANDLE, "lbvh.wgsl", Shader::from_wgsl);
+        app.world.resource_mut::<Assets<Shader>>().insert(
+            RT_SDF_HANDLE,
+            Shader::from_wgsl(
+                sdf::sdf_module_source(&self.sdf_functions),
+                "bevy_ray_tracing/sdf.wgsl",
+            ),
+        );
 
         app.add_plugins((
             ExtractComponentPlugin::<RayTracingSettings>::default(),
@@ -57,20 +491,52 @@ impl Plugin for RayTracingPlugin {
         };
 
         render_app
+            .insert_resource(self.bvh_builder)
+            .insert_resource(SceneBvh::new(self.bvh_rebuild_ratio))
             .init_resource::<GlobalRayTraceMeta>()
+            .init_resource::<MeshBlasCache>()
+            .init_resource::<BlasRegions>()
+            .init_resource::<InstanceBlasCache>()
+            .init_resource::<HeightfieldCache>()
+            .init_resource::<CurveBlasCache>()
+            .init_resource::<PointCloudBlasCache>()
+            .init_resource::<DisplacementCache>()
+            .init_resource::<SkinSourceCache>()
+            .init_resource::<PreviousTransforms>()
+            .init_resource::<LbvhBuffers>()
             .init_resource::<SpecializedRenderPipelines<RayTracePipeline>>()
             .add_plugins(ExtractComponentPlugin::<RTSphere>::default())
             .add_plugins(ExtractComponentPlugin::<RTQuad>::default())
+            .add_plugins(ExtractComponentPlugin::<RTCuboid>::default())
+            .add_plugins(ExtractComponentPlugin::<RTDisk>::default())
+            .add_plugins(ExtractComponentPlugin::<RTTriangle>::default())
+            .add_plugins(ExtractComponentPlugin::<RTPlane>::default())
+            .add_plugins(ExtractComponentPlugin::<RTCylinder>::default())
+            .add_plugins(ExtractComponentPlugin::<RTCone>::default())
+            .add_plugins(ExtractComponentPlugin::<RTCapsule>::default())
+            .add_plugins(ExtractComponentPlugin::<RTTorus>::default())
+            .add_plugins(ExtractComponentPlugin::<RTSdf>::default())
+            .add_plugins(ExtractComponentPlugin::<RTCsg>::default())
+            .add_plugins(ExtractComponentPlugin::<RTHeightfield>::default())
+            .add_plugins(ExtractComponentPlugin::<RTMesh>::default())
             .add_systems(ExtractSchedule, extract_ray_trace)
             .add_systems(
                 Render,
                 (
                     prepare_ray_trace.in_set(RenderSet::ManageViews),
+                    prepare_lbvh.in_set(RenderSet::ManageViews),
+                    prepare_textures.in_set(RenderSet::ManageViews),
                     prepare_rt_pipelines.in_set(RenderSet::Prepare),
                 ),
             )
             .add_render_graph_node::<ViewNodeRunner<RayTraceNode>>(Core3d, RayTraceLabel)
             .add_render_graph_edges(Core3d, (Node3d::EndMainPass, RayTraceLabel, Node3d::Bloom));
+
+        let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+        render_graph.add_node(SkinLabel, SkinNode);
+        render_graph.add_node_edge(SkinLabel, CameraDriverLabel);
+        render_graph.add_node(LbvhLabel, LbvhNode);
+        render_graph.add_node_edge(LbvhLabel, CameraDriverLabel);
     }
 
     fn finish(&self, app: &mut App) {
@@ -78,6 +544,17 @@ impl Plugin for RayTracingPlugin {
             return;
         };
 
-        render_app.init_resource::<RayTracePipeline>();
+        // Material textures share one array, as many as it has layers
+        let max_layers = render_app
+            .world
+            .resource::<RenderDevice>()
+            .limits()
+            .max_texture_array_layers;
+        render_app
+            .insert_resource(TextureCache::new(self.texture_size, max_layers))
+            .init_resource::<RayTracePipeline>()
+            .init_resource::<RayTraceTextures>()
+            .init_resource::<SkinPipeline>()
+            .init_resource::<LbvhPipeline>();
     }
 }
@@ -0,0 +1,147 @@
+use crate::{
+    bvh::{Aabb, Bvh, BvhNode},
+    types::{RayTraceTriangle, RayTraceVertex},
+};
+
+use bevy::{
+    asset::{Assets, UntypedAssetId},
+    ecs::system::Resource,
+    math::{Vec2, Vec3, Vec4},
+    render::{
+        mesh::{Mesh, VertexAttributeValues},
+        render_resource::PrimitiveTopology,
+    },
+    utils::HashMap,
+};
+
+/// A mesh's object space triangles, ordered by its bottom level BVH
+pub struct MeshBlas {
+    pub nodes: Vec<BvhNode>,
+    pub triangles: Vec<RayTraceTriangle>,
+    /// Three corners per triangle
+    pub vertices: Vec<RayTraceVertex>,
+    /// The mesh vertex each corner came from
+    pub corners: Vec<u32>,
+}
+
+impl MeshBlas {
+    /// Returns `None` if the mesh isn't a triangle list or has no positions
+    pub fn from_mesh(mesh: &Mesh) -> Option<Self> {
+        if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+            return None;
+        }
+
+        let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+        let indices: Vec<usize> = match mesh.indices() {
+            Some(indices) => indices.iter().collect(),
+            None => (0..positions.len()).collect(),
+        };
+
+        let triangles: Vec<RayTraceTriangle> = indices
+            .chunks_exact(3)
+            .map(|i| RayTraceTriangle {
+                a: positions[i[0]].into(),
+                b: positions[i[1]].into(),
+                c: positions[i[2]].into(),
+            })
+            .collect();
+
+        let normals = mesh
+            .attribute(Mesh::ATTRIBUTE_NORMAL)
+            .and_then(VertexAttributeValues::as_float3);
+        let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            Some(VertexAttributeValues::Float32x2(uvs)) => Some(uvs),
+            _ => None,
+        };
+        let tangents = match mesh.attribute(Mesh::ATTRIBUTE_TANGENT) {
+            Some(VertexAttributeValues::Float32x4(tangents)) => Some(tangents),
+            _ => None,
+        };
+        let colors = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+            Some(VertexAttributeValues::Float32x4(colors)) => Some(colors),
+            _ => None,
+        };
+
+        // Missing normals fall back to the face's, and missing UVs to the
+        // barycentric coordinates, matching a mesh without attributes
+        const CORNER_UVS: [Vec2; 3] = [Vec2::ZERO, Vec2::X, Vec2::Y];
+        let vertices: Vec<[RayTraceVertex; 3]> = indices
+            .chunks_exact(3)
+            .zip(&triangles)
+            .map(|(i, triangle)| {
+                let face_normal = (triangle.b - triangle.a)
+                    .cross(triangle.c - triangle.a)
+                    .normalize_or_zero();
+                [0, 1, 2].map(|corner| {
+                    let vertex = i[corner];
+                    RayTraceVertex {
+                        normal: normals.map_or(face_normal, |n| Vec3::from(n[vertex])),
+                        uv: uvs.map_or(CORNER_UVS[corner], |uv| Vec2::from(uv[vertex])),
+                        tangent: tangents.map_or(Vec4::ZERO, |t| Vec4::from(t[vertex])),
+                        color: colors.map_or(Vec4::ONE, |c| Vec4::from(c[vertex])),
+                    }
+                })
+            })
+            .collect();
+
+        let corners: Vec<u32> = indices.iter().map(|&corner| corner as u32).collect();
+        Some(Self::new(&triangles, &vertices, &corners))
+    }
+
+    /// Builds the BVH over `triangles`, with three `corners` per triangle
+    pub fn new(
+        triangles: &[RayTraceTriangle],
+        vertices: &[[RayTraceVertex; 3]],
+        corners: &[u32],
+    ) -> Self {
+        let bounds: Vec<Aabb> = triangles
+            .iter()
+            .map(|triangle| Aabb::from_points([triangle.a, triangle.b, triangle.c]))
+            .collect();
+        let bvh = Bvh::build(&bounds);
+
+        Self {
+            nodes: bvh.nodes,
+            triangles: bvh.indices.iter().map(|&i| triangles[i as usize]).collect(),
+            vertices: bvh
+                .indices
+                .iter()
+                .flat_map(|&i| vertices[i as usize])
+                .collect(),
+            corners: bvh
+                .indices
+                .iter()
+                .flat_map(|&i| &corners[i as usize * 3..i as usize * 3 + 3])
+                .copied()
+                .collect(),
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes[0].bounds
+    }
+}
+
+/// Bottom level acceleration structures kept across frames
+///
+/// Instances only need their bounds and transform in the top level, so a
+/// moving instance never rebuilds the BVH of the mesh it uses.
+#[derive(Resource, Default)]
+pub struct MeshBlasCache {
+    map: HashMap<UntypedAssetId, MeshBlas>,
+}
+
+impl MeshBlasCache {
+    pub fn get_or_build(&mut self, id: UntypedAssetId, meshes: &Assets<Mesh>) -> Option<&MeshBlas> {
+        if !self.map.contains_key(&id) {
+            let blas = MeshBlas::from_mesh(meshes.get(id.typed::<Mesh>())?)?;
+            self.map.insert(id, blas);
+        }
+
+        self.map.get(&id)
+    }
+
+    pub fn remove(&mut self, id: UntypedAssetId) {
+        self.map.remove(&id);
+    }
+}
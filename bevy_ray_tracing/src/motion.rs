@@ -0,0 +1,28 @@
+use bevy::{
+    ecs::{entity::Entity, system::Resource},
+    math::Affine3A,
+    utils::HashMap,
+};
+
+/// Transforms from the last extraction, keyed by the main world entity
+///
+/// Objects are traced somewhere between their previous and current transform
+/// for motion blur.
+#[derive(Resource, Default)]
+pub struct PreviousTransforms {
+    current: HashMap<Entity, Affine3A>,
+    previous: HashMap<Entity, Affine3A>,
+}
+
+impl PreviousTransforms {
+    /// Records `transform` for this frame, returning last frame's or `transform` if the entity is new
+    pub fn swap(&mut self, entity: Entity, transform: Affine3A) -> Affine3A {
+        self.current.insert(entity, transform);
+        self.previous.get(&entity).copied().unwrap_or(transform)
+    }
+
+    /// Entities not seen this frame are forgotten
+    pub fn end_frame(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+}
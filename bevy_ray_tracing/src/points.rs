@@ -0,0 +1,113 @@
+use crate::{
+    bvh::{Aabb, Bvh, BvhNode},
+    types::RayTracePoint,
+    RTPointCloud,
+};
+
+use bevy::{
+    ecs::{entity::Entity, system::Resource},
+    math::Vec3,
+    utils::HashMap,
+};
+
+/// A cloud's points, ordered by their BVH
+pub struct PointCloudBlas {
+    pub nodes: Vec<BvhNode>,
+    pub points: Vec<RayTracePoint>,
+}
+
+impl PointCloudBlas {
+    pub fn new(cloud: &RTPointCloud) -> Self {
+        let bounds: Vec<Aabb> = cloud
+            .points
+            .iter()
+            .map(|point| {
+                let radius = Vec3::splat(point.radius);
+                Aabb::new(point.position - radius, point.position + radius)
+            })
+            .collect();
+        let bvh = Bvh::build(&bounds);
+
+        Self {
+            nodes: bvh.nodes,
+            points: bvh
+                .indices
+                .iter()
+                .map(|&i| {
+                    let point = &cloud.points[i as usize];
+                    RayTracePoint {
+                        position: point.position,
+                        radius: point.radius,
+                        color: point.color.rgba_to_vec4(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes[0].bounds
+    }
+}
+
+/// Point cloud BVHs kept across frames, keyed by the main world entity
+#[derive(Resource, Default)]
+pub struct PointCloudBlasCache {
+    map: HashMap<Entity, PointCloudBlas>,
+}
+
+impl PointCloudBlasCache {
+    /// The entity's BLAS, and whether it was built just now
+    pub fn get_or_build(
+        &mut self,
+        entity: Entity,
+        changed: bool,
+        cloud: &RTPointCloud,
+    ) -> (&PointCloudBlas, bool) {
+        if changed {
+            self.map.remove(&entity);
+        }
+
+        let built = !self.map.contains_key(&entity);
+        let blas = self
+            .map
+            .entry(entity)
+            .or_insert_with(|| PointCloudBlas::new(cloud));
+        (blas, built)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(Entity) -> bool) {
+        self.map.retain(|entity, _| keep(*entity));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{CloudPoint, PointStyle};
+    use bevy::render::color::Color;
+
+    #[test]
+    fn points_keep_their_color() {
+        let cloud = RTPointCloud {
+            points: (0..100)
+                .map(|i| CloudPoint {
+                    position: Vec3::new((i * 37 % 100) as f32, 0.0, 0.0),
+                    radius: 0.5,
+                    color: Color::rgb(i as f32 / 100.0, 0.0, 0.0),
+                })
+                .collect(),
+            style: PointStyle::Sphere,
+        };
+        let blas = PointCloudBlas::new(&cloud);
+
+        assert_eq!(blas.points.len(), 100);
+        for point in &blas.points {
+            let i = (point.color.x * 100.0).round() as usize;
+            assert_eq!(point.position, cloud.points[i].position);
+        }
+        assert_eq!(blas.bounds().min, Vec3::new(-0.5, -0.5, -0.5));
+        assert_eq!(blas.bounds().max, Vec3::new(99.5, 0.5, 0.5));
+    }
+}
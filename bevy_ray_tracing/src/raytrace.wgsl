@@ -1,16 +1,30 @@
 #import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput;
 #import bevy_render::view::View;
 
-#import bevy_ray_tracing::types::{RTSettings, Camera, Ray, Object, Sphere, Quad, Material, HitRecord, PI, EPSILON, SHAPE_SPHERE, SHAPE_QUAD, hit_record, rng_state};
+#import bevy_ray_tracing::bsdf::{sample_bsdf, evaluate_bsdf, bsdf_pdf}
+#import bevy_ray_tracing::sdf::sdf
+#import bevy_ray_tracing::types::{RTSettings, Camera, Ray, Object, ObjectTransform, BvhNode, Sphere, Cuboid, Disk, Plane, Cylinder, Cone, Capsule, Torus, Sdf, Csg, CsgNode, CsgInterval, Mesh, Triangle, Vertex, InstanceSet, Instance, Heightfield, Curves, CurveSegment, PointCloud, Point, HairSample, PolyRoots, Material, HitRecord, LightSample, PI, EPSILON, T_MAX, SHAPE_SPHERE, SHAPE_QUAD, SHAPE_CUBOID, SHAPE_DISK, SHAPE_TRIANGLE, SHAPE_PLANE, SHAPE_CYLINDER, SHAPE_CONE, SHAPE_CAPSULE, SHAPE_TORUS, SHAPE_SDF, SHAPE_CSG, SHAPE_INSTANCES, SHAPE_HEIGHTFIELD, SHAPE_CURVES, SHAPE_POINT_CLOUD, SHAPE_MESH, CURVE_RIBBON, CURVE_TUBE, POINT_SPHERE, POINT_DISC, NORMAL_MAP_FLIP_Y, NORMAL_MAP_TWO_COMPONENT, HAIR_R_PROBABILITY, HAIR_TT_PROBABILITY, CSG_UNION, CSG_INTERSECTION, CSG_DIFFERENCE, CSG_MAX_SHAPES, CSG_NODE_SIZE, SDF_MAX_STEPS, SDF_HIT_DISTANCE, POLY_MAX_ITERATIONS, POLY_TOLERANCE, BVH_STACK_SIZE, TOP_LEVEL_STACK_SIZE, LOBE_SPECULAR_TRANSMISSION, PLANE_LIGHT_FOOTPRINT, LIGHT_HIT_TOLERANCE, hit_record, hit_tangent, hit_primitive, rng_state, ray_time};
 
 @group(0) @binding(0) var<storage, read_write> camera: Camera;
 @group(0) @binding(1) var<storage, read_write> objects: array<Object>;
-@group(0) @binding(2) var<storage, read_write> emissives: array<i32>;
-@group(0) @binding(3) var<storage, read_write> spheres: array<Sphere>;
-@group(0) @binding(4) var<storage, read_write> quads: array<Quad>;
-@group(0) @binding(5) var<storage, read_write> materials: array<Material>;
-@group(0) @binding(6) var<uniform> settings: RTSettings;
-@group(0) @binding(7) var<uniform> view: View;
+@group(0) @binding(2) var<storage, read_write> bvh: array<BvhNode>;
+@group(0) @binding(3) var<storage, read_write> emissives: array<i32>;
+@group(0) @binding(4) var<storage, read_write> shapes: array<vec4<u32>>;
+@group(0) @binding(5) var<storage, read_write> planes: array<Plane>;
+@group(0) @binding(6) var<storage, read_write> blas_nodes: array<BvhNode>;
+@group(0) @binding(7) var<storage, read_write> triangles: array<Triangle>;
+@group(0) @binding(8) var<storage, read_write> vertices: array<Vertex>;
+@group(0) @binding(9) var<storage, read_write> instances: array<Instance>;
+@group(0) @binding(10) var<storage, read_write> heights: array<f32>;
+@group(0) @binding(11) var<storage, read_write> curve_segments: array<CurveSegment>;
+@group(0) @binding(12) var<storage, read_write> points: array<Point>;
+@group(0) @binding(13) var<storage, read_write> materials: array<Material>;
+@group(0) @binding(14) var<uniform> settings: RTSettings;
+@group(0) @binding(15) var<uniform> view: View;
+
+@group(1) @binding(0) var textures: texture_2d_array<f32>;
+@group(1) @binding(1) var texture_sampler: sampler;
+@group(1) @binding(2) var<storage, read_write> texture_layers: array<u32>;
 
 // ---- Setup and Return ----
 @fragment
@@ -25,28 +39,196 @@ fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
         let offset_r = rand() * 2.0 - 1.0;
         let offset = uv_delta * offset_r.xy * 0.5;
 
-        let direction = (camera.forward + (uv.x + offset.x) * camera.right + (uv.y + offset.y) * camera.up);
-        let ray = Ray(camera.position, direction);
+        // The shutter closes at the end of the frame
+        ray_time = 1.0 - settings.shutter * rand_f32();
+        let position = mix(camera.previous_position, camera.position, ray_time);
+        let forward = mix(camera.previous_forward, camera.forward, ray_time);
+        let right = mix(camera.previous_right, camera.right, ray_time);
+        let up = mix(camera.previous_up, camera.up, ray_time);
+
+        let direction = (forward + (uv.x + offset.x) * right + (uv.y + offset.y) * up);
+        let ray = Ray(position, direction);
         color += trace(ray, settings.bounces);
     }
 
     return vec4<f32>(color / f32(settings.samples), 1.0);
 }
 
+// ---- Shapes ----
+// Each shape's parameters start at its object's `shape_index`, floats stored
+// as their bits, as `PackedShape` writes them
+fn shape_floats(i: i32) -> vec4<f32> {
+    return bitcast<vec4<f32>>(shapes[i]);
+}
+
+fn load_sphere(i: i32) -> Sphere {
+    return Sphere(shape_floats(i).x);
+}
+
+fn load_cuboid(i: i32) -> Cuboid {
+    return Cuboid(shape_floats(i).xyz);
+}
+
+fn load_disk(i: i32) -> Disk {
+    return Disk(shape_floats(i).x);
+}
+
+fn load_triangle(i: i32) -> Triangle {
+    return Triangle(shape_floats(i).xyz, shape_floats(i + 1).xyz, shape_floats(i + 2).xyz);
+}
+
+fn load_cylinder(i: i32) -> Cylinder {
+    let params = shape_floats(i);
+    return Cylinder(params.x, params.y, shapes[i].z);
+}
+
+fn load_cone(i: i32) -> Cone {
+    let params = shape_floats(i);
+    return Cone(params.x, params.y, shapes[i].z);
+}
+
+fn load_capsule(i: i32) -> Capsule {
+    let params = shape_floats(i);
+    return Capsule(params.x, params.y);
+}
+
+fn load_torus(i: i32) -> Torus {
+    let params = shape_floats(i);
+    return Torus(params.x, params.y);
+}
+
+fn load_sdf(i: i32) -> Sdf {
+    let params = array<vec4<f32>, 4>(shape_floats(i + 1), shape_floats(i + 2), shape_floats(i + 3), shape_floats(i + 4));
+    return Sdf(shape_floats(i).xyz, shapes[i].w, params);
+}
+
+// The nodes follow the CSG
+fn load_csg(i: i32) -> Csg {
+    return Csg(u32(i) + 1u, shapes[i].x);
+}
+
+fn load_csg_node(i: u32) -> CsgNode {
+    let x = bitcast<vec4<f32>>(shapes[i]);
+    let y = bitcast<vec4<f32>>(shapes[i + 1u]);
+    let z = bitcast<vec4<f32>>(shapes[i + 2u]);
+    let position = bitcast<vec4<f32>>(shapes[i + 3u]);
+    let params = bitcast<vec4<f32>>(shapes[i + 4u]);
+    return CsgNode(mat3x3<f32>(x.xyz, y.xyz, z.xyz), position.xyz, shapes[i + 3u].w, params, shapes[i].w);
+}
+
+fn load_heightfield(i: i32) -> Heightfield {
+    return Heightfield(shape_floats(i).xyz, shapes[i].w, shapes[i + 1].x, shapes[i + 1].y);
+}
+
+fn load_curves(i: i32) -> Curves {
+    return Curves(shapes[i].x, shapes[i].y, shapes[i].z);
+}
+
+fn load_point_cloud(i: i32) -> PointCloud {
+    return PointCloud(shapes[i].x, shapes[i].y, shapes[i].z, shapes[i].w);
+}
+
+fn load_mesh(i: i32) -> Mesh {
+    return Mesh(shapes[i].x, shapes[i].y, shapes[i].z);
+}
+
+fn load_instance_set(i: i32) -> InstanceSet {
+    let header = shapes[i];
+    return InstanceSet(header.x, header.y, header.z, bitcast<i32>(header.w), shape_floats(i + 1));
+}
+
+// ---- Textures ----
+// Layers are stored as they were authored, so sRGB colors are decoded here
+fn sample_texture(layer: i32, uv: vec2<f32>) -> vec4<f32> {
+    let texel = textureSampleLevel(textures, texture_sampler, uv, layer, 0.0);
+    if texture_layers[layer] == 0u {
+        return texel;
+    }
+
+    let rgb = texel.xyz;
+    let linear = select(pow((rgb + 0.055) / 1.055, vec3<f32>(2.4)), rgb / 12.92, rgb <= vec3<f32>(0.04045));
+    return vec4<f32>(linear, texel.w);
+}
+
+fn material_uv(material: Material, uv: vec2<f32>) -> vec2<f32> {
+    return (material.uv_transform * vec3<f32>(uv, 1.0)).xy;
+}
+
+fn material_emission(material: Material, uv: vec2<f32>) -> vec3<f32> {
+    var emission = material.emissive.xyz;
+    if material.emissive_texture >= 0 {
+        emission *= sample_texture(material.emissive_texture, uv).xyz;
+    }
+    return emission;
+}
+
+// Tilts `n` by the material's normal map, in the tangent frame Bevy uses
+fn apply_normal_map(material: Material, n: vec3<f32>, tangent: vec4<f32>, uv: vec2<f32>) -> vec3<f32> {
+    var nt = sample_texture(material.normal_map_texture, uv).xyz * 2.0 - 1.0;
+    if (material.normal_map_flags & NORMAL_MAP_TWO_COMPONENT) != 0u {
+        nt.z = sqrt(max(1.0 - nt.x * nt.x - nt.y * nt.y, 0.0));
+    }
+    if (material.normal_map_flags & NORMAL_MAP_FLIP_Y) != 0u {
+        nt.y = -nt.y;
+    }
+
+    // Interpolated and transformed tangents drift, so square them up with `n`
+    let t = normalize(tangent.xyz - n * dot(n, tangent.xyz));
+    let b = tangent.w * cross(n, t);
+    return normalize(nt.x * t + nt.y * b + nt.z * n);
+}
+
 // ---- Ray Tracing ----
 fn trace(d_ray: Ray, max_bounces: i32) -> vec3<f32> {
     var ray = d_ray;
     var incoming_light = vec3<f32>(0.0);
     var ray_color = vec3<f32>(1.0);
+    // Density of the last bounce's direction over solid angle, zero when it
+    // didn't sample lights too so the emitters it finds count in full
+    var scatter_pdf = 0.0;
 
     for (var i = 0; i < max_bounces; i++) {
         if hit(ray) {
             let old_ray_dir = ray.dir;
             var hit_surface = hit_record;
+            let primitive = hit_primitive;
             hit_surface.n = normalize(hit_surface.n);
 
-            // Material
-            let material = materials[hit_surface.material_index];
+            // Material, textures and vertex colors tint the base color and point colors replace it
+            var material = materials[hit_surface.material_index];
+            let uv = material_uv(material, hit_surface.uv);
+
+            // Emitters found by scattering share their light with the light
+            // sampling that could have found them
+            let emission = material_emission(material, uv);
+            if emission.x + emission.y + emission.z > EPSILON {
+                var mis_weight = 1.0;
+                if scatter_pdf > 0.0 {
+                    let light_pdf = emitter_pdf(hit_surface.object_index, ray.pos, hit_surface.p, primitive);
+                    mis_weight = power_heuristic(scatter_pdf, light_pdf);
+                }
+                incoming_light += emission * ray_color * mis_weight;
+
+                // If the material is emissive then we can't scatter light
+                break;
+            }
+
+            var base_color = material.color.xyz * hit_surface.color.xyz;
+            if material.base_color_texture >= 0 {
+                base_color *= sample_texture(material.base_color_texture, uv).xyz;
+            }
+            if material.metallic_roughness_texture >= 0 {
+                // Roughness in green and metallic in blue, as in glTF
+                let metallic_roughness = sample_texture(material.metallic_roughness_texture, uv);
+                material.roughness *= metallic_roughness.y;
+                material.metallic *= metallic_roughness.z;
+            }
+            if material.normal_map_texture >= 0 && dot(hit_surface.tangent.xyz, hit_surface.tangent.xyz) > 0.0 {
+                hit_surface.n = apply_normal_map(material, hit_surface.n, hit_surface.tangent, uv);
+            }
+            if objects[hit_surface.object_index].shape_type == SHAPE_POINT_CLOUD {
+                base_color = hit_surface.color.xyz;
+            }
 
             // Scatter
             var refraction_ratio = material.ior;
@@ -54,165 +236,579 @@ fn trace(d_ray: Ray, max_bounces: i32) -> vec3<f32> {
                 refraction_ratio = 1.0 / refraction_ratio;
             }
 
-            ray.dir = scatter_lambertian(hit_surface.n, material.roughness)
-                + scatter_lambertian(-hit_surface.n, material.diffuse_transmission)
-                + scatter_reflect(ray.dir, hit_surface.n, material.metallic)
-                + scatter_refract(ray.dir, hit_surface.n, refraction_ratio, material.specular_transmission);
+            // Curves pick one of the hair lobes, other surfaces one of the
+            // BSDF's. Hair can't be evaluated towards a light, so only the
+            // surfaces sample lights.
+            let hair = objects[hit_surface.object_index].shape_type == SHAPE_CURVES;
+            var weight = vec3<f32>(0.0);
+            scatter_pdf = 0.0;
+            if hair {
+                let hair_sample = scatter_hair(ray.dir, hit_surface.n, hit_tangent, material.roughness, base_color);
+                ray.dir = hair_sample.dir;
+                weight = hair_sample.weight;
+            } else {
+                incoming_light += ray_color * sample_light(material, base_color, hit_surface, -old_ray_dir);
+
+                let u = vec3<f32>(rand_f32(), rand_f32(), rand_f32());
+                let scattered = sample_bsdf(material, base_color, hit_surface.n, -ray.dir, refraction_ratio, u);
+                ray.dir = scattered.dir;
+                if scattered.pdf > 0.0 {
+                    weight = scattered.value / scattered.pdf;
+                    // Smooth transmission is never chosen by light sampling
+                    if scattered.lobe != LOBE_SPECULAR_TRANSMISSION {
+                        scatter_pdf = scattered.pdf;
+                    }
+                }
+            }
 
             ray.dir = normalize(ray.dir); // Normalize
             ray.pos = hit_surface.p + ray.dir * EPSILON;
 
-            // BRDF
-            let N = normalize(hit_surface.n);
-            let ndotl = clamp(dot(N, ray.dir), 0.0, 1.0);
-
-            // Color
-            // incoming_light += material.emissive.xyz * ray_color;
-            incoming_light += color_BRDF_lambertian(material.emissive.xyz, N, -old_ray_dir, ray.dir) * ray_color;
-            if material.emissive.x + material.emissive.y + material.emissive.z > EPSILON {
-                // If the material is emissive then we can't scatter light
-                break;
+            // Baked occlusion stands in for the shadowing of bounced light
+            if material.occlusion_texture >= 0 {
+                let occlusion = sample_texture(material.occlusion_texture, uv).x;
+                weight *= mix(1.0, occlusion, settings.occlusion);
             }
 
-            var attenuation = color_BRDF_lambertian(material.color.xyz, N, -old_ray_dir, ray.dir);
-            ray_color *= attenuation * ndotl * PI;
+            ray_color *= weight;
             if dot(ray_color, ray_color) < EPSILON {
                 // The ray has no color
                 break;
             }
+        } else {
+            incoming_light += ray_color * settings.sky;
+            break;
+        }
+    }
+
+    return incoming_light / f32(max_bounces);
+}
 
-            // Attempt to hit an emissive object
-            {
-                let emissive_rand = rand_u32();
-                let emissive_index = emissives[emissive_rand % arrayLength(&emissives)];
-                let emissive_object = objects[emissive_index];
+// ---- Lights ----
+// Light from a point on a random emitter through the BSDF, weighted against
+// the BSDF scattering towards it
+fn sample_light(material: Material, base_color: vec3<f32>, surface: HitRecord, v: vec3<f32>) -> vec3<f32> {
+    let emissive_index = emissives[rand_u32() % arrayLength(&emissives)];
+    let light = sample_emitter(emissive_index, surface.p);
+    let to_light = light.p - surface.p;
+    let distance_sq = dot(to_light, to_light);
+    let l = to_light * inverseSqrt(distance_sq);
+    let cos_light = abs(dot(light.n, l));
+    if light.pdf <= 0.0 || cos_light < EPSILON {
+        return vec3<f32>(0.0);
+    }
 
-                let test_ray = Ray(hit_surface.p, emissive_object.position - hit_surface.p);
-                if hit(test_ray) && hit_record.object_index == emissive_index {
-                    let emissive_material = materials[emissive_object.material_index];
+    // Only lit if the sampled point itself is hit, not another part of the emitter in front of it
+    let shadow_ray = Ray(surface.p, to_light);
+    if !hit(shadow_ray) || hit_record.object_index != emissive_index || hit_record.t < 1.0 - LIGHT_HIT_TOLERANCE {
+        return vec3<f32>(0.0);
+    }
 
-                    // BRDF
-                    let N = normalize(hit_record.n);
-                    let ndotl = clamp(dot(N, test_ray.dir), 0.0, 1.0);
+    let emissive_material = materials[objects[emissive_index].material_index];
+    let emission = material_emission(emissive_material, material_uv(emissive_material, hit_record.uv));
 
-                    // Emissive
-                    incoming_light += color_BRDF_lambertian(emissive_material.emissive.xyz, N, -old_ray_dir, test_ray.dir) * ray_color;
-                }
+    let light_pdf = light.pdf * distance_sq / cos_light / f32(arrayLength(&emissives));
+    let mis_weight = power_heuristic(light_pdf, bsdf_pdf(material, base_color, surface.n, v, l));
+    return emission * evaluate_bsdf(material, base_color, surface.n, v, l) * mis_weight / light_pdf;
+}
+
+// Density of `sample_light` choosing point `p` on emissive object `i` from
+// `origin`, over solid angle
+fn emitter_pdf(i: i32, origin: vec3<f32>, p: vec3<f32>, primitive: u32) -> f32 {
+    let object = objects[i];
+    let transform = object_transform(object);
+    let local_origin = transform.inverse_model * (origin - transform.position);
+    let light = emitter_surface(object, transform, local_origin, transform.inverse_model * (p - transform.position), primitive);
+
+    let to_light = p - origin;
+    let distance_sq = dot(to_light, to_light);
+    let cos_light = abs(dot(light.n, to_light * inverseSqrt(distance_sq)));
+    if light.pdf <= 0.0 || cos_light < EPSILON {
+        return 0.0;
+    }
+    return light.pdf * distance_sq / cos_light / f32(arrayLength(&emissives));
+}
+
+// Weights one of two strategies by their densities, as in Veach's thesis
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    return select(0.0, a / (a + b), a > 0.0);
+}
+
+// A point on emissive object `i`'s surface to aim a shadow ray at from `origin`
+fn sample_emitter(i: i32, origin: vec3<f32>) -> LightSample {
+    let object = objects[i];
+    let transform = object_transform(object);
+    let local_origin = transform.inverse_model * (origin - transform.position);
+    let u = vec2<f32>(rand_f32(), rand_f32());
+
+    var p = vec3<f32>(0.0);
+    var primitive = 0u;
+    switch object.shape_type {
+        case SHAPE_SPHERE: {
+            p = load_sphere(object.shape_index).radius * sample_sphere(u);
+        }
+        case SHAPE_QUAD: {
+            p = vec3<f32>(u.x - 0.5, 0.0, u.y - 0.5);
+        }
+        case SHAPE_CUBOID: {
+            p = sample_cuboid(load_cuboid(object.shape_index), u);
+        }
+        case SHAPE_DISK: {
+            let d = load_disk(object.shape_index).radius * sample_disk(u);
+            p = vec3<f32>(d.x, 0.0, d.y);
+        }
+        case SHAPE_TRIANGLE: {
+            p = sample_triangle(load_triangle(object.shape_index), u);
+        }
+        case SHAPE_PLANE: {
+            p = sample_plane(local_origin, u);
+        }
+        case SHAPE_CYLINDER: {
+            p = sample_cylinder(load_cylinder(object.shape_index), u);
+        }
+        case SHAPE_CONE: {
+            p = sample_cone(load_cone(object.shape_index), u);
+        }
+        case SHAPE_CAPSULE: {
+            p = sample_capsule(load_capsule(object.shape_index), u);
+        }
+        case SHAPE_TORUS: {
+            p = sample_torus(load_torus(object.shape_index), u);
+        }
+        case SHAPE_POINT_CLOUD: {
+            let cloud = load_point_cloud(object.shape_index);
+            primitive = min(u32(rand_f32() * f32(cloud.point_count)), cloud.point_count - 1u);
+            p = sample_point(points[cloud.first_point + primitive], cloud.style, local_origin, u);
+        }
+        case SHAPE_MESH: {
+            let mesh = load_mesh(object.shape_index);
+            primitive = min(u32(rand_f32() * f32(mesh.triangle_count)), mesh.triangle_count - 1u);
+            p = sample_triangle(triangles[mesh.first_triangle + primitive], u);
+        }
+        default: {}
+    }
+
+    return emitter_surface(object, transform, local_origin, p, primitive);
+}
+
+// Point `p` on an emitter in world space with its normal and the density of
+// `sample_emitter` picking it over area, zero for shapes it can't sample.
+// `p` and `origin` are in the object's space, `primitive` is the triangle or
+// point `p` is on.
+fn emitter_surface(object: Object, transform: ObjectTransform, origin: vec3<f32>, p: vec3<f32>, primitive: u32) -> LightSample {
+    var n = vec3<f32>(0.0, 1.0, 0.0);
+    var pdf = 0.0;
+    switch object.shape_type {
+        case SHAPE_SPHERE: {
+            let r = load_sphere(object.shape_index).radius;
+            n = p / r;
+            pdf = 1.0 / (4.0 * PI * r * r);
+        }
+        case SHAPE_QUAD: {
+            pdf = 1.0;
+        }
+        case SHAPE_CUBOID: {
+            let h = load_cuboid(object.shape_index).half_size;
+            let d = abs(p / h);
+            if d.x >= d.y && d.x >= d.z {
+                n = vec3<f32>(sign(p.x), 0.0, 0.0);
+            } else if d.y >= d.z {
+                n = vec3<f32>(0.0, sign(p.y), 0.0);
+            } else {
+                n = vec3<f32>(0.0, 0.0, sign(p.z));
+            }
+            pdf = 1.0 / (8.0 * (h.y * h.z + h.x * h.z + h.x * h.y));
+        }
+        case SHAPE_DISK: {
+            let r = load_disk(object.shape_index).radius;
+            pdf = 1.0 / (PI * r * r);
+        }
+        case SHAPE_TRIANGLE: {
+            let triangle = load_triangle(object.shape_index);
+            let normal = cross(triangle.b - triangle.a, triangle.c - triangle.a);
+            n = normalize(normal);
+            pdf = 2.0 / length(normal);
+        }
+        case SHAPE_PLANE: {
+            let radius = PLANE_LIGHT_FOOTPRINT * abs(origin.y);
+            let offset = p.xz - origin.xz;
+            if radius > EPSILON && dot(offset, offset) <= radius * radius {
+                pdf = 1.0 / (PI * radius * radius);
             }
-        } else {
-            incoming_light = ray_color * settings.sky;
-            break;
         }
+        case SHAPE_CYLINDER: {
+            let cylinder = load_cylinder(object.shape_index);
+            let r = cylinder.radius;
+            let h = cylinder.half_height;
+            let cap_area = select(0.0, PI * r * r, cylinder.capped == 1u);
+            // On whichever of the side and the caps it's closer to
+            if cylinder.capped == 1u && abs(abs(p.y) - h) < abs(length(p.xz) - r) {
+                n = vec3<f32>(0.0, sign(p.y), 0.0);
+            } else {
+                n = normalize(vec3<f32>(p.x, 0.0, p.z));
+            }
+            pdf = 1.0 / (4.0 * PI * r * h + 2.0 * cap_area);
+        }
+        case SHAPE_CONE: {
+            let cone = load_cone(object.shape_index);
+            let r = cone.radius;
+            let half_height = cone.height * 0.5;
+            let k = r / cone.height;
+            let base_area = select(0.0, PI * r * r, cone.capped == 1u);
+            let side_distance = abs(length(p.xz) - k * (half_height - p.y)) / sqrt(1.0 + k * k);
+            if cone.capped == 1u && abs(p.y + half_height) < side_distance {
+                n = vec3<f32>(0.0, -1.0, 0.0);
+            } else {
+                n = normalize(vec3<f32>(p.x, k * k * (half_height - p.y), p.z));
+            }
+            pdf = 1.0 / (PI * r * sqrt(r * r + cone.height * cone.height) + base_area);
+        }
+        case SHAPE_CAPSULE: {
+            let capsule = load_capsule(object.shape_index);
+            let r = capsule.radius;
+            let h = capsule.half_length;
+            n = (p - vec3<f32>(0.0, clamp(p.y, -h, h), 0.0)) / r;
+            pdf = 1.0 / (4.0 * PI * r * h + 4.0 * PI * r * r);
+        }
+        case SHAPE_TORUS: {
+            // Uniform in both angles, so denser where the ring is narrower
+            let torus = load_torus(object.shape_index);
+            let ring = length(p.xz);
+            n = normalize(p - torus.major_radius * vec3<f32>(p.x, 0.0, p.z) / max(ring, EPSILON));
+            if ring > EPSILON {
+                pdf = 1.0 / (4.0 * PI * PI * torus.minor_radius * ring);
+            }
+        }
+        case SHAPE_POINT_CLOUD: {
+            let cloud = load_point_cloud(object.shape_index);
+            let point = points[cloud.first_point + primitive];
+            let r = point.radius;
+            if cloud.style == POINT_SPHERE {
+                n = (p - point.position) / r;
+                pdf = 1.0 / (f32(cloud.point_count) * 4.0 * PI * r * r);
+            } else {
+                n = normalize(origin - point.position);
+                pdf = 1.0 / (f32(cloud.point_count) * PI * r * r);
+            }
+        }
+        case SHAPE_MESH: {
+            let mesh = load_mesh(object.shape_index);
+            let triangle = triangles[mesh.first_triangle + primitive];
+            let normal = cross(triangle.b - triangle.a, triangle.c - triangle.a);
+            n = normalize(normal);
+            pdf = 2.0 / (f32(mesh.triangle_count) * length(normal));
+        }
+        default: {}
     }
 
-    return incoming_light / f32(max_bounces);
+    // Areas scale by the determinant and shrink with how much the normal grows
+    let world_n = transpose(transform.inverse_model) * n;
+    let area_scale = abs(determinant(transform.model)) * length(world_n);
+    return LightSample(transform.position + transform.model * p, normalize(world_n), pdf / area_scale);
 }
 
-// ---- BRDF ----
-fn color_BRDF_lambertian(color: vec3<f32>, n: vec3<f32>, e: vec3<f32>, l: vec3<f32>) -> vec3<f32> {
-    let b_d = color / PI;
-    return b_d;
+// Uniform over the unit sphere
+fn sample_sphere(u: vec2<f32>) -> vec3<f32> {
+    let y = 1.0 - 2.0 * u.x;
+    let r = sqrt(max(1.0 - y * y, 0.0));
+    let phi = 2.0 * PI * u.y;
+    return vec3<f32>(r * cos(phi), y, r * sin(phi));
 }
 
-// ---- Scatter ----
-fn scatter_lambertian(n: vec3<f32>, s: f32) -> vec3<f32> {
-    if s <= EPSILON {
-        return vec3<f32>(0.0);
+// Uniform over the unit disk
+fn sample_disk(u: vec2<f32>) -> vec2<f32> {
+    let phi = 2.0 * PI * u.y;
+    return sqrt(u.x) * vec2<f32>(cos(phi), sin(phi));
+}
+
+fn sample_triangle(triangle: Triangle, u: vec2<f32>) -> vec3<f32> {
+    let s = sqrt(u.x);
+    return (1.0 - s) * triangle.a + s * (1.0 - u.y) * triangle.b + s * u.y * triangle.c;
+}
+
+// A face picked by its area
+fn sample_cuboid(cuboid: Cuboid, u: vec2<f32>) -> vec3<f32> {
+    let h = cuboid.half_size;
+    let areas = vec3<f32>(h.y * h.z, h.x * h.z, h.x * h.y);
+    let pick = rand_f32() * (areas.x + areas.y + areas.z);
+    let side = select(-1.0, 1.0, rand_f32() < 0.5);
+    let a = 2.0 * u.x - 1.0;
+    let b = 2.0 * u.y - 1.0;
+    if pick < areas.x {
+        return vec3<f32>(side, a, b) * h;
+    } else if pick < areas.x + areas.y {
+        return vec3<f32>(a, side, b) * h;
     }
+    return vec3<f32>(a, b, side) * h;
+}
 
-    var ts_to_ws: mat3x3<f32>;
-    {
-        // Hugues-Möller
-        let a = abs(n);
-        var t = vec3<f32>(0);
-        if a.x <= a.y && a.x <= a.z {
-            t = vec3<f32>(0, -n.z, n.y);
-        } else if a.y <= a.x && a.y <= a.z {
-            t = vec3<f32>(-n.z, 0, n.x);
-        } else {
-            t = vec3<f32>(-n.y, n.x, 0);
-        }
-        t = normalize(t);
-        let b = normalize(cross(n, t));
+// Within the footprint below `origin`, in the plane's space
+fn sample_plane(origin: vec3<f32>, u: vec2<f32>) -> vec3<f32> {
+    let d = PLANE_LIGHT_FOOTPRINT * abs(origin.y) * sample_disk(u);
+    return vec3<f32>(origin.x + d.x, 0.0, origin.z + d.y);
+}
 
-        ts_to_ws = mat3x3<f32>(t, b, n);
+// The side or a cap, picked by their areas
+fn sample_cylinder(cylinder: Cylinder, u: vec2<f32>) -> vec3<f32> {
+    let r = cylinder.radius;
+    let h = cylinder.half_height;
+    let side_area = 4.0 * PI * r * h;
+    let cap_area = select(0.0, PI * r * r, cylinder.capped == 1u);
+    let pick = rand_f32() * (side_area + 2.0 * cap_area);
+    if pick < side_area {
+        let phi = 2.0 * PI * u.x;
+        return vec3<f32>(r * cos(phi), (2.0 * u.y - 1.0) * h, r * sin(phi));
     }
 
-    return normalize(ts_to_ws * cosine_sample()) * s;
+    let d = r * sample_disk(u);
+    return vec3<f32>(d.x, select(-h, h, pick < side_area + cap_area), d.y);
 }
 
-fn scatter_reflect(d: vec3<f32>, n: vec3<f32>, s: f32) -> vec3<f32> {
-    if s <= EPSILON {
-        return vec3<f32>(0.0);
+// The side or the base, picked by their areas
+fn sample_cone(cone: Cone, u: vec2<f32>) -> vec3<f32> {
+    let r = cone.radius;
+    let half_height = cone.height * 0.5;
+    let side_area = PI * r * sqrt(r * r + cone.height * cone.height);
+    let base_area = select(0.0, PI * r * r, cone.capped == 1u);
+    if rand_f32() * (side_area + base_area) < side_area {
+        // Uniform in the square of the distance from the tip
+        let f = sqrt(u.x);
+        let phi = 2.0 * PI * u.y;
+        return vec3<f32>(f * r * cos(phi), half_height - f * cone.height, f * r * sin(phi));
     }
 
-    return normalize(d - 2.0 * dot(d, n) * n) * s;
+    let d = r * sample_disk(u);
+    return vec3<f32>(d.x, -half_height, d.y);
 }
 
-fn scatter_refract(d: vec3<f32>, n: vec3<f32>, ior: f32, strength: f32) -> vec3<f32> {
-    if strength <= EPSILON {
-        return vec3<f32>(0.0);
+// The side or a hemisphere, picked by their areas
+fn sample_capsule(capsule: Capsule, u: vec2<f32>) -> vec3<f32> {
+    let r = capsule.radius;
+    let h = capsule.half_length;
+    let side_area = 4.0 * PI * r * h;
+    let end_area = 4.0 * PI * r * r;
+    if rand_f32() * (side_area + end_area) < side_area {
+        let phi = 2.0 * PI * u.x;
+        return vec3<f32>(r * cos(phi), (2.0 * u.y - 1.0) * h, r * sin(phi));
+    }
+
+    let d = sample_sphere(u);
+    return r * d + vec3<f32>(0.0, select(-h, h, d.y >= 0.0), 0.0);
+}
+
+// Uniform in the angles around the ring and around the tube, which crowds
+// points on the inside of the ring where there's less surface per angle
+fn sample_torus(torus: Torus, u: vec2<f32>) -> vec3<f32> {
+    let theta = 2.0 * PI * u.x;
+    let phi = 2.0 * PI * u.y;
+    let ring = torus.major_radius + torus.minor_radius * cos(phi);
+    return vec3<f32>(ring * cos(theta), torus.minor_radius * sin(phi), ring * sin(theta));
+}
+
+// Discs face `origin` as they do the rays that hit them
+fn sample_point(point: Point, style: u32, origin: vec3<f32>, u: vec2<f32>) -> vec3<f32> {
+    if style == POINT_SPHERE {
+        return point.position + point.radius * sample_sphere(u);
     }
 
-    let cos_theta = min(dot(-d, n), 1.0);
-    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
-    if ior * sin_theta > 1.0 {
-        // Total Internal Reflection
-        return reflect(d, n) * strength;
+    let axis = normalize(origin - point.position);
+    let t = normalize(cross(axis, select(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), abs(axis.x) > 0.9)));
+    let b = cross(axis, t);
+    let d = point.radius * sample_disk(u);
+    return point.position + d.x * t + d.y * b;
+}
+
+// ---- Scatter ----
+// Marschner style lobes: R reflects off the cuticle, TT passes through the fibre
+// and TRT reflects off its far side, the last two tinted by the pigment once or twice.
+// Each keeps the ray's angle along the strand, shifted by the cuticle's tilt and
+// spread by `roughness`.
+fn scatter_hair(d: vec3<f32>, n: vec3<f32>, tangent: vec3<f32>, roughness: f32, color: vec3<f32>) -> HairSample {
+    let sin_theta = clamp(dot(d, tangent), -1.0, 1.0);
+    var across = d - sin_theta * tangent;
+    if dot(across, across) < EPSILON {
+        across = -n;
     }
+    across = normalize(across);
+    let n_across = normalize(n - dot(n, tangent) * tangent);
 
-    // Schlick Approximation
-    var r0 = (1 - ior) / (1 + ior);
-    r0 = r0 * r0;
-    if r0 + (1 - r0) * pow(1 - cos_theta, 5.0) > rand_f32() {
-        return reflect(d, n) * strength;
+    let beta = mix(radians(2.0), radians(30.0), roughness);
+    let alpha = radians(-3.0);
+
+    var scattered: HairSample;
+    var shift: f32;
+    var width: f32;
+    let lobe = rand_f32();
+    if lobe < HAIR_R_PROBABILITY {
+        across = reflect(across, n_across);
+        shift = -2.0 * alpha;
+        width = beta;
+        scattered.weight = vec3<f32>(1.0);
+    } else if lobe < HAIR_R_PROBABILITY + HAIR_TT_PROBABILITY {
+        shift = alpha;
+        width = beta * 0.5;
+        scattered.weight = color;
+    } else {
+        across = reflect(across, n_across);
+        shift = 4.0 * alpha;
+        width = beta * 2.0;
+        scattered.weight = color * color;
     }
 
-    // Snell's Law
-    let r_out_perp = ior * (d + cos_theta * n);
-    let r_out_parallel = -sqrt(abs(1.0 - dot(r_out_perp, r_out_perp))) * n;
-    return normalize(r_out_perp + r_out_parallel) * strength;
+    let theta = clamp(asin(sin_theta) + shift + width * rand_normal(), -0.5 * PI, 0.5 * PI);
+    scattered.dir = sin(theta) * tangent + cos(theta) * across;
+    return scattered;
 }
 
 // ---- Hit ----
 // #import bevy_ray_tracing::hit::{hit_sphere, hit_quad};
 
 fn hit(ray: Ray) -> bool {
-    hit_record.t = 1000.0;
+    hit_record.t = T_MAX;
     var hit = false;
-    for (var i = 0; i < i32(arrayLength(&objects)); i++) {
-        let object = objects[i];
-        var object_hit = false;
-
-        let material = materials[object.material_index];
-        let double_sided = material.double_sided == 1;
 
-        var test_ray = ray;
-        test_ray.pos -= object.position;
+    let inv_dir = 1.0 / ray.dir;
+    var stack: array<u32, TOP_LEVEL_STACK_SIZE>;
+    var stack_size = 1u;
+    stack[0] = 0u;
+    while stack_size > 0u {
+        stack_size--;
+        let node = bvh[stack[stack_size]];
+        if !hit_aabb(ray, inv_dir, node.min, node.max, hit_record.t) {
+            continue;
+        }
 
-        switch object.shape_type {
-            case SHAPE_SPHERE: {
-                object_hit = hit_sphere(test_ray, spheres[object.shape_index], 0.001, hit_record.t, double_sided);
-            }
-            case SHAPE_QUAD: {
-                object_hit = hit_quad(test_ray, quads[object.shape_index], 0.001, hit_record.t, double_sided);
-            }
-            default: {
-                continue;
+        if node.count > 0u {
+            // Leaf
+            for (var i = node.first; i < node.first + node.count; i++) {
+                hit = hit_object(ray, i32(i)) || hit;
             }
+        } else if stack_size + 2u <= TOP_LEVEL_STACK_SIZE {
+            stack[stack_size] = node.first + 1u;
+            stack[stack_size + 1u] = node.first;
+            stack_size += 2u;
         }
+    }
 
-        if object_hit {
-            hit = true;
-            hit_record.material_index = object.material_index;
-            hit_record.object_index = i;
-            hit_record.p += object.position;
-        }
+    // Unbounded objects aren't in the BVH
+    for (var i = 0u; i < arrayLength(&planes); i++) {
+        hit = hit_object(ray, planes[i].object_index) || hit;
     }
 
     return hit;
 }
 
+fn hit_object(ray: Ray, i: i32) -> bool {
+    let object = objects[i];
+    var object_hit = false;
+
+    let material = materials[object.material_index];
+    let double_sided = material.double_sided == 1;
+
+    let transform = object_transform(object);
+    let position = transform.position;
+    let model = transform.model;
+    let inverse_model = transform.inverse_model;
+
+    var test_ray = ray;
+    test_ray.pos -= position;
+
+    // Object space, `t` is unchanged since the direction isn't normalized
+    let local_ray = Ray(inverse_model * test_ray.pos, inverse_model * test_ray.dir);
+
+    switch object.shape_type {
+        case SHAPE_SPHERE: {
+            object_hit = hit_sphere(local_ray, load_sphere(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_QUAD: {
+            object_hit = hit_quad(local_ray, 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_CUBOID: {
+            object_hit = hit_cuboid(local_ray, load_cuboid(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_DISK: {
+            object_hit = hit_disk(local_ray, load_disk(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_TRIANGLE: {
+            object_hit = hit_triangle(local_ray, load_triangle(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_PLANE: {
+            object_hit = hit_plane(local_ray, 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_CYLINDER: {
+            object_hit = hit_cylinder(local_ray, load_cylinder(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_CONE: {
+            object_hit = hit_cone(local_ray, load_cone(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_CAPSULE: {
+            object_hit = hit_capsule(local_ray, load_capsule(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_TORUS: {
+            object_hit = hit_torus(local_ray, load_torus(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_SDF: {
+            object_hit = hit_sdf(local_ray, load_sdf(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_CSG: {
+            object_hit = hit_csg(local_ray, load_csg(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_INSTANCES: {
+            object_hit = hit_instances(local_ray, load_instance_set(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_HEIGHTFIELD: {
+            object_hit = hit_heightfield(local_ray, load_heightfield(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_CURVES: {
+            object_hit = hit_curves(local_ray, load_curves(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_POINT_CLOUD: {
+            object_hit = hit_point_cloud(local_ray, load_point_cloud(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        case SHAPE_MESH: {
+            object_hit = hit_mesh(local_ray, load_mesh(object.shape_index), 0.001, hit_record.t, double_sided);
+        }
+        default: {}
+    }
+
+    if object_hit {
+        // Back to world space, normals transform with the inverse transpose
+        hit_record.p = ray.pos + hit_record.t * ray.dir;
+        hit_record.n = normalize(transpose(inverse_model) * hit_record.n);
+        if object.shape_type == SHAPE_CURVES {
+            hit_tangent = normalize(model * hit_tangent);
+        }
+        if dot(hit_record.tangent.xyz, hit_record.tangent.xyz) > 0.0 {
+            hit_record.tangent = vec4<f32>(normalize(model * hit_record.tangent.xyz), hit_record.tangent.w);
+        }
+        hit_record.material_index = object.material_index;
+        hit_record.object_index = i;
+    }
+
+    return object_hit;
+}
+
+// Moving objects are interpolated linearly, keeping them inside their swept bounds
+fn object_transform(object: Object) -> ObjectTransform {
+    if object.moving == 0u {
+        return ObjectTransform(object.position, object.model, object.inverse_model);
+    }
+
+    let model = object.previous_model + (object.model - object.previous_model) * ray_time;
+    return ObjectTransform(mix(object.previous_position, object.position, ray_time), model, inverse_mat3(model));
+}
+
+fn hit_aabb(ray: Ray, inv_dir: vec3<f32>, aabb_min: vec3<f32>, aabb_max: vec3<f32>, t_max: f32) -> bool {
+    // Slab test
+    let t0 = (aabb_min - ray.pos) * inv_dir;
+    let t1 = (aabb_max - ray.pos) * inv_dir;
+    let near = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), min(t0.z, t1.z));
+    let far = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), max(t0.z, t1.z));
+    return near <= far && far >= 0.0 && near < t_max;
+}
+
 fn hit_sphere(ray: Ray, sphere: Sphere, t_min: f32, t_max: f32, double_sided: bool) -> bool {
     let oc = ray.pos;
     let a = dot(ray.dir, ray.dir);
@@ -248,70 +844,1266 @@ fn hit_sphere(ray: Ray, sphere: Sphere, t_min: f32, t_max: f32, double_sided: bo
     let phi = atan2(-n.z, n.x) + PI;
     let uv = vec2<f32>(phi / (2 * PI), theta / PI);
 
-    hit_record = HitRecord(root, p, n, uv, front_face, -1, -1);
+    // Along increasing u, zero at the poles where it isn't defined
+    var tangent = vec4<f32>(0.0);
+    if abs(p.x) + abs(p.z) > EPSILON {
+        tangent = vec4<f32>(normalize(vec3<f32>(p.z, 0.0, -p.x)), 1.0);
+    }
+
+    hit_record = HitRecord(root, p, n, uv, tangent, vec4<f32>(1.0), front_face, -1, -1);
     return true;
 }
 
-fn hit_quad(ray: Ray, quad: Quad, t_min: f32, t_max: f32, double_sided: bool) -> bool {
-    // Plane
-    let _q = quad.model * vec3<f32>(-0.5, 0.0, -0.5);
-    let u = quad.model * vec3<f32>(0.0, 0.0, 1.0);
-    let v = quad.model * vec3<f32>(1.0, 0.0, 0.0);
-    var n = cross(u, v);
-    let _d = dot(n, _q);
-
-    // Hit Plane
-    let denom = dot(n, ray.dir);
-    if abs(denom) < EPSILON {
+fn hit_quad(ray: Ray, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    // Unit square facing +Y
+    if abs(ray.dir.y) < EPSILON {
         return false;
     }
 
-    let t = (_d - dot(n, ray.pos)) / denom;
+    let t = -ray.pos.y / ray.dir.y;
     if t <= t_min || t_max <= t {
         return false;
     }
 
     let p = ray.pos + t * ray.dir;
-    let w = n / dot(n, n);
-    let planar_hit_vector = p - _q;
-    let alpha = dot(w, cross(planar_hit_vector, v));
-    let beta = dot(w, cross(u, planar_hit_vector));
-    if alpha < 0 || 1 < alpha || beta < 0 || 1 < beta {
+    if abs(p.x) > 0.5 || abs(p.z) > 0.5 {
         return false;
     }
 
-    var record = HitRecord(t, p, n, vec2<f32>(alpha, beta), dot(ray.dir, n) < 0.0, -1, -1);
-    if !record.front_face {
+    var n = vec3<f32>(0.0, 1.0, 0.0);
+    let front_face = ray.dir.y < 0.0;
+    if !front_face {
         if !double_sided {
             return false;
         }
 
-        record.n = -record.n;
+        n = -n;
     }
 
-    hit_record = record;
+    // u runs along z and v along x
+    let uv = vec2<f32>(p.z + 0.5, p.x + 0.5);
+    let tangent = vec4<f32>(0.0, 0.0, 1.0, 1.0);
+    hit_record = HitRecord(t, p, n, uv, tangent, vec4<f32>(1.0), front_face, -1, -1);
     return true;
 }
 
-// ---- Random ----
-fn rand_u32() -> u32 {
-    rng_state = rng_state * 747796405u + 2891336453u;
-    let word = ((rng_state >> ((rng_state >> 28u) + 4u)) ^ rng_state) * 277803737u;
-    return (word >> 22u) ^ word;
+fn hit_cuboid(ray: Ray, cuboid: Cuboid, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    // Slab test
+    let inv_dir = 1.0 / ray.dir;
+    let t0 = (-cuboid.half_size - ray.pos) * inv_dir;
+    let t1 = (cuboid.half_size - ray.pos) * inv_dir;
+    let t_near = min(t0, t1);
+    let t_far = max(t0, t1);
+    let near = max(max(t_near.x, t_near.y), t_near.z);
+    let far = min(min(t_far.x, t_far.y), t_far.z);
+    if far < near {
+        return false;
+    }
+
+    // Leaving the cuboid from the inside hits a back face
+    var t = near;
+    var front_face = true;
+    if t <= t_min || t_max <= t {
+        t = far;
+        front_face = false;
+        if t <= t_min || t_max <= t {
+            return false;
+        }
+    }
+
+    if !front_face && !double_sided {
+        return false;
+    }
+
+    // The face is on the axis the hit point is furthest along
+    let p = ray.pos + t * ray.dir;
+    let d = abs(p / cuboid.half_size);
+    let face_uv = p / cuboid.half_size * 0.5 + 0.5;
+    var n: vec3<f32>;
+    var uv: vec2<f32>;
+    if d.x >= d.y && d.x >= d.z {
+        n = vec3<f32>(sign(p.x), 0.0, 0.0);
+        uv = face_uv.zy;
+    } else if d.y >= d.z {
+        n = vec3<f32>(0.0, sign(p.y), 0.0);
+        uv = face_uv.xz;
+    } else {
+        n = vec3<f32>(0.0, 0.0, sign(p.z));
+        uv = face_uv.xy;
+    }
+
+    if !front_face {
+        n = -n;
+    }
+
+    hit_record = HitRecord(t, p, n, uv, vec4<f32>(0.0), vec4<f32>(1.0), front_face, -1, -1);
+    return true;
 }
 
-fn rand_f32() -> f32 {
-    return abs(fract(f32(rand_u32()) / 3141.592653));
+fn hit_disk(ray: Ray, disk: Disk, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    // Plane facing +Y
+    if abs(ray.dir.y) < EPSILON {
+        return false;
+    }
+
+    let t = -ray.pos.y / ray.dir.y;
+    if t <= t_min || t_max <= t {
+        return false;
+    }
+
+    let p = ray.pos + t * ray.dir;
+    if dot(p.xz, p.xz) > disk.radius * disk.radius {
+        return false;
+    }
+
+    var n = vec3<f32>(0.0, 1.0, 0.0);
+    let front_face = ray.dir.y < 0.0;
+    if !front_face {
+        if !double_sided {
+            return false;
+        }
+
+        n = -n;
+    }
+
+    let uv = p.xz / disk.radius * 0.5 + 0.5;
+    hit_record = HitRecord(t, p, n, uv, vec4<f32>(0.0), vec4<f32>(1.0), front_face, -1, -1);
+    return true;
 }
 
-fn rand() -> vec3<f32> {
-    return vec3<f32>(rand_f32(), rand_f32(), rand_f32());
+fn hit_triangle(ray: Ray, triangle: Triangle, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    let tuv = intersect_triangle(ray, triangle, double_sided);
+    if tuv.x <= t_min || t_max <= tuv.x {
+        return false;
+    }
+
+    var n = normalize(cross(triangle.b - triangle.a, triangle.c - triangle.a));
+    let front_face = dot(ray.dir, n) < 0.0;
+    if !front_face {
+        n = -n;
+    }
+
+    hit_record = HitRecord(tuv.x, ray.pos + tuv.x * ray.dir, n, tuv.yz, vec4<f32>(0.0), vec4<f32>(1.0), front_face, -1, -1);
+    return true;
 }
 
-fn cosine_sample() -> vec3<f32> {
-    let phi = 2 * PI * rand_f32();
-    let sqr_sin_theta = rand_f32();
-    let sin_theta = sqrt(sqr_sin_theta);
-    let cos_theta = sqrt(1 - sqr_sin_theta);
-    return vec3<f32>(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+fn hit_plane(ray: Ray, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    // Infinite plane facing +Y
+    if abs(ray.dir.y) < EPSILON {
+        return false;
+    }
+
+    let t = -ray.pos.y / ray.dir.y;
+    if t <= t_min || t_max <= t {
+        return false;
+    }
+
+    var n = vec3<f32>(0.0, 1.0, 0.0);
+    let front_face = ray.dir.y < 0.0;
+    if !front_face {
+        if !double_sided {
+            return false;
+        }
+
+        n = -n;
+    }
+
+    let p = ray.pos + t * ray.dir;
+    hit_record = HitRecord(t, p, n, p.xz, vec4<f32>(0.0), vec4<f32>(1.0), front_face, -1, -1);
+    return true;
+}
+
+fn hit_cylinder(ray: Ray, cylinder: Cylinder, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    let r = cylinder.radius;
+    let h = cylinder.half_height;
+
+    var t = t_max;
+    var n = vec3<f32>(0.0);
+    var uv = vec2<f32>(0.0);
+
+    // Side
+    let roots = solve_quadratic(
+        dot(ray.dir.xz, ray.dir.xz),
+        dot(ray.pos.xz, ray.dir.xz),
+        dot(ray.pos.xz, ray.pos.xz) - r * r,
+    );
+    for (var i = 0; i < 2; i++) {
+        let root = roots[i];
+        let p = ray.pos + root * ray.dir;
+        if t_min < root && root < t && abs(p.y) <= h {
+            t = root;
+            n = vec3<f32>(p.x, 0.0, p.z) / r;
+            uv = vec2<f32>(revolve_u(p), (p.y + h) / (2.0 * h));
+        }
+    }
+
+    // Caps
+    if cylinder.capped == 1u && abs(ray.dir.y) > EPSILON {
+        for (var side = -1.0; side <= 1.0; side += 2.0) {
+            let root = (side * h - ray.pos.y) / ray.dir.y;
+            let p = ray.pos + root * ray.dir;
+            if t_min < root && root < t && dot(p.xz, p.xz) <= r * r {
+                t = root;
+                n = vec3<f32>(0.0, side, 0.0);
+                uv = p.xz / r * 0.5 + 0.5;
+            }
+        }
+    }
+
+    if t_max <= t {
+        return false;
+    }
+
+    return set_hit_record(ray, t, n, uv, double_sided);
+}
+
+fn hit_cone(ray: Ray, cone: Cone, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    let half_height = cone.height * 0.5;
+    let k = cone.radius / cone.height;
+    let k2 = k * k;
+
+    var t = t_max;
+    var n = vec3<f32>(0.0);
+    var uv = vec2<f32>(0.0);
+
+    // Side, `x² + z² = k² (tip - y)²`
+    let tip = half_height - ray.pos.y;
+    let roots = solve_quadratic(
+        dot(ray.dir.xz, ray.dir.xz) - k2 * ray.dir.y * ray.dir.y,
+        dot(ray.pos.xz, ray.dir.xz) + k2 * tip * ray.dir.y,
+        dot(ray.pos.xz, ray.pos.xz) - k2 * tip * tip,
+    );
+    for (var i = 0; i < 2; i++) {
+        let root = roots[i];
+        let p = ray.pos + root * ray.dir;
+        if t_min < root && root < t && abs(p.y) <= half_height {
+            t = root;
+            n = normalize(vec3<f32>(p.x, k2 * (half_height - p.y), p.z));
+            uv = vec2<f32>(revolve_u(p), (p.y + half_height) / cone.height);
+        }
+    }
+
+    // Base
+    if cone.capped == 1u && abs(ray.dir.y) > EPSILON {
+        let root = (-half_height - ray.pos.y) / ray.dir.y;
+        let p = ray.pos + root * ray.dir;
+        if t_min < root && root < t && dot(p.xz, p.xz) <= cone.radius * cone.radius {
+            t = root;
+            n = vec3<f32>(0.0, -1.0, 0.0);
+            uv = p.xz / cone.radius * 0.5 + 0.5;
+        }
+    }
+
+    if t_max <= t {
+        return false;
+    }
+
+    return set_hit_record(ray, t, n, uv, double_sided);
+}
+
+fn hit_capsule(ray: Ray, capsule: Capsule, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    let r = capsule.radius;
+    let h = capsule.half_length;
+
+    var t = t_max;
+
+    // Side
+    let side_roots = solve_quadratic(
+        dot(ray.dir.xz, ray.dir.xz),
+        dot(ray.pos.xz, ray.dir.xz),
+        dot(ray.pos.xz, ray.pos.xz) - r * r,
+    );
+    for (var i = 0; i < 2; i++) {
+        let root = side_roots[i];
+        let p = ray.pos + root * ray.dir;
+        if t_min < root && root < t && abs(p.y) <= h {
+            t = root;
+        }
+    }
+
+    // Hemispheres, only the half beyond the side counts
+    for (var side = -1.0; side <= 1.0; side += 2.0) {
+        let oc = ray.pos - vec3<f32>(0.0, side * h, 0.0);
+        let roots = solve_quadratic(dot(ray.dir, ray.dir), dot(oc, ray.dir), dot(oc, oc) - r * r);
+        for (var i = 0; i < 2; i++) {
+            let root = roots[i];
+            let p = ray.pos + root * ray.dir;
+            if t_min < root && root < t && side * p.y >= h {
+                t = root;
+            }
+        }
+    }
+
+    if t_max <= t {
+        return false;
+    }
+
+    // The normal points away from the closest point on the capsule's segment
+    let p = ray.pos + t * ray.dir;
+    let n = (p - vec3<f32>(0.0, clamp(p.y, -h, h), 0.0)) / r;
+    let uv = vec2<f32>(revolve_u(p), (p.y + h + r) / (2.0 * (h + r)));
+    return set_hit_record(ray, t, n, uv, double_sided);
+}
+
+fn hit_torus(ray: Ray, torus: Torus, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    let major = torus.major_radius;
+    let minor = torus.minor_radius;
+    let outer = major + minor;
+
+    // Solved along a unit direction from where the ray enters the bounding
+    // sphere, scaled so the sphere has unit radius, which keeps the quartic's
+    // coefficients and roots near one
+    let len = length(ray.dir);
+    let dir = ray.dir / len;
+    let bounds = solve_quadratic(1.0, dot(ray.pos, dir), dot(ray.pos, ray.pos) - outer * outer);
+    if bounds.y <= 0.0 {
+        return false;
+    }
+
+    let t_start = max(bounds.x, 0.0);
+    let lo = max(t_min * len - t_start, 0.0) / outer;
+    let hi = (min(bounds.y, t_max * len) - t_start) / outer;
+    if hi <= lo {
+        return false;
+    }
+
+    let o = (ray.pos + t_start * dir) / outer;
+    let r2 = (major / outer) * (major / outer);
+    let m = dot(o, dir);
+    let k = dot(o, o) + r2 - (minor / outer) * (minor / outer);
+    let c = array<f32, 5>(
+        k * k - 4.0 * r2 * dot(o.xz, o.xz),
+        4.0 * m * k - 8.0 * r2 * dot(o.xz, dir.xz),
+        4.0 * m * m + 2.0 * k - 4.0 * r2 * dot(dir.xz, dir.xz),
+        4.0 * m,
+        1.0,
+    );
+
+    var solutions = poly_roots(c, 4, lo, hi);
+    var s = -1.0;
+    for (var i = solutions.count - 1; i >= 0; i--) {
+        if lo < solutions.roots[i] && solutions.roots[i] < hi {
+            s = solutions.roots[i];
+        }
+    }
+
+    if s < 0.0 {
+        return false;
+    }
+
+    // The normal points away from the closest point on the tube's centre circle
+    let t = (t_start + s * outer) / len;
+    let p = ray.pos + t * ray.dir;
+    let ring = length(p.xz);
+    let n = normalize(p - major * vec3<f32>(p.x, 0.0, p.z) / max(ring, EPSILON));
+    let uv = vec2<f32>(revolve_u(p), atan2(p.y, ring - major) / (2.0 * PI) + 0.5);
+    return set_hit_record(ray, t, n, uv, double_sided);
+}
+
+fn hit_sdf(ray: Ray, shape: Sdf, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    // Only march the part of the ray inside the bounding box
+    let t0 = (-shape.half_size - ray.pos) / ray.dir;
+    let t1 = (shape.half_size - ray.pos) / ray.dir;
+    let t_near = min(t0, t1);
+    let t_far = max(t0, t1);
+    var t = max(max(max(t_near.x, t_near.y), t_near.z), t_min);
+    let far = min(min(min(t_far.x, t_far.y), t_far.z), t_max);
+    if far < t {
+        return false;
+    }
+
+    // Distances are along the ray in object space, which isn't normalized
+    let inv_len = 1.0 / length(ray.dir);
+    let start = sdf(shape.function, ray.pos + t * ray.dir, shape.params);
+    let side = select(1.0, -1.0, start < 0.0);
+    var hit = false;
+    for (var i = 0; i < SDF_MAX_STEPS; i++) {
+        let d = side * sdf(shape.function, ray.pos + t * ray.dir, shape.params);
+        if d < SDF_HIT_DISTANCE {
+            hit = true;
+            break;
+        }
+
+        t += d * inv_len;
+        if t >= far {
+            break;
+        }
+    }
+
+    if !hit || t <= t_min || t_max <= t {
+        return false;
+    }
+
+    let p = ray.pos + t * ray.dir;
+    let e = vec2<f32>(SDF_HIT_DISTANCE, 0.0);
+    let n = normalize(vec3<f32>(
+        sdf(shape.function, p + e.xyy, shape.params) - sdf(shape.function, p - e.xyy, shape.params),
+        sdf(shape.function, p + e.yxy, shape.params) - sdf(shape.function, p - e.yxy, shape.params),
+        sdf(shape.function, p + e.yyx, shape.params) - sdf(shape.function, p - e.yyx, shape.params),
+    ));
+
+    // Box projected like the cuboid
+    let d = abs(n);
+    let face_uv = p / shape.half_size * 0.5 + 0.5;
+    var uv: vec2<f32>;
+    if d.x >= d.y && d.x >= d.z {
+        uv = face_uv.zy;
+    } else if d.y >= d.z {
+        uv = face_uv.xz;
+    } else {
+        uv = face_uv.xy;
+    }
+
+    return set_hit_record(ray, t, n, uv, double_sided);
+}
+
+// Folds each shape's interval into a sorted list of disjoint intervals along the ray,
+// the nearest boundary is the hit
+fn hit_csg(ray: Ray, csg: Csg, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    var intervals: array<CsgInterval, CSG_MAX_SHAPES>;
+    var count = 0u;
+
+    for (var i = 0u; i < csg.node_count; i++) {
+        let node = load_csg_node(csg.first_node + i * CSG_NODE_SIZE);
+        let b = csg_node_interval(ray, node);
+        let b_hit = b.t_in < b.t_out;
+
+        var result: array<CsgInterval, CSG_MAX_SHAPES>;
+        var result_count = 0u;
+        if node.operation == CSG_UNION {
+            // Absorb every interval overlapping `b`, keeping the list sorted
+            var merged = b;
+            var placed = !b_hit;
+            for (var j = 0u; j < count; j++) {
+                let a = intervals[j];
+                if !b_hit || a.t_out < merged.t_in {
+                    result[result_count] = a;
+                    result_count++;
+                } else if merged.t_out < a.t_in {
+                    if !placed {
+                        result[result_count] = merged;
+                        result_count++;
+                        placed = true;
+                    }
+                    result[result_count] = a;
+                    result_count++;
+                } else {
+                    if a.t_in < merged.t_in {
+                        merged.t_in = a.t_in;
+                        merged.n_in = a.n_in;
+                    }
+                    if merged.t_out < a.t_out {
+                        merged.t_out = a.t_out;
+                        merged.n_out = a.n_out;
+                    }
+                }
+            }
+            if !placed && result_count < CSG_MAX_SHAPES {
+                result[result_count] = merged;
+                result_count++;
+            }
+        } else if node.operation == CSG_INTERSECTION {
+            for (var j = 0u; j < count && b_hit; j++) {
+                var a = intervals[j];
+                if a.t_in < b.t_in {
+                    a.t_in = b.t_in;
+                    a.n_in = b.n_in;
+                }
+                if b.t_out < a.t_out {
+                    a.t_out = b.t_out;
+                    a.n_out = b.n_out;
+                }
+                if a.t_in < a.t_out {
+                    result[result_count] = a;
+                    result_count++;
+                }
+            }
+        } else if node.operation == CSG_DIFFERENCE {
+            // `b` can split one interval in two, its surface faces the other way
+            for (var j = 0u; j < count; j++) {
+                let a = intervals[j];
+                if !b_hit || a.t_out <= b.t_in || b.t_out <= a.t_in {
+                    result[result_count] = a;
+                    result_count++;
+                    continue;
+                }
+
+                if a.t_in < b.t_in {
+                    result[result_count] = CsgInterval(a.t_in, b.t_in, a.n_in, -b.n_in);
+                    result_count++;
+                }
+                if b.t_out < a.t_out && result_count < CSG_MAX_SHAPES {
+                    result[result_count] = CsgInterval(b.t_out, a.t_out, -b.n_out, a.n_out);
+                    result_count++;
+                }
+            }
+        }
+
+        intervals = result;
+        count = result_count;
+    }
+
+    for (var j = 0u; j < count; j++) {
+        let interval = intervals[j];
+        var t = interval.t_in;
+        var n = interval.n_in;
+        if t <= t_min {
+            t = interval.t_out;
+            n = interval.n_out;
+        }
+
+        if t_min < t && t < t_max {
+            // Box projected, the solid has no natural parameterization
+            let p = ray.pos + t * ray.dir;
+            let d = abs(n);
+            var uv: vec2<f32>;
+            if d.x >= d.y && d.x >= d.z {
+                uv = fract(p.zy);
+            } else if d.y >= d.z {
+                uv = fract(p.xz);
+            } else {
+                uv = fract(p.xy);
+            }
+
+            return set_hit_record(ray, t, n, uv, double_sided);
+        }
+    }
+
+    return false;
+}
+
+// Interval of a CSG node in the CSG's space, normals are outward but not normalized
+fn csg_node_interval(ray: Ray, node: CsgNode) -> CsgInterval {
+    let local_ray = Ray(node.inverse_model * (ray.pos - node.position), node.inverse_model * ray.dir);
+
+    var interval: CsgInterval;
+    switch node.primitive_type {
+        case SHAPE_SPHERE: {
+            interval = csg_sphere_interval(local_ray, node.params.x);
+        }
+        case SHAPE_CUBOID: {
+            interval = csg_cuboid_interval(local_ray, node.params.xyz);
+        }
+        case SHAPE_CYLINDER: {
+            interval = csg_cylinder_interval(local_ray, node.params.x, node.params.y);
+        }
+        default: {
+            interval = CsgInterval(T_MAX, -T_MAX, vec3<f32>(0.0), vec3<f32>(0.0));
+        }
+    }
+
+    let normal_matrix = transpose(node.inverse_model);
+    interval.n_in = normal_matrix * interval.n_in;
+    interval.n_out = normal_matrix * interval.n_out;
+    return interval;
+}
+
+fn csg_sphere_interval(ray: Ray, radius: f32) -> CsgInterval {
+    let a = dot(ray.dir, ray.dir);
+    let half_b = dot(ray.pos, ray.dir);
+    let c = dot(ray.pos, ray.pos) - radius * radius;
+    let discriminant = half_b * half_b - a * c;
+    if discriminant <= 0.0 {
+        return CsgInterval(T_MAX, -T_MAX, vec3<f32>(0.0), vec3<f32>(0.0));
+    }
+
+    let sqrt_d = sqrt(discriminant);
+    let t_in = (-half_b - sqrt_d) / a;
+    let t_out = (-half_b + sqrt_d) / a;
+    return CsgInterval(t_in, t_out, ray.pos + t_in * ray.dir, ray.pos + t_out * ray.dir);
+}
+
+fn csg_cuboid_interval(ray: Ray, half_size: vec3<f32>) -> CsgInterval {
+    let t0 = (-half_size - ray.pos) / ray.dir;
+    let t1 = (half_size - ray.pos) / ray.dir;
+    let t_near = min(t0, t1);
+    let t_far = max(t0, t1);
+
+    // Faces are on the axis the ray crosses last on entry and first on exit
+    var interval: CsgInterval;
+    let s = sign(ray.dir);
+    if t_near.x >= t_near.y && t_near.x >= t_near.z {
+        interval.t_in = t_near.x;
+        interval.n_in = vec3<f32>(-s.x, 0.0, 0.0);
+    } else if t_near.y >= t_near.z {
+        interval.t_in = t_near.y;
+        interval.n_in = vec3<f32>(0.0, -s.y, 0.0);
+    } else {
+        interval.t_in = t_near.z;
+        interval.n_in = vec3<f32>(0.0, 0.0, -s.z);
+    }
+    if t_far.x <= t_far.y && t_far.x <= t_far.z {
+        interval.t_out = t_far.x;
+        interval.n_out = vec3<f32>(s.x, 0.0, 0.0);
+    } else if t_far.y <= t_far.z {
+        interval.t_out = t_far.y;
+        interval.n_out = vec3<f32>(0.0, s.y, 0.0);
+    } else {
+        interval.t_out = t_far.z;
+        interval.n_out = vec3<f32>(0.0, 0.0, s.z);
+    }
+
+    return interval;
+}
+
+// The infinite side clipped by the slab between the caps
+fn csg_cylinder_interval(ray: Ray, radius: f32, half_height: f32) -> CsgInterval {
+    let empty = CsgInterval(T_MAX, -T_MAX, vec3<f32>(0.0), vec3<f32>(0.0));
+    var interval = CsgInterval(-T_MAX, T_MAX, vec3<f32>(0.0), vec3<f32>(0.0));
+
+    let a = dot(ray.dir.xz, ray.dir.xz);
+    let half_b = dot(ray.pos.xz, ray.dir.xz);
+    let c = dot(ray.pos.xz, ray.pos.xz) - radius * radius;
+    if a < EPSILON {
+        if c > 0.0 {
+            return empty;
+        }
+    } else {
+        let discriminant = half_b * half_b - a * c;
+        if discriminant <= 0.0 {
+            return empty;
+        }
+
+        let sqrt_d = sqrt(discriminant);
+        interval.t_in = (-half_b - sqrt_d) / a;
+        interval.t_out = (-half_b + sqrt_d) / a;
+        let p_in = ray.pos + interval.t_in * ray.dir;
+        let p_out = ray.pos + interval.t_out * ray.dir;
+        interval.n_in = vec3<f32>(p_in.x, 0.0, p_in.z);
+        interval.n_out = vec3<f32>(p_out.x, 0.0, p_out.z);
+    }
+
+    if abs(ray.dir.y) < EPSILON {
+        if abs(ray.pos.y) > half_height {
+            return empty;
+        }
+    } else {
+        let t0 = (-half_height - ray.pos.y) / ray.dir.y;
+        let t1 = (half_height - ray.pos.y) / ray.dir.y;
+        let s = sign(ray.dir.y);
+        if interval.t_in < min(t0, t1) {
+            interval.t_in = min(t0, t1);
+            interval.n_in = vec3<f32>(0.0, -s, 0.0);
+        }
+        if max(t0, t1) < interval.t_out {
+            interval.t_out = max(t0, t1);
+            interval.n_out = vec3<f32>(0.0, s, 0.0);
+        }
+    }
+
+    return interval;
+}
+
+fn hit_instances(ray: Ray, instance_set: InstanceSet, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    let inv_dir = 1.0 / ray.dir;
+
+    var closest = t_max;
+    var hit = false;
+
+    // The set's own BVH over its instances
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size = 1u;
+    stack[0] = 0u;
+    while stack_size > 0u {
+        stack_size--;
+        let node = blas_nodes[instance_set.first_node + stack[stack_size]];
+        if !hit_aabb(ray, inv_dir, node.min, node.max, closest) {
+            continue;
+        }
+
+        if node.count == 0u {
+            if stack_size + 2u <= BVH_STACK_SIZE {
+                stack[stack_size] = node.first + 1u;
+                stack[stack_size + 1u] = node.first;
+                stack_size += 2u;
+            }
+            continue;
+        }
+
+        for (var i = node.first; i < node.first + node.count; i++) {
+            let instance = instances[instance_set.first_instance + i];
+            let local_ray = Ray(instance.inverse_model * (ray.pos - instance.position), instance.inverse_model * ray.dir);
+            if !hit_instance(local_ray, instance_set, t_min, closest, double_sided) {
+                continue;
+            }
+
+            hit = true;
+            closest = hit_record.t;
+            hit_record.n = transpose(instance.inverse_model) * hit_record.n;
+            hit_record.tangent = vec4<f32>(inverse_mat3(instance.inverse_model) * hit_record.tangent.xyz, hit_record.tangent.w);
+        }
+    }
+
+    return hit;
+}
+
+fn hit_instance(ray: Ray, instance_set: InstanceSet, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    switch instance_set.shape_type {
+        case SHAPE_SPHERE: {
+            return hit_sphere(ray, Sphere(instance_set.params.x), t_min, t_max, double_sided);
+        }
+        case SHAPE_CUBOID: {
+            return hit_cuboid(ray, Cuboid(instance_set.params.xyz), t_min, t_max, double_sided);
+        }
+        case SHAPE_MESH: {
+            return hit_mesh(ray, load_mesh(instance_set.shape_index), t_min, t_max, double_sided);
+        }
+        default: {
+            return false;
+        }
+    }
+}
+
+// Walks the cells under the ray with a 2D DDA, testing each cell as two triangles
+fn hit_heightfield(ray: Ray, heightfield: Heightfield, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    // Grid space has one unit per cell and heights in [0, 1], `t` is unchanged
+    let cells = vec2<i32>(i32(heightfield.width) - 1, i32(heightfield.depth) - 1);
+    let grid_size = vec3<f32>(f32(cells.x), 1.0, f32(cells.y));
+    let scale = grid_size / heightfield.size;
+    let grid_ray = Ray((ray.pos + vec3<f32>(0.5, 0.0, 0.5) * heightfield.size) * scale, ray.dir * scale);
+
+    let t0 = -grid_ray.pos / grid_ray.dir;
+    let t1 = (grid_size - grid_ray.pos) / grid_ray.dir;
+    let t_near = min(t0, t1);
+    let t_far = max(t0, t1);
+    var t = max(max(max(t_near.x, t_near.y), t_near.z), t_min);
+    let t_exit = min(min(min(t_far.x, t_far.y), t_far.z), t_max);
+    if t_exit < t {
+        return false;
+    }
+
+    let dir = grid_ray.dir.xz;
+    let moves = abs(dir) > vec2<f32>(EPSILON);
+    let step = vec2<i32>(sign(dir));
+    let t_delta = select(vec2<f32>(T_MAX), abs(1.0 / dir), moves);
+    var cell = clamp(vec2<i32>(floor((grid_ray.pos + t * grid_ray.dir).xz)), vec2<i32>(0), cells - 1);
+    let boundary = vec2<f32>(cell) + select(vec2<f32>(0.0), vec2<f32>(1.0), dir > vec2<f32>(0.0));
+    var t_next = select(vec2<f32>(T_MAX), (boundary - grid_ray.pos.xz) / dir, moves);
+
+    for (var i = 0; i < cells.x + cells.y + 1; i++) {
+        let cell_exit = min(min(t_next.x, t_next.y), t_exit);
+
+        // Skip cells the ray passes over
+        let h00 = heightfield_height(heightfield, cell);
+        let h10 = heightfield_height(heightfield, cell + vec2<i32>(1, 0));
+        let h01 = heightfield_height(heightfield, cell + vec2<i32>(0, 1));
+        let h11 = heightfield_height(heightfield, cell + vec2<i32>(1, 1));
+        let y_min = min(grid_ray.pos.y + t * grid_ray.dir.y, grid_ray.pos.y + cell_exit * grid_ray.dir.y);
+        if y_min <= max(max(h00, h10), max(h01, h11)) {
+            let corner = vec2<f32>(cell);
+            let a = vec3<f32>(corner.x, h00, corner.y);
+            let b = vec3<f32>(corner.x + 1.0, h10, corner.y);
+            let c = vec3<f32>(corner.x, h01, corner.y + 1.0);
+            let d = vec3<f32>(corner.x + 1.0, h11, corner.y + 1.0);
+
+            var closest = t_max;
+            var n = vec3<f32>(0.0);
+            let first = intersect_triangle(grid_ray, Triangle(a, c, b), true);
+            if t_min < first.x && first.x < closest {
+                closest = first.x;
+                n = (1.0 - first.y - first.z) * heightfield_normal(heightfield, cell)
+                    + first.y * heightfield_normal(heightfield, cell + vec2<i32>(0, 1))
+                    + first.z * heightfield_normal(heightfield, cell + vec2<i32>(1, 0));
+            }
+            let second = intersect_triangle(grid_ray, Triangle(b, c, d), true);
+            if t_min < second.x && second.x < closest {
+                closest = second.x;
+                n = (1.0 - second.y - second.z) * heightfield_normal(heightfield, cell + vec2<i32>(1, 0))
+                    + second.y * heightfield_normal(heightfield, cell + vec2<i32>(0, 1))
+                    + second.z * heightfield_normal(heightfield, cell + vec2<i32>(1, 1));
+            }
+
+            if closest < t_max {
+                // Back to object space, normals scale the opposite way to points
+                let p = grid_ray.pos + closest * grid_ray.dir;
+                let uv = p.xz / grid_size.xz;
+                return set_hit_record(ray, closest, n * scale, uv, double_sided);
+            }
+        }
+
+        if cell_exit >= t_exit {
+            break;
+        }
+
+        t = cell_exit;
+        if t_next.x < t_next.y {
+            cell.x += step.x;
+            t_next.x += t_delta.x;
+        } else {
+            cell.y += step.y;
+            t_next.y += t_delta.y;
+        }
+
+        if any(cell < vec2<i32>(0)) || any(cell >= cells) {
+            break;
+        }
+    }
+
+    return false;
+}
+
+fn heightfield_height(heightfield: Heightfield, texel: vec2<i32>) -> f32 {
+    let size = vec2<i32>(i32(heightfield.width), i32(heightfield.depth));
+    let clamped = clamp(texel, vec2<i32>(0), size - 1);
+    return heights[heightfield.first_height + u32(clamped.y * size.x + clamped.x)];
+}
+
+// Central differences in grid space
+fn heightfield_normal(heightfield: Heightfield, texel: vec2<i32>) -> vec3<f32> {
+    let dx = heightfield_height(heightfield, texel + vec2<i32>(1, 0)) - heightfield_height(heightfield, texel - vec2<i32>(1, 0));
+    let dz = heightfield_height(heightfield, texel + vec2<i32>(0, 1)) - heightfield_height(heightfield, texel - vec2<i32>(0, 1));
+    return vec3<f32>(-dx * 0.5, 1.0, -dz * 0.5);
+}
+
+fn hit_curves(ray: Ray, curves: Curves, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    let inv_dir = 1.0 / ray.dir;
+
+    var closest = t_max;
+    var hit = false;
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size = 1u;
+    stack[0] = 0u;
+    while stack_size > 0u {
+        stack_size--;
+        let node = blas_nodes[curves.first_node + stack[stack_size]];
+        if !hit_aabb(ray, inv_dir, node.min, node.max, closest) {
+            continue;
+        }
+
+        if node.count == 0u {
+            if stack_size + 2u <= BVH_STACK_SIZE {
+                stack[stack_size] = node.first + 1u;
+                stack[stack_size + 1u] = node.first;
+                stack_size += 2u;
+            }
+            continue;
+        }
+
+        for (var i = node.first; i < node.first + node.count; i++) {
+            let segment = curve_segments[curves.first_segment + i];
+            if hit_curve_segment(ray, segment, curves.style, t_min, closest, double_sided) {
+                hit = true;
+                closest = hit_record.t;
+            }
+        }
+    }
+
+    return hit;
+}
+
+// Closest approach between the ray and the segment's axis, clamping to the
+// segment gives the strand round joints
+fn hit_curve_segment(ray: Ray, segment: CurveSegment, style: u32, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    let axis = segment.b.xyz - segment.a.xyz;
+    let w = ray.pos - segment.a.xyz;
+    let dd = dot(ray.dir, ray.dir);
+    let da = dot(ray.dir, axis);
+    let aa = dot(axis, axis);
+    let denom = dd * aa - da * da;
+    if aa < EPSILON || abs(denom) < EPSILON {
+        return false;
+    }
+
+    let s = clamp((dd * dot(axis, w) - da * dot(ray.dir, w)) / denom, 0.0, 1.0);
+    var t = (s * da - dot(ray.dir, w)) / dd;
+    let offset = w + t * ray.dir - s * axis;
+    let radius = mix(segment.a.w, segment.b.w, s);
+    let distance_sq = dot(offset, offset);
+    if distance_sq > radius * radius {
+        return false;
+    }
+
+    // The ray's direction across the strand
+    let dir_across = ray.dir - da / aa * axis;
+    let across_sq = max(dot(dir_across, dir_across), EPSILON);
+
+    var n: vec3<f32>;
+    if style == CURVE_TUBE {
+        t -= sqrt((radius * radius - distance_sq) / across_sq);
+        let p = ray.pos + t * ray.dir;
+        let on_axis = segment.a.xyz + clamp(dot(p - segment.a.xyz, axis) / aa, 0.0, 1.0) * axis;
+        n = p - on_axis;
+    } else {
+        // Facing the ray, bent towards the edges to shade like a tube
+        let edge = offset - dot(offset, dir_across) / across_sq * dir_across;
+        n = -normalize(dir_across);
+        if dot(edge, edge) > EPSILON {
+            let h = sqrt(distance_sq) / radius;
+            n = normalize(edge) * h + n * sqrt(max(1.0 - h * h, 0.0));
+        }
+    }
+
+    if t <= t_min || t_max <= t {
+        return false;
+    }
+
+    let uv = vec2<f32>(s, 0.5 + 0.5 * sqrt(distance_sq) / radius);
+    if !set_hit_record(ray, t, n, uv, double_sided) {
+        return false;
+    }
+
+    hit_tangent = axis;
+    return true;
+}
+
+fn hit_point_cloud(ray: Ray, cloud: PointCloud, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    let inv_dir = 1.0 / ray.dir;
+
+    var closest = t_max;
+    var hit = false;
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size = 1u;
+    stack[0] = 0u;
+    while stack_size > 0u {
+        stack_size--;
+        let node = blas_nodes[cloud.first_node + stack[stack_size]];
+        if !hit_aabb(ray, inv_dir, node.min, node.max, closest) {
+            continue;
+        }
+
+        if node.count == 0u {
+            if stack_size + 2u <= BVH_STACK_SIZE {
+                stack[stack_size] = node.first + 1u;
+                stack[stack_size + 1u] = node.first;
+                stack_size += 2u;
+            }
+            continue;
+        }
+
+        for (var i = node.first; i < node.first + node.count; i++) {
+            let point = points[cloud.first_point + i];
+            if hit_point(ray, point, cloud.style, t_min, closest, double_sided) {
+                hit = true;
+                closest = hit_record.t;
+                hit_primitive = i;
+            }
+        }
+    }
+
+    return hit;
+}
+
+fn hit_point(ray: Ray, point: Point, style: u32, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    let oc = ray.pos - point.position;
+    let a = dot(ray.dir, ray.dir);
+    let half_b = dot(oc, ray.dir);
+
+    var t: f32;
+    var n: vec3<f32>;
+    var uv: vec2<f32>;
+    if style == POINT_DISC {
+        // Facing the ray, through the point's center
+        t = -half_b / a;
+        let offset = oc + t * ray.dir;
+        let distance_sq = dot(offset, offset);
+        if distance_sq > point.radius * point.radius {
+            return false;
+        }
+
+        n = -ray.dir;
+        uv = vec2<f32>(sqrt(distance_sq) / point.radius, 0.0);
+    } else {
+        let c = dot(oc, oc) - point.radius * point.radius;
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0.0 {
+            return false;
+        }
+
+        t = (-half_b - sqrt(discriminant)) / a;
+        if t <= t_min && double_sided {
+            t = (-half_b + sqrt(discriminant)) / a;
+        }
+        n = (oc + t * ray.dir) / point.radius;
+        uv = vec2<f32>(atan2(-n.z, n.x) / (2.0 * PI) + 0.5, acos(-n.y) / PI);
+    }
+
+    if t <= t_min || t_max <= t {
+        return false;
+    }
+    if !set_hit_record(ray, t, n, uv, double_sided) {
+        return false;
+    }
+
+    hit_record.color = point.color;
+    return true;
+}
+
+// Orients the outward normal `n` against the ray, back faces only hit when double sided
+fn set_hit_record(ray: Ray, t: f32, n: vec3<f32>, uv: vec2<f32>, double_sided: bool) -> bool {
+    let front_face = dot(ray.dir, n) < 0.0;
+    if !front_face && !double_sided {
+        return false;
+    }
+
+    hit_record = HitRecord(t, ray.pos + t * ray.dir, select(-n, n, front_face), uv, vec4<f32>(0.0), vec4<f32>(1.0), front_face, -1, -1);
+    return true;
+}
+
+// Sorted roots of `a t² + 2 half_b t + c`, both negative when there are none
+fn solve_quadratic(a: f32, half_b: f32, c: f32) -> vec2<f32> {
+    if abs(a) < EPSILON {
+        if abs(half_b) < EPSILON {
+            return vec2<f32>(-1.0);
+        }
+
+        return vec2<f32>(-c / (2.0 * half_b));
+    }
+
+    let discriminant = half_b * half_b - a * c;
+    if discriminant < 0.0 {
+        return vec2<f32>(-1.0);
+    }
+
+    let sqrt_d = sqrt(discriminant);
+    let t0 = (-half_b - sqrt_d) / a;
+    let t1 = (-half_b + sqrt_d) / a;
+    return vec2<f32>(min(t0, t1), max(t0, t1));
+}
+
+// `c[0] + c[1] t + ... + c[degree] t^degree`
+fn poly_eval(c: array<f32, 5>, degree: i32, t: f32) -> f32 {
+    var coefficients = c;
+    var result = 0.0;
+    for (var i = degree; i >= 0; i--) {
+        result = result * t + coefficients[i];
+    }
+    return result;
+}
+
+fn poly_derivative(c: array<f32, 5>, degree: i32) -> array<f32, 5> {
+    var coefficients = c;
+    var derivative = array<f32, 5>();
+    for (var i = 1; i <= degree; i++) {
+        derivative[i - 1] = f32(i) * coefficients[i];
+    }
+    return derivative;
+}
+
+// Real roots of `c` in `[lo, hi]`, isolated by the roots of each derivative in
+// turn, as the polynomial is monotone between its derivative's roots
+fn poly_roots(c: array<f32, 5>, degree: i32, lo: f32, hi: f32) -> PolyRoots {
+    var derivatives: array<array<f32, 5>, 5>;
+    derivatives[0] = c;
+    for (var k = 1; k <= degree; k++) {
+        derivatives[k] = poly_derivative(derivatives[k - 1], degree - k + 1);
+    }
+
+    // The last derivative is a constant, which has no roots
+    var roots = PolyRoots(array<f32, 4>(), 0);
+    for (var k = degree - 1; k >= 0; k--) {
+        roots = poly_roots_between(derivatives[k], derivatives[k + 1], degree - k, lo, hi, roots);
+    }
+    return roots;
+}
+
+// Roots of `c` in `[lo, hi]`, given the sorted roots of its derivative there
+fn poly_roots_between(
+    c: array<f32, 5>,
+    derivative: array<f32, 5>,
+    degree: i32,
+    lo: f32,
+    hi: f32,
+    critical: PolyRoots,
+) -> PolyRoots {
+    var critical_roots = critical.roots;
+    var roots = PolyRoots(array<f32, 4>(), 0);
+
+    var a = lo;
+    var fa = poly_eval(c, degree, a);
+    for (var i = 0; i <= critical.count; i++) {
+        var b = hi;
+        if i < critical.count {
+            b = critical_roots[i];
+        }
+        if b <= a {
+            continue;
+        }
+
+        let fb = poly_eval(c, degree, b);
+        if fa == 0.0 {
+            roots.roots[roots.count] = a;
+            roots.count += 1;
+        } else if (fa < 0.0) != (fb < 0.0) && fb != 0.0 {
+            roots.roots[roots.count] = poly_refine(c, derivative, degree, a, b, fa);
+            roots.count += 1;
+        }
+
+        a = b;
+        fa = fb;
+    }
+
+    if fa == 0.0 {
+        roots.roots[roots.count] = a;
+        roots.count += 1;
+    }
+    return roots;
+}
+
+// Newton's method, falling back to bisection whenever a step leaves the
+// bracket `[a, b]` around the root
+fn poly_refine(c: array<f32, 5>, derivative: array<f32, 5>, degree: i32, a: f32, b: f32, fa: f32) -> f32 {
+    var lo = a;
+    var hi = b;
+    var t = 0.5 * (a + b);
+    for (var i = 0; i < POLY_MAX_ITERATIONS; i++) {
+        let ft = poly_eval(c, degree, t);
+        if ft == 0.0 {
+            return t;
+        }
+
+        if (ft < 0.0) == (fa < 0.0) {
+            lo = t;
+        } else {
+            hi = t;
+        }
+
+        var next = t - ft / poly_eval(derivative, degree - 1, t);
+        if !(lo < next && next < hi) {
+            next = 0.5 * (lo + hi);
+        }
+
+        if abs(next - t) <= POLY_TOLERANCE * max(abs(t), 1.0) {
+            return next;
+        }
+        t = next;
+    }
+    return t;
+}
+
+fn inverse_mat3(m: mat3x3<f32>) -> mat3x3<f32> {
+    let r0 = cross(m[1], m[2]);
+    let r1 = cross(m[2], m[0]);
+    let r2 = cross(m[0], m[1]);
+    return transpose(mat3x3<f32>(r0, r1, r2)) * (1.0 / dot(m[0], r0));
+}
+
+// Angle around the Y axis, matching the sphere's `u`
+fn revolve_u(p: vec3<f32>) -> f32 {
+    return (atan2(-p.z, p.x) + PI) / (2.0 * PI);
+}
+
+// Returns the hit's `t` and barycentric `uv`, `t` is negative on a miss
+fn intersect_triangle(ray: Ray, triangle: Triangle, double_sided: bool) -> vec3<f32> {
+    // Möller–Trumbore
+    let e1 = triangle.b - triangle.a;
+    let e2 = triangle.c - triangle.a;
+    let p = cross(ray.dir, e2);
+    let det = dot(e1, p);
+    // Relative to the edges and direction, which scale it, so small triangles
+    // are only missed when the ray runs along them
+    if abs(det) <= EPSILON * length(e1) * length(e2) * length(ray.dir) {
+        return vec3<f32>(-1.0);
+    }
+
+    if det < 0.0 && !double_sided {
+        // Back face
+        return vec3<f32>(-1.0);
+    }
+
+    let inv_det = 1.0 / det;
+    let s = ray.pos - triangle.a;
+    let u = dot(s, p) * inv_det;
+    if u < 0.0 || 1.0 < u {
+        return vec3<f32>(-1.0);
+    }
+
+    let q = cross(s, e1);
+    let v = dot(ray.dir, q) * inv_det;
+    if v < 0.0 || 1.0 < u + v {
+        return vec3<f32>(-1.0);
+    }
+
+    return vec3<f32>(dot(e2, q) * inv_det, u, v);
+}
+
+fn hit_mesh(ray: Ray, mesh: Mesh, t_min: f32, t_max: f32, double_sided: bool) -> bool {
+    let inv_dir = 1.0 / ray.dir;
+
+    var closest = t_max;
+    var closest_index = 0u;
+    var closest_uv = vec2<f32>(0.0);
+    var hit = false;
+
+    // Bottom level BVH
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size = 1u;
+    stack[0] = 0u;
+    while stack_size > 0u {
+        stack_size--;
+        let node = blas_nodes[mesh.first_node + stack[stack_size]];
+        if !hit_aabb(ray, inv_dir, node.min, node.max, closest) {
+            continue;
+        }
+
+        if node.count == 0u {
+            if stack_size + 2u <= BVH_STACK_SIZE {
+                stack[stack_size] = node.first + 1u;
+                stack[stack_size + 1u] = node.first;
+                stack_size += 2u;
+            }
+            continue;
+        }
+
+        for (var i = node.first; i < node.first + node.count; i++) {
+            let triangle = triangles[mesh.first_triangle + i];
+
+            let tuv = intersect_triangle(ray, triangle, double_sided);
+            if tuv.x <= t_min || closest <= tuv.x {
+                continue;
+            }
+
+            hit = true;
+            closest = tuv.x;
+            closest_index = mesh.first_triangle + i;
+            closest_uv = tuv.yz;
+        }
+    }
+
+    if !hit {
+        return false;
+    }
+
+    // The face decides the side, the interpolated normal only shades
+    let triangle = triangles[closest_index];
+    let front_face = dot(ray.dir, cross(triangle.b - triangle.a, triangle.c - triangle.a)) < 0.0;
+
+    let a = vertices[closest_index * 3u];
+    let b = vertices[closest_index * 3u + 1u];
+    let c = vertices[closest_index * 3u + 2u];
+    let w = vec3<f32>(1.0 - closest_uv.x - closest_uv.y, closest_uv.x, closest_uv.y);
+
+    let n = normalize(w.x * a.normal + w.y * b.normal + w.z * c.normal);
+    let uv = w.x * a.uv + w.y * b.uv + w.z * c.uv;
+    var tangent = w.x * a.tangent + w.y * b.tangent + w.z * c.tangent;
+    if dot(tangent.xyz, tangent.xyz) > 0.0 {
+        tangent = vec4<f32>(normalize(tangent.xyz), select(-1.0, 1.0, tangent.w >= 0.0));
+    }
+    let color = w.x * a.color + w.y * b.color + w.z * c.color;
+
+    hit_record = HitRecord(closest, ray.pos + closest * ray.dir, select(-n, n, front_face), uv, tangent, color, front_face, -1, -1);
+    hit_primitive = closest_index - mesh.first_triangle;
+    return true;
+}
+
+// ---- Random ----
+fn rand_u32() -> u32 {
+    rng_state = rng_state * 747796405u + 2891336453u;
+    let word = ((rng_state >> ((rng_state >> 28u) + 4u)) ^ rng_state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+fn rand_f32() -> f32 {
+    return abs(fract(f32(rand_u32()) / 3141.592653));
+}
+
+// Box-Muller
+fn rand_normal() -> f32 {
+    let u = max(rand_f32(), EPSILON);
+    return sqrt(-2.0 * log(u)) * cos(2.0 * PI * rand_f32());
+}
+
+fn rand() -> vec3<f32> {
+    return vec3<f32>(rand_f32(), rand_f32(), rand_f32());
 }
@@ -0,0 +1,140 @@
+use crate::bvh::{Aabb, Bvh};
+
+use bevy::{
+    ecs::{entity::Entity, system::Resource},
+    utils::HashSet,
+};
+
+/// The BVH over the scene's objects, kept across frames
+///
+/// While the same objects are extracted in the same order, only the nodes
+/// above objects that changed are refit. Refitting keeps the tree's shape, so
+/// once moving objects have grown its cost by `rebuild_ratio` since the last
+/// build it's rebuilt from scratch.
+#[derive(Resource)]
+pub struct SceneBvh {
+    bvh: Bvh,
+    entities: Vec<Entity>,
+    leaves: Vec<u32>,
+    parents: Vec<u32>,
+    built_cost: f32,
+    /// Changed last frame, so their swept bounds still reach back to where they were
+    moving: HashSet<Entity>,
+    invalidated: bool,
+    rebuild_ratio: f32,
+}
+
+impl SceneBvh {
+    pub fn new(rebuild_ratio: f32) -> Self {
+        Self {
+            bvh: Bvh::default(),
+            entities: Vec::new(),
+            leaves: Vec::new(),
+            parents: Vec::new(),
+            built_cost: 0.0,
+            moving: HashSet::default(),
+            invalidated: true,
+            rebuild_ratio,
+        }
+    }
+
+    /// Rebuilds on the next update, for changes to bounds no entity is marked for,
+    /// like a mesh asset being edited
+    pub fn invalidate(&mut self) {
+        self.invalidated = true;
+    }
+
+    /// Brings the tree up to date with `bounds`, one per object of `entities`,
+    /// refitting the objects in `changed`
+    pub fn update(
+        &mut self,
+        entities: Vec<Entity>,
+        bounds: &[Aabb],
+        changed: &HashSet<Entity>,
+    ) -> &Bvh {
+        let refit = !self.invalidated && entities == self.entities;
+        if refit {
+            let dirty = entities
+                .iter()
+                .enumerate()
+                .filter(|(_, &entity)| changed.contains(&entity) || self.moving.contains(&entity))
+                .map(|(i, _)| i as u32);
+            self.bvh.refit(bounds, dirty, &self.leaves, &self.parents);
+        }
+
+        if !refit || self.bvh.cost() > self.built_cost * self.rebuild_ratio {
+            self.bvh = Bvh::build(bounds);
+            self.leaves = self.bvh.leaves();
+            self.parents = self.bvh.parents();
+            self.built_cost = self.bvh.cost();
+        }
+
+        self.entities = entities;
+        self.moving.clone_from(changed);
+        self.invalidated = false;
+        &self.bvh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bevy::math::Vec3;
+
+    fn row(offset: impl Fn(usize) -> f32) -> Vec<Aabb> {
+        (0..100)
+            .map(|i| {
+                let center = Vec3::new(i as f32 * 2.0 + offset(i), 0.0, 0.0);
+                Aabb::new(center - 0.5, center + 0.5)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn refits_until_degraded() {
+        let entities: Vec<Entity> = (0..100).map(Entity::from_raw).collect();
+        let mut scene = SceneBvh::new(1.5);
+        scene.update(entities.clone(), &row(|_| 0.0), &HashSet::default());
+        let built = scene.bvh.nodes.clone();
+
+        // A small nudge keeps the tree, only refitting it
+        let changed = HashSet::from_iter([entities[11]]);
+        let nudged = row(|i| if i == 11 { 0.25 } else { 0.0 });
+        let bvh = scene.update(entities.clone(), &nudged, &changed);
+        assert!(bvh
+            .nodes
+            .iter()
+            .zip(&built)
+            .all(|(a, b)| (a.first, a.count) == (b.first, b.count)));
+        assert_ne!(bvh.nodes, built);
+
+        // Last frame's movers are refit again as their swept bounds settle
+        let bvh = scene.update(entities.clone(), &row(|_| 0.0), &HashSet::default());
+        assert_eq!(bvh.nodes, built);
+
+        // Shuffling the row leaves every node spanning most of it
+        let shuffled = row(|i| ((i * 37 % 100) as f32 - i as f32) * 2.0);
+        let changed = HashSet::from_iter(entities.iter().copied());
+        let bvh = scene.update(entities.clone(), &shuffled, &changed);
+        assert_eq!(bvh.nodes, Bvh::build(&shuffled).nodes);
+    }
+
+    #[test]
+    fn rebuilds_when_objects_change() {
+        let entities: Vec<Entity> = (0..100).map(Entity::from_raw).collect();
+        let mut scene = SceneBvh::new(1.5);
+        scene.update(entities.clone(), &row(|_| 0.0), &HashSet::default());
+
+        // Nothing is marked changed, but with one fewer object the tree is rebuilt
+        let bounds = row(|_| 0.0);
+        let bvh = scene.update(entities[..99].to_vec(), &bounds[..99], &HashSet::default());
+        assert_eq!(bvh.nodes, Bvh::build(&bounds[..99]).nodes);
+
+        // Or when told to, for bounds changing without their entity
+        let moved = row(|i| if i == 50 { 100.0 } else { 0.0 });
+        scene.invalidate();
+        let bvh = scene.update(entities[..99].to_vec(), &moved[..99], &HashSet::default());
+        assert_eq!(bvh.nodes, Bvh::build(&moved[..99]).nodes);
+    }
+}
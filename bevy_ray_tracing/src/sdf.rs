@@ -0,0 +1,45 @@
+use std::fmt::Write;
+
+/// A WGSL signed distance function traced by [`RTSdf`](crate::RTSdf)
+///
+/// `source` must define `fn <entry_point>(p: vec3<f32>, params: array<vec4<f32>, 4>) -> f32`
+/// returning the distance from the object space point `p` to the surface,
+/// negative inside. It may define helper functions too, which share a module
+/// with every other registered function.
+#[derive(Clone)]
+pub struct SdfFunction {
+    pub entry_point: String,
+    pub source: String,
+}
+
+impl SdfFunction {
+    pub fn new(entry_point: impl Into<String>, source: impl Into<String>) -> Self {
+        Self {
+            entry_point: entry_point.into(),
+            source: source.into(),
+        }
+    }
+}
+
+/// Builds the `bevy_ray_tracing::sdf` module, dispatching to each function by its index
+pub(crate) fn sdf_module_source(functions: &[SdfFunction]) -> String {
+    let mut source = String::from("#define_import_path bevy_ray_tracing::sdf\n\n");
+    for function in functions {
+        source.push_str(&function.source);
+        source.push('\n');
+    }
+
+    source.push_str(
+        "fn sdf(function: u32, p: vec3<f32>, params: array<vec4<f32>, 4>) -> f32 {\n    switch function {\n",
+    );
+    for (i, function) in functions.iter().enumerate() {
+        let _ = writeln!(
+            source,
+            "        case {i}u: {{ return {}(p, params); }}",
+            function.entry_point
+        );
+    }
+    source.push_str("        default: { return 1e30; }\n    }\n}\n");
+
+    source
+}
@@ -1,22 +1,59 @@
 use super::{
+    arena::{BlasKey, BlasRegion, BlasRegions},
+    bvh::{Aabb, BvhNode},
+    curves::CurveBlasCache,
+    displacement::{displace, quad_blas, DisplacementCache, DisplacementKey},
+    heightfield::HeightfieldCache,
+    instances::InstanceBlasCache,
+    lbvh,
+    mesh::{MeshBlas, MeshBlasCache},
+    motion::PreviousTransforms,
+    points::PointCloudBlasCache,
+    scene::SceneBvh,
+    skin::{SkinSource, SkinSourceCache},
+    textures::TextureCache,
     types::{
-        RayTraceCamera, RayTraceEmissive, RayTraceEmissives, RayTraceMaterial, RayTraceMaterials,
-        RayTraceObject, RayTraceObjects, RayTraceQuad, RayTraceQuads, RayTraceSphere,
-        RayTraceSpheres, SHAPE_QUAD, SHAPE_SPHERE,
+        RayTraceBvhNode, RayTraceBvhNodes, RayTraceCamera, RayTraceCapsule, RayTraceCone,
+        RayTraceCsg, RayTraceCsgNode, RayTraceCuboid, RayTraceCurveSegments, RayTraceCurves,
+        RayTraceCylinder, RayTraceDisk, RayTraceEmissive, RayTraceEmissives, RayTraceHeightfield,
+        RayTraceHeights, RayTraceInstanceSet, RayTraceInstances, RayTraceJoints, RayTraceLbvh,
+        RayTraceMaterial, RayTraceMaterials, RayTraceMesh, RayTraceMorph, RayTraceMorphWeights,
+        RayTraceMorphs, RayTraceObject, RayTraceObjects, RayTracePlane, RayTracePlanes,
+        RayTracePointCloud, RayTracePoints, RayTraceSdf, RayTraceShapes, RayTraceSkin,
+        RayTraceSkinIndices, RayTraceSkinVertex, RayTraceSkinVertices, RayTraceSkins,
+        RayTraceSphere, RayTraceTextureLayers, RayTraceTorus, RayTraceTriangle, RayTraceTriangles,
+        RayTraceVertices, CSG_DIFFERENCE, CSG_INTERSECTION, CSG_UNION, CURVE_RIBBON, CURVE_TUBE,
+        LBVH_RADIX_BITS, LBVH_RADIX_SIZE, LBVH_WORKGROUP_SIZE, NORMAL_MAP_FLIP_Y,
+        NORMAL_MAP_TWO_COMPONENT, POINT_DISC, POINT_SPHERE, SHAPE_CAPSULE, SHAPE_CONE, SHAPE_CSG,
+        SHAPE_CUBOID, SHAPE_CURVES, SHAPE_CYLINDER, SHAPE_DISK, SHAPE_HEIGHTFIELD, SHAPE_INSTANCES,
+        SHAPE_MESH, SHAPE_PLANE, SHAPE_POINT_CLOUD, SHAPE_QUAD, SHAPE_SDF, SHAPE_SPHERE,
+        SHAPE_TORUS, SHAPE_TRIANGLE, SKIN_WORKGROUP_SIZE,
     },
-    GlobalRayTraceMeta, RTQuad, RTSphere, RayTracingSettings, RT_SHADER_HANDLE,
+    BvhBuilder, CsgOperation, CsgPrimitive, CurveStyle, GlobalRayTraceMeta, InstanceShape,
+    PointStyle, RTCapsule, RTCone, RTCsg, RTCuboid, RTCurves, RTCylinder, RTDisk, RTDisplacement,
+    RTHeightfield, RTInstances, RTMesh, RTPlane, RTPointCloud, RTQuad, RTSdf, RTSphere, RTTorus,
+    RTTriangle, RTUvTransform, RayTracingSettings, RT_LBVH_HANDLE, RT_SHADER_HANDLE,
+    RT_SKIN_HANDLE,
 };
 
 use bevy::{
     asset::UntypedAssetId,
     core_pipeline::fullscreen_vertex_shader::fullscreen_shader_vertex_state,
-    ecs::query::QueryItem,
+    ecs::{query::QueryItem, system::SystemParam},
+    math::{Affine2, Affine3A},
     prelude::*,
     render::{
         extract_component::ComponentUniforms,
-        render_graph::{NodeRunError, RenderGraphContext, RenderLabel, ViewNode},
+        mesh::{
+            morph::MeshMorphWeights,
+            skinning::{SkinnedMesh, SkinnedMeshInverseBindposes},
+        },
+        render_graph::{Node, NodeRunError, RenderGraphContext, RenderLabel, ViewNode},
         render_resource::{
-            binding_types::{storage_buffer, uniform_buffer},
+            binding_types::{
+                sampler, storage_buffer, storage_buffer_read_only, storage_buffer_sized,
+                texture_2d_array, uniform_buffer,
+            },
             *,
         },
         renderer::{RenderContext, RenderDevice, RenderQueue},
@@ -24,7 +61,7 @@ use bevy::{
         view::{ExtractedView, ViewTarget, ViewUniform, ViewUniforms},
         Extract,
     },
-    utils::HashMap,
+    utils::{HashMap, HashSet},
 };
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
@@ -62,7 +99,7 @@ impl ViewNode for RayTraceNode {
         };
 
         let ray_trace_meta = world.resource::<GlobalRayTraceMeta>();
-        if ray_trace_meta.camera.binding().is_none() || ray_trace_meta.spheres.binding().is_none() {
+        if ray_trace_meta.camera.binding().is_none() || ray_trace_meta.shapes.binding().is_none() {
             return Ok(());
         }
 
@@ -70,6 +107,9 @@ impl ViewNode for RayTraceNode {
         let Some(settings_binding) = settings_uniforms.uniforms().binding() else {
             return Ok(());
         };
+        let Some(texture_layers) = ray_trace_meta.texture_layers.binding() else {
+            return Ok(());
+        };
 
         let post_process = view_target.post_process_write();
         let bind_group = render_context.render_device().create_bind_group(
@@ -78,14 +118,28 @@ impl ViewNode for RayTraceNode {
             &BindGroupEntries::sequential((
                 ray_trace_meta.camera.binding().unwrap(),
                 ray_trace_meta.objects.binding().unwrap(),
+                ray_trace_meta.bvh.binding().unwrap(),
                 ray_trace_meta.emissives.binding().unwrap(),
-                ray_trace_meta.spheres.binding().unwrap(),
-                ray_trace_meta.quads.binding().unwrap(),
+                ray_trace_meta.shapes.binding().unwrap(),
+                ray_trace_meta.planes.binding().unwrap(),
+                ray_trace_meta.blas_nodes.binding().unwrap(),
+                ray_trace_meta.triangles.binding().unwrap(),
+                ray_trace_meta.vertices.binding().unwrap(),
+                ray_trace_meta.instances.binding().unwrap(),
+                ray_trace_meta.heights.binding().unwrap(),
+                ray_trace_meta.curve_segments.binding().unwrap(),
+                ray_trace_meta.points.binding().unwrap(),
                 ray_trace_meta.materials.binding().unwrap(),
                 settings_binding.clone(),
                 view_uniforms,
             )),
         );
+        let textures = world.resource::<RayTraceTextures>();
+        let texture_bind_group = render_context.render_device().create_bind_group(
+            "ray_trace_texture_bind_group",
+            &pipelines.texture_bind_group_layout,
+            &BindGroupEntries::sequential((&textures.view, &textures.sampler, texture_layers)),
+        );
 
         let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
             label: Some("ray_trace_pass"),
@@ -101,6 +155,7 @@ impl ViewNode for RayTraceNode {
 
         render_pass.set_render_pipeline(rt_pipeline);
         render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.set_bind_group(1, &texture_bind_group, &[]);
         render_pass.draw(0..3, 0..1);
 
         Ok(())
@@ -110,34 +165,80 @@ impl ViewNode for RayTraceNode {
 #[derive(Resource)]
 pub struct RayTracePipeline {
     rt_bind_group_layout: BindGroupLayout,
+    texture_bind_group_layout: BindGroupLayout,
 }
 
 impl FromWorld for RayTracePipeline {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.resource::<RenderDevice>();
-        let layout = render_device.create_bind_group_layout(
-            "ray_trace_bind_group_layout",
-            &BindGroupLayoutEntries::sequential(
-                ShaderStages::FRAGMENT,
-                (
-                    storage_buffer::<RayTraceCamera>(false),     // camera
-                    storage_buffer::<RayTraceObjects>(false),    // objects
-                    storage_buffer::<RayTraceEmissives>(false),  // emissives
-                    storage_buffer::<RayTraceSpheres>(false),    // spheres
-                    storage_buffer::<RayTraceQuads>(false),      // quads
-                    storage_buffer::<RayTraceMaterials>(false),  // materials
-                    uniform_buffer::<RayTracingSettings>(false), // settings
-                    uniform_buffer::<ViewUniform>(false),        // view
-                ),
+        let entries = BindGroupLayoutEntries::sequential(
+            ShaderStages::FRAGMENT,
+            (
+                storage_buffer::<RayTraceCamera>(false),        // camera
+                storage_buffer::<RayTraceObjects>(false),       // objects
+                storage_buffer::<RayTraceBvhNodes>(false),      // bvh
+                storage_buffer::<RayTraceEmissives>(false),     // emissives
+                storage_buffer::<RayTraceShapes>(false),        // shapes
+                storage_buffer::<RayTracePlanes>(false),        // planes
+                storage_buffer::<RayTraceBvhNodes>(false),      // blas nodes
+                storage_buffer::<RayTraceTriangles>(false),     // triangles
+                storage_buffer::<RayTraceVertices>(false),      // vertices
+                storage_buffer::<RayTraceInstances>(false),     // instances
+                storage_buffer::<RayTraceHeights>(false),       // heights
+                storage_buffer::<RayTraceCurveSegments>(false), // curve segments
+                storage_buffer::<RayTracePoints>(false),        // points
+                storage_buffer::<RayTraceMaterials>(false),     // materials
+                uniform_buffer::<RayTracingSettings>(false),    // settings
+                uniform_buffer::<ViewUniform>(false),           // view
             ),
         );
+        let texture_entries = BindGroupLayoutEntries::sequential(
+            ShaderStages::FRAGMENT,
+            (
+                texture_2d_array(TextureSampleType::Float { filterable: true }),
+                sampler(SamplerBindingType::Filtering),
+                storage_buffer::<RayTraceTextureLayers>(false),
+            ),
+        );
+
+        // Fails with a validation error deep in pipeline creation otherwise
+        let storage_buffers =
+            storage_buffer_count(&entries) + storage_buffer_count(&texture_entries);
+        let limit = render_device.limits().max_storage_buffers_per_shader_stage;
+        if storage_buffers > limit {
+            panic!(
+                "Ray tracing binds {storage_buffers} storage buffers in its fragment shader, \
+                but this device only supports {limit} per shader stage"
+            );
+        }
+
+        let layout =
+            render_device.create_bind_group_layout("ray_trace_bind_group_layout", &entries);
+        let texture_bind_group_layout = render_device
+            .create_bind_group_layout("ray_trace_texture_bind_group_layout", &texture_entries);
 
         Self {
             rt_bind_group_layout: layout,
+            texture_bind_group_layout,
         }
     }
 }
 
+fn storage_buffer_count(entries: &[BindGroupLayoutEntry]) -> u32 {
+    entries
+        .iter()
+        .filter(|entry| {
+            matches!(
+                entry.ty,
+                BindingType::Buffer {
+                    ty: BufferBindingType::Storage { .. },
+                    ..
+                }
+            )
+        })
+        .count() as u32
+}
+
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub struct RayTracePipelineKey {
     hdr: bool,
@@ -157,7 +258,10 @@ impl SpecializedRenderPipeline for RayTracePipeline {
 
         RenderPipelineDescriptor {
             label: Some("ray_trace_pipeline".into()),
-            layout: vec![self.rt_bind_group_layout.clone()],
+            layout: vec![
+                self.rt_bind_group_layout.clone(),
+                self.texture_bind_group_layout.clone(),
+            ],
             vertex: fullscreen_shader_vertex_state(),
             fragment: Some(FragmentState {
                 shader: RT_SHADER_HANDLE,
@@ -198,6 +302,517 @@ pub(super) fn prepare_rt_pipelines(
     }
 }
 
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct SkinLabel;
+
+/// Deforms skinned and morphed meshes into their BLAS copies once a frame,
+/// before any view is traced
+#[derive(Default)]
+pub struct SkinNode;
+
+impl Node for SkinNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let ray_trace_meta = world.resource::<GlobalRayTraceMeta>();
+        let skins = &ray_trace_meta.skins.get().data;
+        if skins.is_empty() || ray_trace_meta.skins.binding().is_none() {
+            return Ok(());
+        }
+
+        let pipelines = world.resource::<SkinPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let (Some(skin_pipeline), Some(refit_pipeline)) = (
+            pipeline_cache.get_compute_pipeline(pipelines.skin),
+            pipeline_cache.get_compute_pipeline(pipelines.refit),
+        ) else {
+            return Ok(());
+        };
+
+        let bind_group = render_context.render_device().create_bind_group(
+            "skin_bind_group",
+            &pipelines.bind_group_layout,
+            &BindGroupEntries::sequential((
+                ray_trace_meta.skins.binding().unwrap(),
+                ray_trace_meta.skin_vertices.binding().unwrap(),
+                ray_trace_meta.skin_corners.binding().unwrap(),
+                ray_trace_meta.morphs.binding().unwrap(),
+                ray_trace_meta.joints.binding().unwrap(),
+                ray_trace_meta.morph_weights.binding().unwrap(),
+                ray_trace_meta.skin_refit.binding().unwrap(),
+                ray_trace_meta.skin_levels.binding().unwrap(),
+                ray_trace_meta.blas_nodes.binding().unwrap(),
+                ray_trace_meta.triangles.binding().unwrap(),
+                ray_trace_meta.vertices.binding().unwrap(),
+            )),
+        );
+
+        let max_triangles = skins.iter().map(|skin| skin.triangle_count).max().unwrap();
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("skin_pass"),
+                    timestamp_writes: None,
+                });
+        pass.set_bind_group(0, &bind_group, &[]);
+
+        pass.set_pipeline(skin_pipeline);
+        pass.dispatch_workgroups(
+            max_triangles.div_ceil(SKIN_WORKGROUP_SIZE),
+            skins.len() as u32,
+            1,
+        );
+
+        pass.set_pipeline(refit_pipeline);
+        pass.dispatch_workgroups(skins.len() as u32, 1, 1);
+
+        Ok(())
+    }
+}
+
+#[derive(Resource)]
+pub struct SkinPipeline {
+    bind_group_layout: BindGroupLayout,
+    skin: CachedComputePipelineId,
+    refit: CachedComputePipelineId,
+}
+
+impl FromWorld for SkinPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let bind_group_layout = render_device.create_bind_group_layout(
+            "skin_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    storage_buffer_read_only::<RayTraceSkins>(false), // skins
+                    storage_buffer_read_only::<RayTraceSkinVertices>(false), // skin vertices
+                    storage_buffer_read_only::<RayTraceSkinIndices>(false), // skin corners
+                    storage_buffer_read_only::<RayTraceMorphs>(false), // morphs
+                    storage_buffer_read_only::<RayTraceJoints>(false), // joints
+                    storage_buffer_read_only::<RayTraceMorphWeights>(false), // morph weights
+                    storage_buffer_read_only::<RayTraceSkinIndices>(false), // skin refit
+                    storage_buffer_read_only::<RayTraceSkinIndices>(false), // skin levels
+                    storage_buffer::<RayTraceBvhNodes>(false),        // blas nodes
+                    storage_buffer::<RayTraceTriangles>(false),       // triangles
+                    storage_buffer::<RayTraceVertices>(false),        // vertices
+                ),
+            ),
+        );
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let queue = |entry_point: &'static str| {
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(format!("{entry_point}_pipeline").into()),
+                layout: vec![bind_group_layout.clone()],
+                push_constant_ranges: vec![],
+                shader: RT_SKIN_HANDLE,
+                shader_defs: vec![],
+                entry_point: entry_point.into(),
+            })
+        };
+
+        Self {
+            skin: queue("skin"),
+            refit: queue("refit"),
+            bind_group_layout,
+        }
+    }
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct LbvhLabel;
+
+/// Builds the scene's BVH from the objects' bounds when [`BvhBuilder::Gpu`]
+/// is used, before any view is traced
+#[derive(Default)]
+pub struct LbvhNode;
+
+impl Node for LbvhNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let ray_trace_meta = world.resource::<GlobalRayTraceMeta>();
+        let count = ray_trace_meta.lbvh.get().count;
+        if count < 2 || ray_trace_meta.lbvh.binding().is_none() {
+            return Ok(());
+        }
+
+        let buffers = world.resource::<LbvhBuffers>();
+        let Some(bind_group) = buffers.bind_group(
+            render_context.render_device(),
+            &world.resource::<LbvhPipeline>().bind_group_layout,
+            &ray_trace_meta.lbvh,
+            &ray_trace_meta.lbvh_leaves,
+            &ray_trace_meta.bvh,
+        ) else {
+            return Ok(());
+        };
+
+        // Extraction builds the tree on the CPU until every pipeline is ready
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let Some(pipelines) = world.resource::<LbvhPipeline>().get(pipeline_cache) else {
+            return Ok(());
+        };
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("lbvh_pass"),
+                    timestamp_writes: None,
+                });
+        dispatch_lbvh(&mut pass, &pipelines, &bind_group, count);
+
+        Ok(())
+    }
+}
+
+/// The GPU builder's stages, each an entry point of `lbvh.wgsl`
+#[derive(Clone, Copy)]
+pub enum LbvhStage {
+    Morton,
+    CountDigits,
+    ScanDigits,
+    ScatterDigits,
+    NextDigit,
+    Emit,
+    Grow,
+    Finalize,
+}
+
+impl LbvhStage {
+    pub const ALL: [Self; 8] = [
+        Self::Morton,
+        Self::CountDigits,
+        Self::ScanDigits,
+        Self::ScatterDigits,
+        Self::NextDigit,
+        Self::Emit,
+        Self::Grow,
+        Self::Finalize,
+    ];
+
+    pub fn entry_point(self) -> &'static str {
+        match self {
+            Self::Morton => "morton",
+            Self::CountDigits => "count_digits",
+            Self::ScanDigits => "scan_digits",
+            Self::ScatterDigits => "scatter_digits",
+            Self::NextDigit => "next_digit",
+            Self::Emit => "emit",
+            Self::Grow => "grow",
+            Self::Finalize => "finalize",
+        }
+    }
+}
+
+/// Records a build over `count` objects, with a pipeline per [`LbvhStage`] in order
+pub fn dispatch_lbvh<'a>(
+    pass: &mut ComputePass<'a>,
+    pipelines: &[&'a ComputePipeline],
+    bind_group: &'a BindGroup,
+    count: u32,
+) {
+    let objects = count.div_ceil(LBVH_WORKGROUP_SIZE);
+    let internal = (count - 1).div_ceil(LBVH_WORKGROUP_SIZE);
+
+    let mut dispatches = vec![(LbvhStage::Morton, objects)];
+    // A workgroup per block of keys for every digit
+    for _ in 0..32 / LBVH_RADIX_BITS {
+        dispatches.extend([
+            (LbvhStage::CountDigits, objects),
+            (LbvhStage::ScanDigits, 1),
+            (LbvhStage::ScatterDigits, objects),
+            (LbvhStage::NextDigit, 1),
+        ]);
+    }
+    dispatches.extend([
+        (LbvhStage::Emit, internal),
+        (LbvhStage::Grow, objects),
+        (LbvhStage::Finalize, internal),
+    ]);
+
+    pass.set_bind_group(0, bind_group, &[]);
+    for (stage, workgroups) in dispatches {
+        pass.set_pipeline(pipelines[stage as usize]);
+        pass.dispatch_workgroups(workgroups, 1, 1);
+    }
+}
+
+#[derive(Resource)]
+pub struct LbvhPipeline {
+    bind_group_layout: BindGroupLayout,
+    /// One per [`LbvhStage`]
+    pipelines: Vec<CachedComputePipelineId>,
+}
+
+impl LbvhPipeline {
+    pub fn layout_entries() -> BindGroupLayoutEntries<8> {
+        BindGroupLayoutEntries::sequential(
+            ShaderStages::COMPUTE,
+            (
+                storage_buffer_read_only::<RayTraceLbvh>(false), // lbvh
+                storage_buffer_read_only::<RayTraceBvhNodes>(false), // leaves
+                storage_buffer_sized(false, None),               // keys
+                storage_buffer_sized(false, None),               // values
+                storage_buffer_sized(false, None),               // tree
+                storage_buffer_sized(false, None),               // bounds
+                storage_buffer::<RayTraceBvhNodes>(false),       // bvh
+                storage_buffer_sized(false, None),               // radix
+            ),
+        )
+    }
+
+    /// Every stage's pipeline, once they've all compiled
+    pub fn get<'a>(&self, pipeline_cache: &'a PipelineCache) -> Option<Vec<&'a ComputePipeline>> {
+        self.pipelines
+            .iter()
+            .map(|&id| pipeline_cache.get_compute_pipeline(id))
+            .collect()
+    }
+}
+
+impl FromWorld for LbvhPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let bind_group_layout = render_device
+            .create_bind_group_layout("lbvh_bind_group_layout", &Self::layout_entries());
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipelines = LbvhStage::ALL
+            .map(|stage| {
+                let entry_point = stage.entry_point();
+                pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                    label: Some(format!("lbvh_{entry_point}_pipeline").into()),
+                    layout: vec![bind_group_layout.clone()],
+                    push_constant_ranges: vec![],
+                    shader: RT_LBVH_HANDLE,
+                    shader_defs: vec![],
+                    entry_point: entry_point.into(),
+                })
+            })
+            .to_vec();
+
+        Self {
+            bind_group_layout,
+            pipelines,
+        }
+    }
+}
+
+/// Scratch space only the GPU builder touches, grown to fit the scene
+#[derive(Resource, Default)]
+pub struct LbvhBuffers {
+    capacity: u32,
+    keys: Option<Buffer>,
+    values: Option<Buffer>,
+    tree: Option<Buffer>,
+    bounds: Option<Buffer>,
+    radix: Option<Buffer>,
+}
+
+impl LbvhBuffers {
+    /// Room for `count` objects, rounded up so growing scenes reallocate rarely
+    pub fn new(render_device: &RenderDevice, count: u32) -> Self {
+        let capacity = count.next_power_of_two();
+        let buffer = |label: &str, len: u32| {
+            Some(render_device.create_buffer(&BufferDescriptor {
+                label: Some(label),
+                size: len as u64 * 4,
+                usage: BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            }))
+        };
+        Self {
+            capacity,
+            // Both halves of the sort
+            keys: buffer("lbvh_keys", 2 * capacity),
+            values: buffer("lbvh_values", 2 * capacity),
+            // A parent and a slot per node
+            tree: buffer("lbvh_tree", 2 * (2 * capacity - 1)),
+            // A min and max per internal node
+            bounds: buffer("lbvh_bounds", 6 * (capacity - 1)),
+            // The digit then a count per digit per block of keys
+            radix: buffer(
+                "lbvh_radix",
+                1 + LBVH_RADIX_SIZE * capacity.div_ceil(LBVH_WORKGROUP_SIZE),
+            ),
+        }
+    }
+
+    /// Binds the scratch space with the builder's input and the BVH it writes
+    pub fn bind_group(
+        &self,
+        render_device: &RenderDevice,
+        layout: &BindGroupLayout,
+        lbvh: &StorageBuffer<RayTraceLbvh>,
+        leaves: &StorageBuffer<RayTraceBvhNodes>,
+        bvh: &StorageBuffer<RayTraceBvhNodes>,
+    ) -> Option<BindGroup> {
+        Some(render_device.create_bind_group(
+            "lbvh_bind_group",
+            layout,
+            &BindGroupEntries::sequential((
+                lbvh.binding()?,
+                leaves.binding()?,
+                self.keys.as_ref()?.as_entire_binding(),
+                self.values.as_ref()?.as_entire_binding(),
+                self.tree.as_ref()?.as_entire_binding(),
+                self.bounds.as_ref()?.as_entire_binding(),
+                bvh.binding()?,
+                self.radix.as_ref()?.as_entire_binding(),
+            )),
+        ))
+    }
+}
+
+pub(super) fn prepare_lbvh(
+    global_ray_trace_meta: Res<GlobalRayTraceMeta>,
+    mut buffers: ResMut<LbvhBuffers>,
+    render_device: Res<RenderDevice>,
+) {
+    let count = global_ray_trace_meta.lbvh.get().count;
+    if count > buffers.capacity {
+        *buffers = LbvhBuffers::new(&render_device, count);
+    }
+}
+
+/// The texture array material textures are sampled from
+#[derive(Resource)]
+pub struct RayTraceTextures {
+    texture: Texture,
+    view: TextureView,
+    sampler: Sampler,
+    /// Layers the array has room for, `0` while it's a white placeholder
+    layers: u32,
+}
+
+impl FromWorld for RayTraceTextures {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let render_queue = world.resource::<RenderQueue>();
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("ray_trace_texture_sampler"),
+            address_mode_u: AddressMode::Repeat,
+            address_mode_v: AddressMode::Repeat,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+
+        let texture = render_device.create_texture_with_data(
+            render_queue,
+            &texture_array_descriptor(1, 1),
+            TextureDataOrder::LayerMajor,
+            &[255; 4],
+        );
+        Self {
+            view: texture_array_view(&texture),
+            texture,
+            sampler,
+            layers: 0,
+        }
+    }
+}
+
+fn texture_array_descriptor(size: u32, layers: u32) -> TextureDescriptor<'static> {
+    TextureDescriptor {
+        label: Some("ray_trace_textures"),
+        size: Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: layers,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: TextureFormat::Rgba8Unorm,
+        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_SRC | TextureUsages::COPY_DST,
+        view_formats: &[],
+    }
+}
+
+fn texture_array_view(texture: &Texture) -> TextureView {
+    texture.create_view(&TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::D2Array),
+        ..default()
+    })
+}
+
+/// Writes the layers handed out this frame, growing the array by doubling
+/// when they don't fit
+pub(super) fn prepare_textures(
+    mut cache: ResMut<TextureCache>,
+    mut textures: ResMut<RayTraceTextures>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let size = cache.size();
+    let layers = cache.layer_count();
+    if layers > textures.layers {
+        let capacity = layers
+            .next_power_of_two()
+            .min(render_device.limits().max_texture_array_layers);
+        let texture = render_device.create_texture(&texture_array_descriptor(size, capacity));
+
+        // Submitted now so the layers written below land after it
+        if textures.layers > 0 {
+            let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+                label: Some("ray_trace_textures_grow"),
+            });
+            encoder.copy_texture_to_texture(
+                textures.texture.as_image_copy(),
+                texture.as_image_copy(),
+                Extent3d {
+                    width: size,
+                    height: size,
+                    depth_or_array_layers: textures.layers,
+                },
+            );
+            render_queue.submit([encoder.finish()]);
+        }
+
+        textures.view = texture_array_view(&texture);
+        textures.texture = texture;
+        textures.layers = capacity;
+    }
+
+    for (layer, data) in cache.take_writes() {
+        render_queue.write_texture(
+            ImageCopyTexture {
+                texture: &textures.texture,
+                mip_level: 0,
+                origin: Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: layer,
+                },
+                aspect: TextureAspect::All,
+            },
+            data,
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(size * 4),
+                rows_per_image: Some(size),
+            },
+            Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+}
+
 // ---- Extract ----
 pub(super) fn prepare_ray_trace(
     mut global_ray_trace_meta: ResMut<GlobalRayTraceMeta>,
@@ -210,129 +825,1186 @@ pub(super) fn prepare_ray_trace(
     global_ray_trace_meta
         .objects
         .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .bvh
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .lbvh
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .lbvh_leaves
+        .write_buffer(&render_device, &render_queue);
     global_ray_trace_meta
         .emissives
         .write_buffer(&render_device, &render_queue);
     global_ray_trace_meta
-        .spheres
+        .shapes
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .planes
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .blas_nodes
         .write_buffer(&render_device, &render_queue);
     global_ray_trace_meta
-        .quads
+        .triangles
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .vertices
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .skins
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .skin_vertices
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .skin_corners
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .morphs
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .joints
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .morph_weights
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .skin_refit
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .skin_levels
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .instances
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .heights
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .curve_segments
+        .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .points
         .write_buffer(&render_device, &render_queue);
     global_ray_trace_meta
         .materials
         .write_buffer(&render_device, &render_queue);
+    global_ray_trace_meta
+        .texture_layers
+        .write_buffer(&render_device, &render_queue);
 }
 
-pub(super) fn extract_ray_trace(
-    camera_query: Extract<
-        Query<(&Camera3d, &GlobalTransform), (Without<RTSphere>, Without<RTQuad>)>,
+type ShapeQuery<'w, 's, S> = Extract<
+    'w,
+    's,
+    Query<
+        'static,
+        'static,
+        (
+            Entity,
+            &'static S,
+            &'static Handle<StandardMaterial>,
+            &'static GlobalTransform,
+        ),
+    >,
+>;
+
+type MeshQuery<'w, 's> = Extract<
+    'w,
+    's,
+    Query<
+        'static,
+        'static,
+        (
+            Entity,
+            &'static RTMesh,
+            &'static Handle<Mesh>,
+            &'static Handle<StandardMaterial>,
+            &'static GlobalTransform,
+        ),
+        (Without<SkinnedMesh>, Without<MeshMorphWeights>),
+    >,
+>;
+
+type DisplacedQuery<'w, 's> = Extract<
+    'w,
+    's,
+    Query<
+        'static,
+        'static,
+        (
+            Entity,
+            &'static RTDisplacement,
+            Has<RTQuad>,
+            Option<&'static Handle<Mesh>>,
+            &'static Handle<StandardMaterial>,
+            &'static GlobalTransform,
+        ),
+        Or<(
+            With<RTQuad>,
+            (
+                With<RTMesh>,
+                Without<SkinnedMesh>,
+                Without<MeshMorphWeights>,
+            ),
+        )>,
     >,
-    sphere_query: Extract<
-        Query<
-            (&RTSphere, &Handle<StandardMaterial>, &GlobalTransform),
-            (Without<Camera3d>, Without<RTQuad>),
-        >,
+>;
+
+type SkinnedQuery<'w, 's> = Extract<
+    'w,
+    's,
+    Query<
+        'static,
+        'static,
+        (
+            Entity,
+            &'static Handle<Mesh>,
+            &'static Handle<StandardMaterial>,
+            &'static GlobalTransform,
+            Option<&'static SkinnedMesh>,
+            Option<&'static MeshMorphWeights>,
+        ),
+        (
+            With<RTMesh>,
+            Or<(With<SkinnedMesh>, With<MeshMorphWeights>)>,
+        ),
+    >,
+>;
+
+/// Objects whose bounds may have changed since the last extraction
+type ChangedQuery<'w, 's> = Extract<
+    'w,
+    's,
+    Query<
+        'static,
+        'static,
+        Entity,
+        Or<(
+            Changed<GlobalTransform>,
+            Changed<Handle<Mesh>>,
+            // `Or` takes at most 15 filters
+            Or<(
+                Changed<RTSphere>,
+                Changed<RTCuboid>,
+                Changed<RTDisk>,
+                Changed<RTTriangle>,
+                Changed<RTCylinder>,
+                Changed<RTCone>,
+                Changed<RTCapsule>,
+                Changed<RTTorus>,
+                Changed<RTSdf>,
+                Changed<RTCsg>,
+                Changed<RTHeightfield>,
+                Changed<RTCurves>,
+                Changed<RTPointCloud>,
+                Changed<RTInstances>,
+            )>,
+        )>,
     >,
-    quad_query: Extract<
-        Query<
-            (&RTQuad, &Handle<StandardMaterial>, &GlobalTransform),
-            (Without<Camera3d>, Without<RTSphere>),
-        >,
+>;
+
+type InstancesQuery<'w, 's> = Extract<
+    'w,
+    's,
+    Query<
+        'static,
+        'static,
+        (
+            Entity,
+            Ref<'static, RTInstances>,
+            &'static Handle<StandardMaterial>,
+            &'static GlobalTransform,
+        ),
     >,
+>;
+
+type CurvesQuery<'w, 's> = Extract<
+    'w,
+    's,
+    Query<
+        'static,
+        'static,
+        (
+            Entity,
+            Ref<'static, RTCurves>,
+            &'static Handle<StandardMaterial>,
+            &'static GlobalTransform,
+        ),
+    >,
+>;
+
+type PointCloudQuery<'w, 's> = Extract<
+    'w,
+    's,
+    Query<
+        'static,
+        'static,
+        (
+            Entity,
+            Ref<'static, RTPointCloud>,
+            &'static Handle<StandardMaterial>,
+            &'static GlobalTransform,
+        ),
+    >,
+>;
+
+#[derive(SystemParam)]
+pub(super) struct ExtractShapes<'w, 's> {
+    spheres: ShapeQuery<'w, 's, RTSphere>,
+    quads: ShapeQuery<'w, 's, RTQuad>,
+    cuboids: ShapeQuery<'w, 's, RTCuboid>,
+    disks: ShapeQuery<'w, 's, RTDisk>,
+    triangles: ShapeQuery<'w, 's, RTTriangle>,
+    planes: ShapeQuery<'w, 's, RTPlane>,
+    cylinders: ShapeQuery<'w, 's, RTCylinder>,
+    cones: ShapeQuery<'w, 's, RTCone>,
+    capsules: ShapeQuery<'w, 's, RTCapsule>,
+    tori: ShapeQuery<'w, 's, RTTorus>,
+    sdfs: ShapeQuery<'w, 's, RTSdf>,
+    csgs: ShapeQuery<'w, 's, RTCsg>,
+    meshes: MeshQuery<'w, 's>,
+    displaced: DisplacedQuery<'w, 's>,
+    skinned: SkinnedQuery<'w, 's>,
+    joints: Extract<'w, 's, Query<'static, 'static, &'static GlobalTransform>>,
+    instances: InstancesQuery<'w, 's>,
+    heightfields: ShapeQuery<'w, 's, RTHeightfield>,
+    curves: CurvesQuery<'w, 's>,
+    point_clouds: PointCloudQuery<'w, 's>,
+    uv_transforms: Extract<'w, 's, Query<'static, 'static, &'static RTUvTransform>>,
+    changed: ChangedQuery<'w, 's>,
+}
+
+#[derive(SystemParam)]
+pub(super) struct ExtractAssets<'w, 's> {
+    meshes: Extract<'w, 's, Res<'static, Assets<Mesh>>>,
+    inverse_bindposes: Extract<'w, 's, Res<'static, Assets<SkinnedMeshInverseBindposes>>>,
+    mesh_events: Extract<'w, 's, EventReader<'static, 'static, AssetEvent<Mesh>>>,
+    images: Extract<'w, 's, Res<'static, Assets<Image>>>,
+    image_events: Extract<'w, 's, EventReader<'static, 'static, AssetEvent<Image>>>,
+}
+
+#[derive(SystemParam)]
+pub(super) struct ExtractCaches<'w> {
+    meshes: ResMut<'w, MeshBlasCache>,
+    instances: ResMut<'w, InstanceBlasCache>,
+    heightfields: ResMut<'w, HeightfieldCache>,
+    curves: ResMut<'w, CurveBlasCache>,
+    point_clouds: ResMut<'w, PointCloudBlasCache>,
+    displacements: ResMut<'w, DisplacementCache>,
+    textures: ResMut<'w, TextureCache>,
+    skins: ResMut<'w, SkinSourceCache>,
+    transforms: ResMut<'w, PreviousTransforms>,
+    scene_bvh: ResMut<'w, SceneBvh>,
+    regions: ResMut<'w, BlasRegions>,
+}
+
+/// Which builder the scene's BVH asks for and whether the GPU one can run yet
+#[derive(SystemParam)]
+pub(super) struct ExtractBvhBuilder<'w> {
+    builder: Res<'w, BvhBuilder>,
+    pipeline_cache: Res<'w, PipelineCache>,
+    lbvh_pipeline: Res<'w, LbvhPipeline>,
+}
+
+impl ExtractBvhBuilder<'_> {
+    fn lbvh_ready(&self) -> bool {
+        self.lbvh_pipeline.get(&self.pipeline_cache).is_some()
+    }
+}
+
+pub(super) fn extract_ray_trace(
+    camera_query: Extract<Query<(Entity, &Camera3d, &GlobalTransform)>>,
+    shapes: ExtractShapes,
     materials: Extract<Res<Assets<StandardMaterial>>>,
+    mut assets: ExtractAssets,
+    mut caches: ExtractCaches,
+    bvh_builder: ExtractBvhBuilder,
     mut global_ray_trace_meta: ResMut<GlobalRayTraceMeta>,
 ) {
-    if let Ok((_camera, transform)) = camera_query.get_single() {
+    for event in assets.mesh_events.read() {
+        match event {
+            AssetEvent::Modified { id } | AssetEvent::Removed { id } => {
+                caches.meshes.remove(id.untyped());
+                caches.regions.remove_asset(id.untyped());
+                caches.skins.remove(id.untyped());
+                caches.displacements.remove(id.untyped());
+                caches.scene_bvh.invalidate();
+            }
+            _ => {}
+        }
+    }
+    for event in assets.image_events.read() {
+        match event {
+            AssetEvent::Modified { id } | AssetEvent::Removed { id } => {
+                caches.heightfields.remove(id.untyped());
+                caches.regions.remove_asset(id.untyped());
+                caches.displacements.remove(id.untyped());
+                caches.textures.remove(id.untyped());
+                caches.scene_bvh.invalidate();
+            }
+            _ => {}
+        }
+    }
+    caches.textures.poll_builds();
+
+    if let Ok((entity, _camera, transform)) = camera_query.get_single() {
+        let previous = GlobalTransform::from(caches.transforms.swap(entity, transform.affine()));
         global_ray_trace_meta.camera.set(RayTraceCamera {
             position: transform.translation(),
             forward: transform.forward(),
             right: transform.right(),
             up: transform.up(),
+            previous_position: previous.translation(),
+            previous_forward: previous.forward(),
+            previous_right: previous.right(),
+            previous_up: previous.up(),
         });
     }
 
+    let mut changed: HashSet<Entity> = shapes.changed.iter().collect();
+
+    // Displaced quads and meshes are traced as tessellated meshes once their
+    // depth map is loaded, and as themselves until then
+    let mut displaced = HashMap::new();
+    for (entity, displacement, quad, mesh_handle, material_handle, _) in shapes.displaced.iter() {
+        let Some(depth_map) = materials
+            .get(material_handle)
+            .and_then(|material| material.depth_map.as_ref())
+        else {
+            continue;
+        };
+        let depth_map = depth_map.id().untyped();
+        if caches
+            .heightfields
+            .get_or_build(depth_map, &assets.images)
+            .is_none()
+        {
+            continue;
+        }
+
+        let mesh = match (quad, mesh_handle) {
+            (true, _) => None,
+            (false, Some(mesh_handle)) => Some(mesh_handle.id().untyped()),
+            (false, None) => continue,
+        };
+        displaced.insert(entity, DisplacementKey::new(mesh, depth_map, displacement));
+    }
     let mut rt_objects = RayTraceObjects::default();
+    let mut object_bounds = Vec::new();
+    let mut object_entities = Vec::new();
     let mut rt_emissives = RayTraceEmissives::default();
+    let mut rt_shapes = RayTraceShapes::default();
     let mut material_handles = MaterialList::default();
+    // Emissive textures are decoded to tell whether they light anything
+    let mut is_emissive = |handle: &Handle<StandardMaterial>| {
+        emits_light(&materials, &mut caches.textures, &assets.images, handle)
+    };
+    // Every object in the BVH is pushed the same way, with bounds swept over the shutter
+    let mut push_object = |entity: Entity,
+                           transform: &GlobalTransform,
+                           shape_type: u32,
+                           index: usize,
+                           material_handle: &Handle<StandardMaterial>,
+                           local_bounds: Aabb| {
+        let matindex = material_handles.add(material_handle);
+        let previous = caches.transforms.swap(entity, transform.affine());
+        rt_objects.data.push(RayTraceObject::new(
+            transform.affine(),
+            previous,
+            shape_type,
+            index as i32,
+            matindex as i32,
+        ));
+        object_bounds.push(local_bounds.swept(&previous, &transform.affine()));
+        object_entities.push(entity);
+
+        if is_sampled_light(shape_type) && is_emissive(material_handle) {
+            rt_emissives.data.push(RayTraceEmissive {
+                index: rt_objects.data.len() as i32 - 1,
+            });
+        }
+    };
+
+    for (entity, sphere, material_handle, transform) in shapes.spheres.iter() {
+        let index = rt_shapes.push(&RayTraceSphere {
+            radius: sphere.radius,
+        });
+        push_object(
+            entity,
+            transform,
+            SHAPE_SPHERE,
+            index,
+            material_handle,
+            Aabb::new(Vec3::splat(-sphere.radius), Vec3::splat(sphere.radius)),
+        );
+    }
+
+    for (entity, _quad, material_handle, transform) in shapes.quads.iter() {
+        if displaced.contains_key(&entity) {
+            continue;
+        }
+
+        // A unit quad in object space, with nothing else to upload
+        push_object(
+            entity,
+            transform,
+            SHAPE_QUAD,
+            0,
+            material_handle,
+            Aabb::new(Vec3::new(-0.5, 0.0, -0.5), Vec3::new(0.5, 0.0, 0.5)),
+        );
+    }
+
+    for (entity, cuboid, material_handle, transform) in shapes.cuboids.iter() {
+        let index = rt_shapes.push(&RayTraceCuboid {
+            half_size: cuboid.half_size,
+        });
+        push_object(
+            entity,
+            transform,
+            SHAPE_CUBOID,
+            index,
+            material_handle,
+            Aabb::new(-cuboid.half_size, cuboid.half_size),
+        );
+    }
+
+    for (entity, disk, material_handle, transform) in shapes.disks.iter() {
+        let index = rt_shapes.push(&RayTraceDisk {
+            radius: disk.radius,
+        });
+        push_object(
+            entity,
+            transform,
+            SHAPE_DISK,
+            index,
+            material_handle,
+            Aabb::new(
+                Vec3::new(-disk.radius, 0.0, -disk.radius),
+                Vec3::new(disk.radius, 0.0, disk.radius),
+            ),
+        );
+    }
+
+    for (entity, triangle, material_handle, transform) in shapes.triangles.iter() {
+        let index = rt_shapes.push(&RayTraceTriangle {
+            a: triangle.a,
+            b: triangle.b,
+            c: triangle.c,
+        });
+        push_object(
+            entity,
+            transform,
+            SHAPE_TRIANGLE,
+            index,
+            material_handle,
+            Aabb::from_points([triangle.a, triangle.b, triangle.c]),
+        );
+    }
+
+    for (entity, cylinder, material_handle, transform) in shapes.cylinders.iter() {
+        let index = rt_shapes.push(&RayTraceCylinder {
+            radius: cylinder.radius,
+            half_height: cylinder.half_height,
+            capped: cylinder.capped as u32,
+        });
+        push_object(
+            entity,
+            transform,
+            SHAPE_CYLINDER,
+            index,
+            material_handle,
+            Aabb::new(
+                Vec3::new(-cylinder.radius, -cylinder.half_height, -cylinder.radius),
+                Vec3::new(cylinder.radius, cylinder.half_height, cylinder.radius),
+            ),
+        );
+    }
+
+    for (entity, cone, material_handle, transform) in shapes.cones.iter() {
+        let index = rt_shapes.push(&RayTraceCone {
+            radius: cone.radius,
+            height: cone.height,
+            capped: cone.capped as u32,
+        });
+        push_object(
+            entity,
+            transform,
+            SHAPE_CONE,
+            index,
+            material_handle,
+            Aabb::new(
+                Vec3::new(-cone.radius, -cone.height * 0.5, -cone.radius),
+                Vec3::new(cone.radius, cone.height * 0.5, cone.radius),
+            ),
+        );
+    }
+
+    for (entity, capsule, material_handle, transform) in shapes.capsules.iter() {
+        let index = rt_shapes.push(&RayTraceCapsule {
+            radius: capsule.radius,
+            half_length: capsule.half_length,
+        });
+        push_object(
+            entity,
+            transform,
+            SHAPE_CAPSULE,
+            index,
+            material_handle,
+            Aabb::new(
+                Vec3::new(
+                    -capsule.radius,
+                    -capsule.half_length - capsule.radius,
+                    -capsule.radius,
+                ),
+                Vec3::new(
+                    capsule.radius,
+                    capsule.half_length + capsule.radius,
+                    capsule.radius,
+                ),
+            ),
+        );
+    }
+
+    for (entity, torus, material_handle, transform) in shapes.tori.iter() {
+        let index = rt_shapes.push(&RayTraceTorus {
+            major_radius: torus.major_radius,
+            minor_radius: torus.minor_radius,
+        });
+        let outer = torus.major_radius + torus.minor_radius;
+        push_object(
+            entity,
+            transform,
+            SHAPE_TORUS,
+            index,
+            material_handle,
+            Aabb::new(
+                Vec3::new(-outer, -torus.minor_radius, -outer),
+                Vec3::new(outer, torus.minor_radius, outer),
+            ),
+        );
+    }
+
+    for (entity, sdf, material_handle, transform) in shapes.sdfs.iter() {
+        let index = rt_shapes.push(&RayTraceSdf {
+            half_size: sdf.half_size,
+            function: sdf.function,
+            params: sdf.params,
+        });
+        push_object(
+            entity,
+            transform,
+            SHAPE_SDF,
+            index,
+            material_handle,
+            Aabb::new(-sdf.half_size, sdf.half_size),
+        );
+    }
+
+    for (entity, csg, material_handle, transform) in shapes.csgs.iter() {
+        let shapes = &csg.shapes[..csg.shapes.len().min(RTCsg::MAX_SHAPES)];
+
+        // Differences can only shrink the solid
+        let mut bounds = Aabb::EMPTY;
+        for shape in shapes {
+            let shape_bounds = csg_primitive_bounds(&shape.primitive)
+                .transformed(&shape.transform.compute_affine());
+            bounds = match shape.operation {
+                CsgOperation::Union => bounds.union(shape_bounds),
+                CsgOperation::Intersection => bounds.intersection(shape_bounds),
+                CsgOperation::Difference => bounds,
+            };
+        }
+        if bounds.is_empty() {
+            continue;
+        }
+
+        let index = rt_shapes.push(&RayTraceCsg {
+            node_count: shapes.len() as u32,
+        });
+        for shape in shapes {
+            let (primitive_type, params) = match shape.primitive {
+                CsgPrimitive::Sphere(sphere) => {
+                    (SHAPE_SPHERE, Vec4::new(sphere.radius, 0.0, 0.0, 0.0))
+                }
+                CsgPrimitive::Cuboid(cuboid) => (SHAPE_CUBOID, cuboid.half_size.extend(0.0)),
+                CsgPrimitive::Cylinder(cylinder) => (
+                    SHAPE_CYLINDER,
+                    Vec4::new(cylinder.radius, cylinder.half_height, 0.0, 0.0),
+                ),
+            };
+
+            rt_shapes.push(&RayTraceCsgNode {
+                inverse_model: Mat3::from(shape.transform.compute_affine().matrix3).inverse(),
+                position: shape.transform.translation,
+                primitive_type,
+                params,
+                operation: match shape.operation {
+                    CsgOperation::Union => CSG_UNION,
+                    CsgOperation::Intersection => CSG_INTERSECTION,
+                    CsgOperation::Difference => CSG_DIFFERENCE,
+                },
+            });
+        }
+
+        push_object(entity, transform, SHAPE_CSG, index, material_handle, bounds);
+    }
+
+    for (entity, heightfield, material_handle, transform) in shapes.heightfields.iter() {
+        let id = heightfield.image.id().untyped();
+        let Some(data) = caches.heightfields.get_or_build(id, &assets.images) else {
+            continue;
+        };
+
+        // Uploaded once per image, however many heightfields share it
+        let region = caches.regions.get_or_upload(
+            BlasKey::Heightfield(id),
+            false,
+            0,
+            data.heights.len(),
+            |region| {
+                global_ray_trace_meta
+                    .heights
+                    .write(region.first_primitive, &data.heights);
+            },
+        );
+
+        let index = rt_shapes.push(&RayTraceHeightfield {
+            size: heightfield.size,
+            first_height: region.first_primitive,
+            width: data.width,
+            depth: data.depth,
+        });
+        let half_size = heightfield.size * 0.5;
+        push_object(
+            entity,
+            transform,
+            SHAPE_HEIGHTFIELD,
+            index,
+            material_handle,
+            Aabb::new(
+                Vec3::new(-half_size.x, data.min * heightfield.size.y, -half_size.z),
+                Vec3::new(half_size.x, data.max * heightfield.size.y, half_size.z),
+            ),
+        );
+    }
 
     {
-        let spheres: Vec<RayTraceSphere> = sphere_query
-            .iter()
-            .enumerate()
-            .map(|(i, (sphere, material_handle, transform))| {
-                let matindex = material_handles.add(material_handle);
-                rt_objects.data.push(RayTraceObject {
-                    position: transform.translation(),
-                    shape_type: SHAPE_SPHERE,
-                    shape_index: i as i32,
-                    material_index: matindex as i32,
-                });
+        let mut mesh_list = MeshList::default();
+        for (entity, _mesh, mesh_handle, material_handle, transform) in shapes.meshes.iter() {
+            if displaced.contains_key(&entity) {
+                continue;
+            }
 
-                let emissive_color = materials
-                    .get(material_handle)
-                    .expect("Missing Material Asset")
-                    .emissive;
-                if emissive_color.r() > f32::EPSILON
-                    || emissive_color.g() > f32::EPSILON
-                    || emissive_color.b() > f32::EPSILON
-                {
-                    rt_emissives.data.push(RayTraceEmissive {
-                        index: rt_objects.data.len() as i32 - 1,
-                    });
+            let id = mesh_handle.id().untyped();
+            let Some(blas) = caches.meshes.get_or_build(id, &assets.meshes) else {
+                continue;
+            };
+            let mesh_index = mesh_list.add(
+                BlasKey::Mesh(id),
+                blas,
+                &mut caches.regions,
+                &mut global_ray_trace_meta,
+                &mut rt_shapes,
+            );
+
+            push_object(
+                entity,
+                transform,
+                SHAPE_MESH,
+                mesh_list.shape_indices[mesh_index],
+                material_handle,
+                mesh_list.bounds[mesh_index],
+            );
+        }
+
+        // Entities sharing a mesh, depth map and displacement share its BLAS
+        let mut displaced_list = HashMap::new();
+        for (entity, displacement, _quad, _mesh, material_handle, transform) in
+            shapes.displaced.iter()
+        {
+            let Some(&key) = displaced.get(&entity) else {
+                continue;
+            };
+
+            let mesh_index = match displaced_list.get(&key) {
+                Some(&mesh_index) => mesh_index,
+                None => {
+                    if !caches.displacements.contains(&key) {
+                        // New bounds the entity wasn't marked changed for
+                        caches.scene_bvh.invalidate();
+                        caches.regions.remove(BlasKey::Displaced(key));
+                    }
+
+                    let Some(blas) = caches.displacements.get_or_build(key, || {
+                        let depth = caches
+                            .heightfields
+                            .get_or_build(key.depth_map, &assets.images)?;
+                        let source = match key.mesh {
+                            Some(id) => caches.meshes.get_or_build(id, &assets.meshes)?,
+                            None => &quad_blas(),
+                        };
+                        Some(displace(source, depth, displacement))
+                    }) else {
+                        continue;
+                    };
+                    if blas.bounds().is_empty() {
+                        continue;
+                    }
+
+                    let mesh_index = mesh_list.add(
+                        BlasKey::Displaced(key),
+                        blas,
+                        &mut caches.regions,
+                        &mut global_ray_trace_meta,
+                        &mut rt_shapes,
+                    );
+                    displaced_list.insert(key, mesh_index);
+                    mesh_index
                 }
+            };
+
+            push_object(
+                entity,
+                transform,
+                SHAPE_MESH,
+                mesh_list.shape_indices[mesh_index],
+                material_handle,
+                mesh_list.bounds[mesh_index],
+            );
+        }
+        caches
+            .displacements
+            .retain(|key| displaced_list.contains_key(key));
 
-                RayTraceSphere {
-                    radius: sphere.radius,
+        // Instance BVHs share the BLAS nodes with meshes
+        for (entity, rt_instances, material_handle, transform) in shapes.instances.iter() {
+            let (shape_type, shape_index, params, shape_bounds) = match &rt_instances.shape {
+                InstanceShape::Sphere(sphere) => (
+                    SHAPE_SPHERE,
+                    -1,
+                    Vec4::new(sphere.radius, 0.0, 0.0, 0.0),
+                    Aabb::new(Vec3::splat(-sphere.radius), Vec3::splat(sphere.radius)),
+                ),
+                InstanceShape::Cuboid(cuboid) => (
+                    SHAPE_CUBOID,
+                    -1,
+                    cuboid.half_size.extend(0.0),
+                    Aabb::new(-cuboid.half_size, cuboid.half_size),
+                ),
+                InstanceShape::Mesh(mesh_handle) => {
+                    let id = mesh_handle.id().untyped();
+                    let Some(blas) = caches.meshes.get_or_build(id, &assets.meshes) else {
+                        continue;
+                    };
+                    let mesh_index = mesh_list.add(
+                        BlasKey::Mesh(id),
+                        blas,
+                        &mut caches.regions,
+                        &mut global_ray_trace_meta,
+                        &mut rt_shapes,
+                    );
+
+                    (
+                        SHAPE_MESH,
+                        mesh_list.shape_indices[mesh_index] as i32,
+                        Vec4::ZERO,
+                        mesh_list.bounds[mesh_index],
+                    )
                 }
-            })
-            .collect();
+            };
+
+            let (blas, built) = caches.instances.get_or_build(
+                entity,
+                rt_instances.is_changed(),
+                &rt_instances.transforms,
+                shape_bounds,
+            );
+            if blas.bounds().is_empty() {
+                continue;
+            }
+
+            let region = caches.regions.get_or_upload(
+                BlasKey::Instances(entity),
+                built,
+                blas.nodes.len(),
+                blas.instances.len(),
+                |region| {
+                    upload_nodes(&mut global_ray_trace_meta, region, &blas.nodes);
+                    global_ray_trace_meta
+                        .instances
+                        .write(region.first_primitive, &blas.instances);
+                },
+            );
+            let index = rt_shapes.push(&RayTraceInstanceSet {
+                first_node: region.first_node,
+                first_instance: region.first_primitive,
+                shape_type,
+                shape_index,
+                params,
+            });
+            push_object(
+                entity,
+                transform,
+                SHAPE_INSTANCES,
+                index,
+                material_handle,
+                blas.bounds(),
+            );
+        }
+        caches
+            .instances
+            .retain(|entity| shapes.instances.contains(entity));
+
+        // Curve BVHs share the BLAS nodes too
+        for (entity, rt_curves, material_handle, transform) in shapes.curves.iter() {
+            let (blas, built) =
+                caches
+                    .curves
+                    .get_or_build(entity, rt_curves.is_changed(), &rt_curves);
+            if blas.bounds().is_empty() {
+                continue;
+            }
+
+            let region = caches.regions.get_or_upload(
+                BlasKey::Curves(entity),
+                built,
+                blas.nodes.len(),
+                blas.segments.len(),
+                |region| {
+                    upload_nodes(&mut global_ray_trace_meta, region, &blas.nodes);
+                    global_ray_trace_meta
+                        .curve_segments
+                        .write(region.first_primitive, &blas.segments);
+                },
+            );
+            let index = rt_shapes.push(&RayTraceCurves {
+                first_node: region.first_node,
+                first_segment: region.first_primitive,
+                style: match rt_curves.style {
+                    CurveStyle::Ribbon => CURVE_RIBBON,
+                    CurveStyle::Tube => CURVE_TUBE,
+                },
+            });
+            push_object(
+                entity,
+                transform,
+                SHAPE_CURVES,
+                index,
+                material_handle,
+                blas.bounds(),
+            );
+        }
+        caches
+            .curves
+            .retain(|entity| shapes.curves.contains(entity));
+
+        // Point clouds too, a BVH over each cloud's points
+        for (entity, cloud, material_handle, transform) in shapes.point_clouds.iter() {
+            let (blas, built) =
+                caches
+                    .point_clouds
+                    .get_or_build(entity, cloud.is_changed(), &cloud);
+            if blas.bounds().is_empty() {
+                continue;
+            }
+
+            let region = caches.regions.get_or_upload(
+                BlasKey::PointCloud(entity),
+                built,
+                blas.nodes.len(),
+                blas.points.len(),
+                |region| {
+                    upload_nodes(&mut global_ray_trace_meta, region, &blas.nodes);
+                    global_ray_trace_meta
+                        .points
+                        .write(region.first_primitive, &blas.points);
+                },
+            );
+            let index = rt_shapes.push(&RayTracePointCloud {
+                first_node: region.first_node,
+                first_point: region.first_primitive,
+                style: match cloud.style {
+                    PointStyle::Sphere => POINT_SPHERE,
+                    PointStyle::Disc => POINT_DISC,
+                },
+                point_count: region.primitive_count,
+            });
+            push_object(
+                entity,
+                transform,
+                SHAPE_POINT_CLOUD,
+                index,
+                material_handle,
+                blas.bounds(),
+            );
+        }
+        caches
+            .point_clouds
+            .retain(|entity| shapes.point_clouds.contains(entity));
+
+        // Skinned and morphed meshes each get their own copy of their mesh's
+        // BLAS, which the skinning pass deforms into world space
+        let mut skins = Vec::new();
+        let mut skin_sources = SkinSourceList::default();
+        let mut joints = Vec::new();
+        let mut morph_weights = Vec::new();
+        for (entity, mesh_handle, material_handle, transform, skinned_mesh, mesh_morph_weights) in
+            shapes.skinned.iter()
+        {
+            let id = mesh_handle.id().untyped();
+            let Some(mesh) = assets.meshes.get(mesh_handle) else {
+                continue;
+            };
+            let Some(blas) = caches.meshes.get_or_build(id, &assets.meshes) else {
+                continue;
+            };
+            if blas.bounds().is_empty() {
+                continue;
+            }
+            let Some(source) = caches.skins.get_or_build(id, mesh, blas, &assets.images) else {
+                continue;
+            };
+
+            let skin_joints: Option<Vec<Affine3A>> = match skinned_mesh {
+                Some(skinned_mesh) => {
+                    let Some(inverse_bindposes) = assets
+                        .inverse_bindposes
+                        .get(&skinned_mesh.inverse_bindposes)
+                    else {
+                        continue;
+                    };
+                    Some(
+                        skinned_mesh
+                            .joints
+                            .iter()
+                            .zip(inverse_bindposes.iter())
+                            .map(|(&joint, inverse_bindpose)| {
+                                let joint = shapes
+                                    .joints
+                                    .get(joint)
+                                    .map_or(Affine3A::IDENTITY, GlobalTransform::affine);
+                                joint * Affine3A::from_mat4(*inverse_bindpose)
+                            })
+                            .collect(),
+                    )
+                }
+                None => None,
+            };
+            let weights = mesh_morph_weights.map_or(&[][..], MeshMorphWeights::weights);
+            let weights = &weights[..weights.len().min(source.morph_count as usize)];
 
+            let mesh_index = mesh_list.add(
+                BlasKey::Skinned(entity, id),
+                blas,
+                &mut caches.regions,
+                &mut global_ray_trace_meta,
+                &mut rt_shapes,
+            );
+            let copy = mesh_list.list[mesh_index];
+            skins.push(RayTraceSkin {
+                morph_count: weights.len() as u32,
+                first_weight: morph_weights.len() as u32,
+                first_joint: joints.len() as u32,
+                skinned: skin_joints.is_some() as u32,
+                first_node: copy.first_node,
+                first_triangle: copy.first_triangle,
+                triangle_count: blas.triangles.len() as u32,
+                ..skin_sources.add(id, source)
+            });
+            morph_weights.extend_from_slice(weights);
+            match &skin_joints {
+                Some(skin_joints) => {
+                    joints.extend(skin_joints.iter().map(|&joint| Mat4::from(joint)))
+                }
+                None => joints.push(transform.compute_matrix()),
+            }
+
+            // Already in world space, so not motion blurred
+            let matindex = material_handles.add(material_handle);
+            rt_objects.data.push(RayTraceObject::new(
+                Affine3A::IDENTITY,
+                Affine3A::IDENTITY,
+                SHAPE_MESH,
+                mesh_list.shape_indices[mesh_index] as i32,
+                matindex as i32,
+            ));
+            if is_emissive(material_handle) {
+                rt_emissives.data.push(RayTraceEmissive {
+                    index: rt_objects.data.len() as i32 - 1,
+                });
+            }
+            // Posed by joints elsewhere in the hierarchy, so refit every frame
+            changed.insert(entity);
+            object_bounds.push(source.deformed_bounds(
+                skin_joints.as_deref(),
+                &transform.affine(),
+                weights,
+            ));
+            object_entities.push(entity);
+        }
+
+        global_ray_trace_meta
+            .skins
+            .set(RayTraceSkins { data: skins });
+        skin_sources.set(&mut global_ray_trace_meta);
+        global_ray_trace_meta
+            .joints
+            .set(RayTraceJoints { data: joints });
         global_ray_trace_meta
-            .spheres
-            .set(RayTraceSpheres { data: spheres });
+            .morph_weights
+            .set(RayTraceMorphWeights {
+                data: morph_weights,
+            });
     }
 
+    // Objects with their own UV transform get their own copy of their material
+    for (object, entity) in rt_objects.data.iter_mut().zip(&object_entities) {
+        if let Ok(uv_transform) = shapes.uv_transforms.get(*entity) {
+            object.material_index =
+                material_handles.transformed(object.material_index as usize, uv_transform.0) as i32;
+        }
+    }
+
+    let gpu_build = *bvh_builder.builder == BvhBuilder::Gpu && object_bounds.len() > 1;
+    let bvh_nodes = if gpu_build && !bvh_builder.lbvh_ready() {
+        // `LbvhNode` is skipped until its pipelines are ready, so the same tree
+        // is built here in the meantime
+        global_ray_trace_meta.lbvh.set(RayTraceLbvh::default());
+        global_ray_trace_meta
+            .lbvh_leaves
+            .set(RayTraceBvhNodes::default());
+
+        lbvh::build(&object_bounds)
+            .nodes
+            .iter()
+            .map(RayTraceBvhNode::from)
+            .collect()
+    } else if gpu_build {
+        // Each leaf holds one object by its index, so the objects stay where they are
+        let centroids = Aabb::from_points(
+            object_bounds
+                .iter()
+                .filter(|bounds| !bounds.is_empty())
+                .map(Aabb::center),
+        );
+        global_ray_trace_meta.lbvh.set(RayTraceLbvh {
+            centroid_min: centroids.min,
+            count: object_bounds.len() as u32,
+            centroid_max: centroids.max,
+        });
+        global_ray_trace_meta.lbvh_leaves.set(RayTraceBvhNodes {
+            data: object_bounds
+                .iter()
+                .enumerate()
+                .map(|(i, bounds)| {
+                    RayTraceBvhNode::from(&BvhNode {
+                        bounds: *bounds,
+                        first: i as u32,
+                        count: 1,
+                    })
+                })
+                .collect(),
+        });
+
+        // Written by `LbvhNode`
+        let empty = RayTraceBvhNode::from(&BvhNode {
+            bounds: Aabb::EMPTY,
+            first: 0,
+            count: 0,
+        });
+        vec![empty; 2 * object_bounds.len() - 1]
+    } else {
+        global_ray_trace_meta.lbvh.set(RayTraceLbvh::default());
+        global_ray_trace_meta
+            .lbvh_leaves
+            .set(RayTraceBvhNodes::default());
+
+        // Objects are stored in leaf order so the leaves can index them directly
+        let bvh = caches
+            .scene_bvh
+            .update(object_entities, &object_bounds, &changed);
+        let mut leaf_order = vec![0; bvh.indices.len()];
+        rt_objects.data = bvh
+            .indices
+            .iter()
+            .enumerate()
+            .map(|(i, &object)| {
+                leaf_order[object as usize] = i as i32;
+                rt_objects.data[object as usize]
+            })
+            .collect();
+        for emissive in rt_emissives.data.iter_mut() {
+            emissive.index = leaf_order[emissive.index as usize];
+        }
+
+        bvh.nodes.iter().map(RayTraceBvhNode::from).collect()
+    };
+
+    // Planes are unbounded so they're kept out of the BVH and tested on their own
     {
-        let quads: Vec<RayTraceQuad> = quad_query
+        let planes: Vec<RayTracePlane> = shapes
+            .planes
             .iter()
             .enumerate()
-            .map(|(i, (_quad, material_handle, transform))| {
+            .map(|(i, (entity, _plane, material_handle, transform))| {
                 let matindex = material_handles.add(material_handle);
-                rt_objects.data.push(RayTraceObject {
-                    position: transform.translation(),
-                    shape_type: SHAPE_QUAD,
-                    shape_index: i as i32,
-                    material_index: matindex as i32,
-                });
+                let previous = caches.transforms.swap(entity, transform.affine());
+                rt_objects.data.push(RayTraceObject::new(
+                    transform.affine(),
+                    previous,
+                    SHAPE_PLANE,
+                    i as i32,
+                    matindex as i32,
+                ));
 
-                let emissive_color = materials
-                    .get(material_handle)
-                    .expect("Missing Material Asset")
-                    .emissive;
-                if emissive_color.r() > f32::EPSILON
-                    || emissive_color.g() > f32::EPSILON
-                    || emissive_color.b() > f32::EPSILON
-                {
+                if is_emissive(material_handle) {
                     rt_emissives.data.push(RayTraceEmissive {
                         index: rt_objects.data.len() as i32 - 1,
                     });
                 }
 
-                RayTraceQuad {
-                    model: transform.affine().matrix3.into(),
+                RayTracePlane {
+                    object_index: rt_objects.data.len() as i32 - 1,
                 }
             })
             .collect();
 
         global_ray_trace_meta
-            .quads
-            .set(RayTraceQuads { data: quads });
+            .planes
+            .set(RayTracePlanes { data: planes });
     }
 
     let mut rt_materials = RayTraceMaterials::default();
-    for handle in material_handles.list {
-        let material = materials.get(handle).unwrap();
+    for (handle, uv_transform) in material_handles.list {
+        let material = materials.get(&handle).unwrap();
+
+        // Two channel normal maps leave z to be reconstructed, as Bevy does
+        let mut normal_map_flags = 0;
+        if material.flip_normal_map_y {
+            normal_map_flags |= NORMAL_MAP_FLIP_Y;
+        }
+        if let Some(normal_map) = material
+            .normal_map_texture
+            .as_ref()
+            .and_then(|texture| assets.images.get(texture))
+        {
+            if matches!(
+                normal_map.texture_descriptor.format,
+                TextureFormat::Rg8Unorm | TextureFormat::Rg16Unorm
+            ) {
+                normal_map_flags |= NORMAL_MAP_TWO_COMPONENT;
+            }
+        }
+
+        let mut texture = |texture: &Option<Handle<Image>>| {
+            texture
+                .as_ref()
+                .map_or(-1, |texture| caches.textures.layer(texture, &assets.images))
+        };
 
         rt_materials.data.push(RayTraceMaterial {
             color: material.base_color.rgba_to_vec4(),
@@ -342,32 +2014,228 @@ pub(super) fn extract_ray_trace(
             diffuse_transmission: material.diffuse_transmission,
             specular_transmission: material.specular_transmission,
             ior: material.ior,
+            reflectance: material.reflectance,
             double_sided: material.double_sided as u32,
+            base_color_texture: texture(&material.base_color_texture),
+            normal_map_texture: texture(&material.normal_map_texture),
+            normal_map_flags,
+            metallic_roughness_texture: texture(&material.metallic_roughness_texture),
+            emissive_texture: texture(&material.emissive_texture),
+            occlusion_texture: texture(&material.occlusion_texture),
+            uv_transform: Mat3::from(uv_transform),
         });
     }
+    caches.textures.end_frame();
+    global_ray_trace_meta
+        .texture_layers
+        .set(RayTraceTextureLayers {
+            data: caches.textures.srgb_layers(),
+        });
 
+    global_ray_trace_meta
+        .bvh
+        .set(RayTraceBvhNodes { data: bvh_nodes });
     global_ray_trace_meta.materials.set(rt_materials);
     global_ray_trace_meta.objects.set(rt_objects);
     global_ray_trace_meta.emissives.set(rt_emissives);
+    global_ray_trace_meta.shapes.set(rt_shapes);
+
+    caches.regions.end_frame();
+    caches.transforms.end_frame();
+}
+
+/// Whether lights should be sampled towards objects with this material
+///
+/// Shapes `sample_emitter` picks points on, emissive objects of other shapes
+/// only light what bounces into them
+fn is_sampled_light(shape_type: u32) -> bool {
+    matches!(
+        shape_type,
+        SHAPE_SPHERE
+            | SHAPE_QUAD
+            | SHAPE_CUBOID
+            | SHAPE_DISK
+            | SHAPE_TRIANGLE
+            | SHAPE_PLANE
+            | SHAPE_CYLINDER
+            | SHAPE_CONE
+            | SHAPE_CAPSULE
+            | SHAPE_TORUS
+            | SHAPE_POINT_CLOUD
+            | SHAPE_MESH
+    )
+}
+
+/// The emissive texture scales the emissive color, so once it's loaded an all
+/// black one turns the material off.
+fn emits_light(
+    materials: &Assets<StandardMaterial>,
+    textures: &mut TextureCache,
+    images: &Assets<Image>,
+    handle: &Handle<StandardMaterial>,
+) -> bool {
+    let material = materials.get(handle).expect("Missing Material Asset");
+    let emissive_color = material.emissive;
+    let lit = emissive_color.r() > f32::EPSILON
+        || emissive_color.g() > f32::EPSILON
+        || emissive_color.b() > f32::EPSILON;
+
+    lit && material
+        .emissive_texture
+        .as_ref()
+        .and_then(|texture| textures.is_black(texture, images))
+        != Some(true)
+}
+
+fn csg_primitive_bounds(primitive: &CsgPrimitive) -> Aabb {
+    let half_size = match primitive {
+        CsgPrimitive::Sphere(sphere) => Vec3::splat(sphere.radius),
+        CsgPrimitive::Cuboid(cuboid) => cuboid.half_size,
+        CsgPrimitive::Cylinder(cylinder) => {
+            Vec3::new(cylinder.radius, cylinder.half_height, cylinder.radius)
+        }
+    };
+
+    Aabb::new(-half_size, half_size)
 }
 
 #[derive(Default)]
 struct MaterialList {
-    list: Vec<Handle<StandardMaterial>>,
-    map: HashMap<UntypedAssetId, usize>,
+    list: Vec<(Handle<StandardMaterial>, Affine2)>,
+    /// Keyed by the material and the bits of its UV transform
+    map: HashMap<(UntypedAssetId, [u32; 6]), usize>,
 }
 
 impl MaterialList {
     pub fn add(&mut self, mat: &Handle<StandardMaterial>) -> usize {
-        let id = mat.id().untyped();
+        self.add_transformed(mat, Affine2::IDENTITY)
+    }
 
-        if let Some(index) = self.map.get(&id) {
+    /// The material at `index` with its UVs transformed by `uv_transform` instead
+    pub fn transformed(&mut self, index: usize, uv_transform: Affine2) -> usize {
+        let mat = self.list[index].0.clone();
+        self.add_transformed(&mat, uv_transform)
+    }
+
+    fn add_transformed(&mut self, mat: &Handle<StandardMaterial>, uv_transform: Affine2) -> usize {
+        let key = (
+            mat.id().untyped(),
+            uv_transform.to_cols_array().map(f32::to_bits),
+        );
+
+        if let Some(index) = self.map.get(&key) {
             *index
         } else {
             let index = self.list.len();
-            self.list.push(mat.clone());
-            self.map.insert(id, index);
+            self.list.push((mat.clone(), uv_transform));
+            self.map.insert(key, index);
             index
         }
     }
 }
+
+#[derive(Default)]
+struct MeshList {
+    list: Vec<RayTraceMesh>,
+    bounds: Vec<Aabb>,
+    /// Where each mesh is in the shapes buffer
+    shape_indices: Vec<usize>,
+    map: HashMap<BlasKey, usize>,
+}
+
+impl MeshList {
+    /// Returns the index of the BLAS in this frame's meshes, uploading it the
+    /// first time it's seen since it was built
+    pub fn add(
+        &mut self,
+        key: BlasKey,
+        blas: &MeshBlas,
+        regions: &mut BlasRegions,
+        meta: &mut GlobalRayTraceMeta,
+        shapes: &mut RayTraceShapes,
+    ) -> usize {
+        if let Some(index) = self.map.get(&key) {
+            return *index;
+        }
+
+        let region = regions.get_or_upload(
+            key,
+            false,
+            blas.nodes.len(),
+            blas.triangles.len(),
+            |region| {
+                upload_nodes(meta, region, &blas.nodes);
+                meta.triangles
+                    .write(region.first_primitive, &blas.triangles);
+                meta.vertices
+                    .write(region.first_primitive * 3, &blas.vertices);
+            },
+        );
+
+        let index = self.list.len();
+        let mesh = RayTraceMesh {
+            first_node: region.first_node,
+            first_triangle: region.first_primitive,
+            triangle_count: region.primitive_count,
+        };
+        self.list.push(mesh);
+        self.bounds.push(blas.bounds());
+        self.shape_indices.push(shapes.push(&mesh));
+        self.map.insert(key, index);
+        index
+    }
+}
+
+fn upload_nodes(meta: &mut GlobalRayTraceMeta, region: BlasRegion, nodes: &[BvhNode]) {
+    let nodes: Vec<RayTraceBvhNode> = nodes.iter().map(RayTraceBvhNode::from).collect();
+    meta.blas_nodes.write(region.first_node, &nodes);
+}
+
+/// Skinning sources uploaded once per mesh, however many entities use them
+#[derive(Default)]
+struct SkinSourceList {
+    map: HashMap<UntypedAssetId, RayTraceSkin>,
+    vertices: Vec<RayTraceSkinVertex>,
+    corners: Vec<u32>,
+    morphs: Vec<RayTraceMorph>,
+    refit: Vec<u32>,
+    levels: Vec<u32>,
+}
+
+impl SkinSourceList {
+    /// Returns a skin with the source's offsets filled in
+    pub fn add(&mut self, id: UntypedAssetId, source: &SkinSource) -> RayTraceSkin {
+        *self.map.entry(id).or_insert_with(|| {
+            let skin = RayTraceSkin {
+                first_vertex: self.vertices.len() as u32,
+                vertex_count: source.vertices.len() as u32,
+                first_corner: self.corners.len() as u32,
+                first_morph: self.morphs.len() as u32,
+                first_refit: self.refit.len() as u32,
+                first_level: self.levels.len() as u32,
+                level_count: source.levels.len() as u32,
+                ..Default::default()
+            };
+
+            self.vertices.extend_from_slice(&source.vertices);
+            self.corners.extend_from_slice(&source.corners);
+            self.morphs.extend_from_slice(&source.morphs);
+            self.refit.extend_from_slice(&source.refit);
+            self.levels.extend_from_slice(&source.levels);
+            skin
+        })
+    }
+
+    pub fn set(self, meta: &mut GlobalRayTraceMeta) {
+        meta.skin_vertices.set(RayTraceSkinVertices {
+            data: self.vertices,
+        });
+        meta.skin_corners
+            .set(RayTraceSkinIndices { data: self.corners });
+        meta.morphs.set(RayTraceMorphs { data: self.morphs });
+        meta.skin_refit
+            .set(RayTraceSkinIndices { data: self.refit });
+        meta.skin_levels
+            .set(RayTraceSkinIndices { data: self.levels });
+    }
+}
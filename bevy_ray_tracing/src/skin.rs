@@ -0,0 +1,248 @@
+use crate::{
+    bvh::{Aabb, BvhNode},
+    mesh::MeshBlas,
+    types::{RayTraceMorph, RayTraceSkinVertex},
+};
+
+use bevy::{
+    asset::{Assets, Handle, UntypedAssetId},
+    ecs::system::Resource,
+    math::{Affine3A, UVec4, Vec3, Vec4},
+    reflect::Struct,
+    render::{
+        mesh::{morph::MorphAttributes, Mesh, VertexAttributeValues},
+        render_resource::TextureFormat,
+        texture::Image,
+    },
+    utils::HashMap,
+};
+
+/// What the skinning pass deforms a mesh from, shared by every entity using it
+pub struct SkinSource {
+    pub vertices: Vec<RayTraceSkinVertex>,
+    /// The source vertex of each of the BLAS's triangle corners
+    pub corners: Vec<u32>,
+    /// Target major, `vertices.len()` per target
+    pub morphs: Vec<RayTraceMorph>,
+    pub morph_count: u32,
+    /// BLAS nodes by depth, deepest first, so each level only reads refitted children
+    pub refit: Vec<u32>,
+    /// Where each level of `refit` ends
+    pub levels: Vec<u32>,
+    /// Bind pose bounds of the vertices each joint moves
+    joint_bounds: Vec<Aabb>,
+    /// Largest offset each morph target gives any vertex
+    morph_extents: Vec<Vec3>,
+    bounds: Aabb,
+}
+
+impl SkinSource {
+    /// Returns `None` while the mesh's morph target image isn't loaded
+    pub fn new(mesh: &Mesh, blas: &MeshBlas, images: &Assets<Image>) -> Option<Self> {
+        let count = mesh.count_vertices();
+        let positions = mesh
+            .attribute(Mesh::ATTRIBUTE_POSITION)
+            .and_then(VertexAttributeValues::as_float3)?;
+        let normals = mesh
+            .attribute(Mesh::ATTRIBUTE_NORMAL)
+            .and_then(VertexAttributeValues::as_float3);
+        let tangents = match mesh.attribute(Mesh::ATTRIBUTE_TANGENT) {
+            Some(VertexAttributeValues::Float32x4(tangents)) => Some(tangents),
+            _ => None,
+        };
+        let joints = match mesh.attribute(Mesh::ATTRIBUTE_JOINT_INDEX) {
+            Some(VertexAttributeValues::Uint16x4(joints)) => Some(joints),
+            _ => None,
+        };
+        let weights = match mesh.attribute(Mesh::ATTRIBUTE_JOINT_WEIGHT) {
+            Some(VertexAttributeValues::Float32x4(weights)) => Some(weights),
+            _ => None,
+        };
+
+        let vertices: Vec<RayTraceSkinVertex> = (0..count)
+            .map(|i| RayTraceSkinVertex {
+                position: positions[i].into(),
+                normal: normals.map_or(Vec3::ZERO, |n| n[i].into()),
+                tangent: tangents.map_or(Vec4::ZERO, |t| t[i].into()),
+                joints: joints.map_or(UVec4::ZERO, |j| UVec4::from(j[i].map(u32::from))),
+                weights: weights.map_or(Vec4::X, |w| w[i].into()),
+            })
+            .collect();
+
+        let morphs = match morph_target_image(mesh) {
+            Some(handle) => morph_targets(images.get(handle)?, count)?,
+            None => Vec::new(),
+        };
+        let morph_count = morphs.len().checked_div(count).unwrap_or(0);
+        let morph_extents = morphs
+            .chunks(count.max(1))
+            .map(|target| {
+                target
+                    .iter()
+                    .fold(Vec3::ZERO, |extent, morph| extent.max(morph.position.abs()))
+            })
+            .collect();
+
+        let mut joint_bounds = Vec::new();
+        for vertex in &vertices {
+            for (joint, weight) in vertex
+                .joints
+                .to_array()
+                .into_iter()
+                .zip(vertex.weights.to_array())
+            {
+                if weight == 0.0 {
+                    continue;
+                }
+
+                let joint = joint as usize;
+                if joint_bounds.len() <= joint {
+                    joint_bounds.resize(joint + 1, Aabb::EMPTY);
+                }
+                joint_bounds[joint] = joint_bounds[joint].grow(vertex.position);
+            }
+        }
+
+        let (refit, levels) = refit_order(&blas.nodes);
+        Some(Self {
+            vertices,
+            corners: blas.corners.clone(),
+            morphs,
+            morph_count: morph_count as u32,
+            refit,
+            levels,
+            joint_bounds,
+            morph_extents,
+            bounds: blas.bounds(),
+        })
+    }
+
+    /// World bounds of the deformed mesh, `joints` is `None` for a mesh that's
+    /// only morphed and placed by `transform`
+    ///
+    /// Morphing moves a vertex at most the weighted sum of each target's
+    /// largest offset, and skinning blends positions moved by the joints that
+    /// influence it, so the result stays inside the union of each joint's
+    /// transformed bounds.
+    pub fn deformed_bounds(
+        &self,
+        joints: Option<&[Affine3A]>,
+        transform: &Affine3A,
+        weights: &[f32],
+    ) -> Aabb {
+        let extent = self
+            .morph_extents
+            .iter()
+            .zip(weights)
+            .fold(Vec3::ZERO, |extent, (target, weight)| {
+                extent + *target * weight.abs()
+            });
+        let morphed = |bounds: Aabb| Aabb::new(bounds.min - extent, bounds.max + extent);
+
+        let Some(joints) = joints else {
+            return morphed(self.bounds).transformed(transform);
+        };
+
+        self.joint_bounds
+            .iter()
+            .zip(joints)
+            .filter(|(bounds, _)| !bounds.is_empty())
+            .fold(Aabb::EMPTY, |union, (bounds, joint)| {
+                union.union(morphed(*bounds).transformed(joint))
+            })
+    }
+}
+
+/// Bevy keeps a mesh's morph targets private, but they're reflected
+fn morph_target_image(mesh: &Mesh) -> Option<&Handle<Image>> {
+    mesh.field("morph_targets")?
+        .downcast_ref::<Option<Handle<Image>>>()?
+        .as_ref()
+}
+
+/// Reads a [`MorphTargetImage`](bevy::render::mesh::morph::MorphTargetImage),
+/// one padded layer of [`MorphAttributes`] per target
+fn morph_targets(image: &Image, vertex_count: usize) -> Option<Vec<RayTraceMorph>> {
+    if image.texture_descriptor.format != TextureFormat::R32Float {
+        return None;
+    }
+
+    let size = image.texture_descriptor.size;
+    let layer = (size.width * size.height) as usize;
+    let components = vertex_count * MorphAttributes::COMPONENT_COUNT;
+    if layer < components {
+        return None;
+    }
+
+    let floats: Vec<f32> = image
+        .data
+        .chunks_exact(4)
+        .map(|f| f32::from_le_bytes([f[0], f[1], f[2], f[3]]))
+        .collect();
+    Some(
+        floats
+            .chunks_exact(layer)
+            .flat_map(|target| {
+                target[..components]
+                    .chunks_exact(MorphAttributes::COMPONENT_COUNT)
+                    .map(|m| RayTraceMorph {
+                        position: Vec3::new(m[0], m[1], m[2]),
+                        normal: Vec3::new(m[3], m[4], m[5]),
+                        tangent: Vec3::new(m[6], m[7], m[8]),
+                    })
+            })
+            .collect(),
+    )
+}
+
+fn refit_order(nodes: &[BvhNode]) -> (Vec<u32>, Vec<u32>) {
+    let mut by_depth = vec![vec![0u32]];
+    loop {
+        let children: Vec<u32> = by_depth
+            .last()
+            .unwrap()
+            .iter()
+            .map(|&i| &nodes[i as usize])
+            .filter(|node| node.count == 0)
+            .flat_map(|node| [node.first, node.first + 1])
+            .collect();
+        if children.is_empty() {
+            break;
+        }
+        by_depth.push(children);
+    }
+
+    let mut refit = Vec::with_capacity(nodes.len());
+    let mut levels = Vec::with_capacity(by_depth.len());
+    for level in by_depth.iter().rev() {
+        refit.extend_from_slice(level);
+        levels.push(refit.len() as u32);
+    }
+    (refit, levels)
+}
+
+/// Skinning sources kept across frames, keyed by mesh like [`MeshBlasCache`](crate::mesh::MeshBlasCache)
+#[derive(Resource, Default)]
+pub struct SkinSourceCache {
+    map: HashMap<UntypedAssetId, SkinSource>,
+}
+
+impl SkinSourceCache {
+    pub fn get_or_build(
+        &mut self,
+        id: UntypedAssetId,
+        mesh: &Mesh,
+        blas: &MeshBlas,
+        images: &Assets<Image>,
+    ) -> Option<&SkinSource> {
+        if !self.map.contains_key(&id) {
+            self.map.insert(id, SkinSource::new(mesh, blas, images)?);
+        }
+
+        self.map.get(&id)
+    }
+
+    pub fn remove(&mut self, id: UntypedAssetId) {
+        self.map.remove(&id);
+    }
+}
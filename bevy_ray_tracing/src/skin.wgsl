@@ -0,0 +1,103 @@
+#import bevy_ray_tracing::types::{BvhNode, Triangle, Vertex, SkinVertex, Morph, Skin, T_MAX, SKIN_WORKGROUP_SIZE}
+
+@group(0) @binding(0) var<storage, read> skins: array<Skin>;
+@group(0) @binding(1) var<storage, read> skin_vertices: array<SkinVertex>;
+@group(0) @binding(2) var<storage, read> skin_corners: array<u32>;
+@group(0) @binding(3) var<storage, read> morphs: array<Morph>;
+@group(0) @binding(4) var<storage, read> joints: array<mat4x4<f32>>;
+@group(0) @binding(5) var<storage, read> morph_weights: array<f32>;
+@group(0) @binding(6) var<storage, read> skin_refit: array<u32>;
+@group(0) @binding(7) var<storage, read> skin_levels: array<u32>;
+@group(0) @binding(8) var<storage, read_write> blas_nodes: array<BvhNode>;
+@group(0) @binding(9) var<storage, read_write> triangles: array<Triangle>;
+@group(0) @binding(10) var<storage, read_write> vertices: array<Vertex>;
+
+// One invocation per triangle, the `y` workgroup picks the skin
+@compute @workgroup_size(SKIN_WORKGROUP_SIZE)
+fn skin(@builtin(global_invocation_id) id: vec3<u32>) {
+    let skin = skins[id.y];
+    if skin.triangle_count <= id.x {
+        return;
+    }
+
+    let triangle = skin.first_triangle + id.x;
+    var corners: array<vec3<f32>, 3>;
+    var has_normals = true;
+    for (var i = 0u; i < 3u; i++) {
+        let index = skin_corners[skin.first_corner + id.x * 3u + i];
+        var vertex = skin_vertices[skin.first_vertex + index];
+
+        for (var t = 0u; t < skin.morph_count; t++) {
+            let weight = morph_weights[skin.first_weight + t];
+            let morph = morphs[skin.first_morph + t * skin.vertex_count + index];
+            vertex.position += weight * morph.position;
+            vertex.normal += weight * morph.normal;
+            vertex.tangent += vec4<f32>(weight * morph.tangent, 0.0);
+        }
+
+        var model = joints[skin.first_joint];
+        if skin.skinned == 1u {
+            model = vertex.weights.x * joints[skin.first_joint + vertex.joints.x]
+                + vertex.weights.y * joints[skin.first_joint + vertex.joints.y]
+                + vertex.weights.z * joints[skin.first_joint + vertex.joints.z]
+                + vertex.weights.w * joints[skin.first_joint + vertex.joints.w];
+        }
+
+        let m = mat3x3<f32>(model[0].xyz, model[1].xyz, model[2].xyz);
+        corners[i] = (model * vec4<f32>(vertex.position, 1.0)).xyz;
+
+        // Normals transform with the cofactor matrix, the inverse transpose up to scale
+        let cofactor = mat3x3<f32>(cross(m[1], m[2]), cross(m[2], m[0]), cross(m[0], m[1]));
+        has_normals = has_normals && dot(vertex.normal, vertex.normal) > 0.0;
+        vertices[triangle * 3u + i].normal = normalize(cofactor * vertex.normal) * sign(dot(m[0], cofactor[0]));
+        if dot(vertex.tangent.xyz, vertex.tangent.xyz) > 0.0 {
+            vertices[triangle * 3u + i].tangent = vec4<f32>(normalize(m * vertex.tangent.xyz), vertex.tangent.w);
+        }
+    }
+
+    triangles[triangle] = Triangle(corners[0], corners[1], corners[2]);
+
+    // Meshes without normals are flat shaded, like unskinned ones
+    if !has_normals {
+        let n = normalize(cross(corners[1] - corners[0], corners[2] - corners[0]));
+        for (var i = 0u; i < 3u; i++) {
+            vertices[triangle * 3u + i].normal = n;
+        }
+    }
+}
+
+// One workgroup per skin, refitting the BLAS a level at a time from the leaves up
+@compute @workgroup_size(SKIN_WORKGROUP_SIZE)
+fn refit(@builtin(workgroup_id) group: vec3<u32>, @builtin(local_invocation_index) thread: u32) {
+    let skin = skins[group.x];
+
+    var start = 0u;
+    for (var level = 0u; level < skin.level_count; level++) {
+        let end = skin_levels[skin.first_level + level];
+        for (var i = start + thread; i < end; i += SKIN_WORKGROUP_SIZE) {
+            let index = skin.first_node + skin_refit[skin.first_refit + i];
+            let node = blas_nodes[index];
+
+            var node_min = vec3<f32>(T_MAX);
+            var node_max = vec3<f32>(-T_MAX);
+            if node.count > 0u {
+                for (var j = node.first; j < node.first + node.count; j++) {
+                    let triangle = triangles[skin.first_triangle + j];
+                    node_min = min(node_min, min(triangle.a, min(triangle.b, triangle.c)));
+                    node_max = max(node_max, max(triangle.a, max(triangle.b, triangle.c)));
+                }
+            } else {
+                let left = blas_nodes[skin.first_node + node.first];
+                let right = blas_nodes[skin.first_node + node.first + 1u];
+                node_min = min(left.min, right.min);
+                node_max = max(left.max, right.max);
+            }
+
+            blas_nodes[index].min = node_min;
+            blas_nodes[index].max = node_max;
+        }
+
+        start = end;
+        storageBarrier();
+    }
+}
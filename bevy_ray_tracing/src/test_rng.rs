@@ -0,0 +1,24 @@
+use bevy::math::Vec3;
+
+/// Deterministic xorshift so tests don't need an rng dependency
+pub struct Rng(pub u32);
+
+impl Rng {
+    /// In `[0, 1]`
+    pub fn f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32
+    }
+
+    /// Each component in `[0, 1]`
+    pub fn vec3(&mut self) -> Vec3 {
+        Vec3::new(self.f32(), self.f32(), self.f32())
+    }
+
+    /// Each component in `[-extent, extent]`
+    pub fn centered_vec3(&mut self, extent: f32) -> Vec3 {
+        self.vec3() * 2.0 * extent - extent
+    }
+}
@@ -0,0 +1,469 @@
+use bevy::{
+    asset::{Assets, Handle, UntypedAssetId},
+    ecs::system::Resource,
+    log::warn,
+    math::{Vec2, Vec4},
+    render::{color::SrgbColorSpace, render_resource::TextureFormat, texture::Image},
+    tasks::{block_on, poll_once, AsyncComputeTaskPool, Task},
+    utils::{warn_once, HashMap, HashSet},
+};
+
+/// An image resampled to one layer of the texture array, as RGBA8
+pub struct TextureLayer {
+    pub data: Vec<u8>,
+    /// Sampled colors are decoded from sRGB, like the image's own format would be
+    pub srgb: bool,
+    /// Every texel's color is black
+    pub black: bool,
+}
+
+impl TextureLayer {
+    /// Returns `None` for formats it can't read
+    pub fn from_image(image: &Image, size: u32) -> Option<Self> {
+        let extent = image.texture_descriptor.size;
+        let (width, height) = (extent.width, extent.height);
+        let format = image.texture_descriptor.format;
+        let mut texels = texels(image)?;
+        if width == 0 || height == 0 || texels.len() < (width * height) as usize {
+            return None;
+        }
+
+        // Averaged in linear light, so shrinking doesn't darken sRGB images
+        let srgb = format.is_srgb();
+        if srgb {
+            for texel in &mut texels {
+                *texel = map_rgb(*texel, f32::nonlinear_to_linear_srgb);
+            }
+        }
+
+        // Bilinear taps spread over each texel's footprint, so shrinking averages
+        let scale = Vec2::new(width as f32, height as f32) / size as f32;
+        let taps_x = scale.x.ceil().max(1.0) as u32;
+        let taps_y = scale.y.ceil().max(1.0) as u32;
+        let bilinear = |p: Vec2| {
+            let base = (p - 0.5).floor();
+            let f = p - 0.5 - base;
+            let texel = |dx: f32, dy: f32| {
+                let x = (base.x + dx).rem_euclid(width as f32) as usize;
+                let y = (base.y + dy).rem_euclid(height as f32) as usize;
+                texels[y * width as usize + x]
+            };
+            let top = texel(0.0, 0.0).lerp(texel(1.0, 0.0), f.x);
+            let bottom = texel(0.0, 1.0).lerp(texel(1.0, 1.0), f.x);
+            top.lerp(bottom, f.y)
+        };
+
+        let mut data = Vec::with_capacity((size * size * 4) as usize);
+        for y in 0..size {
+            for x in 0..size {
+                let corner = Vec2::new(x as f32, y as f32) * scale;
+                let mut sum = Vec4::ZERO;
+                for ty in 0..taps_y {
+                    for tx in 0..taps_x {
+                        let offset = Vec2::new(
+                            (tx as f32 + 0.5) / taps_x as f32,
+                            (ty as f32 + 0.5) / taps_y as f32,
+                        );
+                        sum += bilinear(corner + offset * scale);
+                    }
+                }
+
+                let mut texel = sum / (taps_x * taps_y) as f32;
+                if srgb {
+                    texel = map_rgb(texel, f32::linear_to_nonlinear_srgb);
+                }
+                data.extend(texel.to_array().map(|c| (c * 255.0).round() as u8));
+            }
+        }
+
+        let black = data.chunks_exact(4).all(|texel| texel[..3] == [0; 3]);
+        Some(Self { data, srgb, black })
+    }
+}
+
+/// `f` applied to the color channels but not alpha
+fn map_rgb(texel: Vec4, f: impl Fn(f32) -> f32) -> Vec4 {
+    Vec4::new(f(texel.x), f(texel.y), f(texel.z), texel.w)
+}
+
+/// Texels in `[0, 1]`, missing channels filled in as a shader would sample them
+fn texels(image: &Image) -> Option<Vec<Vec4>> {
+    let data = &image.data;
+    let unorm8 = |c: u8| c as f32 / 255.0;
+    let unorm16 = |c: &[u8]| u16::from_le_bytes([c[0], c[1]]) as f32 / 65535.0;
+    let float32 = |c: &[u8]| f32::from_le_bytes([c[0], c[1], c[2], c[3]]).clamp(0.0, 1.0);
+
+    let texels = match image.texture_descriptor.format {
+        TextureFormat::R8Unorm => data
+            .iter()
+            .map(|&r| Vec4::new(unorm8(r), 0.0, 0.0, 1.0))
+            .collect(),
+        TextureFormat::Rg8Unorm => data
+            .chunks_exact(2)
+            .map(|t| Vec4::new(unorm8(t[0]), unorm8(t[1]), 0.0, 1.0))
+            .collect(),
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => data
+            .chunks_exact(4)
+            .map(|t| Vec4::new(unorm8(t[0]), unorm8(t[1]), unorm8(t[2]), unorm8(t[3])))
+            .collect(),
+        TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => data
+            .chunks_exact(4)
+            .map(|t| Vec4::new(unorm8(t[2]), unorm8(t[1]), unorm8(t[0]), unorm8(t[3])))
+            .collect(),
+        TextureFormat::R16Unorm => data
+            .chunks_exact(2)
+            .map(|t| Vec4::new(unorm16(t), 0.0, 0.0, 1.0))
+            .collect(),
+        TextureFormat::Rg16Unorm => data
+            .chunks_exact(4)
+            .map(|t| Vec4::new(unorm16(t), unorm16(&t[2..]), 0.0, 1.0))
+            .collect(),
+        TextureFormat::Rgba16Unorm => data
+            .chunks_exact(8)
+            .map(|t| {
+                Vec4::new(
+                    unorm16(t),
+                    unorm16(&t[2..]),
+                    unorm16(&t[4..]),
+                    unorm16(&t[6..]),
+                )
+            })
+            .collect(),
+        TextureFormat::R32Float => data
+            .chunks_exact(4)
+            .map(|t| Vec4::new(float32(t), 0.0, 0.0, 1.0))
+            .collect(),
+        TextureFormat::Rgba32Float => data
+            .chunks_exact(16)
+            .map(|t| {
+                Vec4::new(
+                    float32(t),
+                    float32(&t[4..]),
+                    float32(&t[8..]),
+                    float32(&t[12..]),
+                )
+            })
+            .collect(),
+        _ => return None,
+    };
+    Some(texels)
+}
+
+/// Material textures resampled into layers of one texture array
+///
+/// Images are resampled on the async compute pool and only given a layer once
+/// they're done. An image keeps its layer for as long as materials use it, so
+/// only layers given to new images are written to the array.
+#[derive(Resource)]
+pub struct TextureCache {
+    size: u32,
+    /// How many layers the device allows in the array, images past it are left out
+    max_layers: u32,
+    /// `None` for images that can't be read
+    map: HashMap<UntypedAssetId, Option<TextureLayer>>,
+    /// Images still being resampled
+    building: HashMap<UntypedAssetId, Task<Option<TextureLayer>>>,
+    /// The layer each image in the array is in
+    layers: HashMap<UntypedAssetId, u32>,
+    /// The image in each layer, `None` once it's freed
+    slots: Vec<Option<UntypedAssetId>>,
+    /// Layers handed out since the last write to the array
+    writes: Vec<u32>,
+    used: HashSet<UntypedAssetId>,
+}
+
+impl TextureCache {
+    pub fn new(size: u32, max_layers: u32) -> Self {
+        Self {
+            size,
+            max_layers,
+            map: HashMap::default(),
+            building: HashMap::default(),
+            layers: HashMap::default(),
+            slots: Vec::new(),
+            writes: Vec::new(),
+            used: HashSet::default(),
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// How many layers the array needs, including freed ones
+    pub fn layer_count(&self) -> u32 {
+        self.slots.len() as u32
+    }
+
+    /// Takes in the images that finished resampling since the last frame
+    pub fn poll_builds(&mut self) {
+        let map = &mut self.map;
+        self.building
+            .retain(|id, task| match block_on(poll_once(task)) {
+                Some(layer) => {
+                    map.insert(*id, layer);
+                    false
+                }
+                None => true,
+            });
+    }
+
+    /// Starts resampling the image if it isn't already, `None` until it's done
+    fn get_or_build(
+        &mut self,
+        handle: &Handle<Image>,
+        images: &Assets<Image>,
+    ) -> Option<&TextureLayer> {
+        let id = handle.id().untyped();
+        if !self.map.contains_key(&id) && !self.building.contains_key(&id) {
+            let image = images.get(handle)?.clone();
+            let size = self.size;
+            let task = AsyncComputeTaskPool::get().spawn(async move {
+                let layer = TextureLayer::from_image(&image, size);
+                if layer.is_none() {
+                    warn!(
+                        "Can't read a {:?} material texture, it's left out",
+                        image.texture_descriptor.format
+                    );
+                }
+                layer
+            });
+            self.building.insert(id, task);
+        }
+
+        self.map.get(&id)?.as_ref()
+    }
+
+    /// The image's layer, or `-1` while it isn't resampled, can't be read or
+    /// doesn't fit in the array
+    pub fn layer(&mut self, handle: &Handle<Image>, images: &Assets<Image>) -> i32 {
+        let id = handle.id().untyped();
+        if let Some(&layer) = self.layers.get(&id) {
+            self.used.insert(id);
+            return layer as i32;
+        }
+
+        if self.get_or_build(handle, images).is_none() {
+            return -1;
+        }
+
+        // The first freed layer, or a new one while the array has room
+        let layer = match self.slots.iter().position(Option::is_none) {
+            Some(layer) => layer,
+            None if (self.slots.len() as u32) < self.max_layers => {
+                self.slots.push(None);
+                self.slots.len() - 1
+            }
+            None => {
+                warn_once!(
+                    "More material textures than the {} layers the texture array can hold, the rest are left out",
+                    self.max_layers
+                );
+                return -1;
+            }
+        };
+
+        self.slots[layer] = Some(id);
+        self.layers.insert(id, layer as u32);
+        self.writes.push(layer as u32);
+        self.used.insert(id);
+        layer as i32
+    }
+
+    /// Whether the image is all black, `None` while it isn't resampled or can't be read
+    pub fn is_black(&mut self, handle: &Handle<Image>, images: &Assets<Image>) -> Option<bool> {
+        self.get_or_build(handle, images).map(|layer| layer.black)
+    }
+
+    /// Whether each layer is sRGB, freed ones aren't
+    pub fn srgb_layers(&self) -> Vec<u32> {
+        self.slots
+            .iter()
+            .map(|id| id.map_or(0, |id| self.layer_data(&id).srgb as u32))
+            .collect()
+    }
+
+    /// An image with a layer, which is always resampled
+    fn layer_data(&self, id: &UntypedAssetId) -> &TextureLayer {
+        self.map[id].as_ref().unwrap()
+    }
+
+    pub fn remove(&mut self, id: UntypedAssetId) {
+        self.building.remove(&id);
+        self.map.remove(&id);
+        if let Some(layer) = self.layers.remove(&id) {
+            self.slots[layer as usize] = None;
+        }
+    }
+
+    /// Frees the layers of images no material used this frame
+    pub fn end_frame(&mut self) {
+        let unused: Vec<UntypedAssetId> = self
+            .layers
+            .keys()
+            .filter(|id| !self.used.contains(*id))
+            .copied()
+            .collect();
+        for id in unused {
+            if let Some(layer) = self.layers.remove(&id) {
+                self.slots[layer as usize] = None;
+            }
+        }
+        self.used.clear();
+    }
+
+    /// The texels of each layer handed out since the last call, to write to the array
+    pub fn take_writes(&mut self) -> Vec<(u32, &[u8])> {
+        let mut writes = std::mem::take(&mut self.writes);
+        writes.sort_unstable();
+        writes.dedup();
+        writes
+            .into_iter()
+            .filter_map(|layer| {
+                let id = self.slots[layer as usize]?;
+                Some((layer, self.layer_data(&id).data.as_slice()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bevy::{
+        render::{
+            render_asset::RenderAssetUsages,
+            render_resource::{Extent3d, TextureDimension},
+        },
+        tasks::TaskPool,
+    };
+
+    fn image(width: u32, height: u32, data: Vec<u8>, format: TextureFormat) -> Image {
+        Image::new(
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            format,
+            RenderAssetUsages::default(),
+        )
+    }
+
+    fn checkerboard(format: TextureFormat) -> Image {
+        let data = (0..8 * 8)
+            .flat_map(|i| {
+                let c = if (i % 8 + i / 8) % 2 == 0 { 255 } else { 0 };
+                [c, c, c, 255]
+            })
+            .collect();
+        image(8, 8, data, format)
+    }
+
+    /// The image's layer once it's resampled, as a later frame would see it
+    fn resampled_layer(
+        cache: &mut TextureCache,
+        handle: &Handle<Image>,
+        images: &Assets<Image>,
+    ) -> i32 {
+        AsyncComputeTaskPool::get_or_init(TaskPool::default);
+        cache.layer(handle, images);
+        while !cache.building.is_empty() {
+            cache.poll_builds();
+            std::thread::yield_now();
+        }
+        cache.layer(handle, images)
+    }
+
+    #[test]
+    fn shrinking_averages_texels() {
+        // A checkerboard averages to grey, keeping its format's encoding
+        let layer = TextureLayer::from_image(&checkerboard(TextureFormat::Rgba8Unorm), 2).unwrap();
+        assert!(!layer.srgb && !layer.black);
+        assert_eq!(layer.data.len(), 2 * 2 * 4);
+        for texel in layer.data.chunks_exact(4) {
+            assert_eq!(texel, [128, 128, 128, 255]);
+        }
+
+        // Half the light of white, which sRGB encodes brighter than half way
+        let layer =
+            TextureLayer::from_image(&checkerboard(TextureFormat::Rgba8UnormSrgb), 2).unwrap();
+        assert!(layer.srgb);
+        for texel in layer.data.chunks_exact(4) {
+            assert_eq!(texel, [188, 188, 188, 255]);
+        }
+    }
+
+    #[test]
+    fn two_channel_formats_leave_blue_empty() {
+        let data = [0xff, 0xff, 0, 0].repeat(4);
+        let layer =
+            TextureLayer::from_image(&image(2, 2, data, TextureFormat::Rg16Unorm), 2).unwrap();
+        for texel in layer.data.chunks_exact(4) {
+            assert_eq!(texel, [255, 0, 0, 255]);
+        }
+    }
+
+    /// Each written layer and its first texel
+    fn writes(cache: &mut TextureCache) -> Vec<(u32, [u8; 4])> {
+        cache
+            .take_writes()
+            .into_iter()
+            .map(|(layer, data)| (layer, data[..4].try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn writes_only_new_layers() {
+        let mut images = Assets::<Image>::default();
+        let format = TextureFormat::Rgba8UnormSrgb;
+        let red = images.add(image(1, 1, vec![255, 0, 0, 255], format));
+        let blue = images.add(image(1, 1, vec![0, 0, 255, 255], format));
+        let green = images.add(image(1, 1, vec![0, 255, 0, 255], format));
+        let mut cache = TextureCache::new(4, 256);
+
+        // Nothing has a layer until it's resampled
+        assert_eq!(cache.layer(&red, &images), -1);
+        assert_eq!(resampled_layer(&mut cache, &red, &images), 0);
+        assert_eq!(writes(&mut cache), [(0, [255, 0, 0, 255])]);
+        cache.end_frame();
+
+        // Layers already in the array keep their place and aren't written again
+        assert_eq!(cache.layer(&red, &images), 0);
+        assert_eq!(resampled_layer(&mut cache, &blue, &images), 1);
+        assert_eq!(writes(&mut cache), [(1, [0, 0, 255, 255])]);
+        assert_eq!(cache.layer_count(), 2);
+        cache.end_frame();
+
+        // A layer no material used last frame goes to the next new image
+        assert_eq!(cache.layer(&blue, &images), 1);
+        cache.end_frame();
+        assert_eq!(resampled_layer(&mut cache, &green, &images), 0);
+        assert_eq!(cache.layer(&blue, &images), 1);
+        assert_eq!(writes(&mut cache), [(0, [0, 255, 0, 255])]);
+        assert_eq!(cache.layer_count(), 2);
+        cache.end_frame();
+
+        // Editing an image in the array writes it again
+        cache.remove(blue.id().untyped());
+        assert_eq!(cache.srgb_layers(), [1, 0]);
+        assert_eq!(resampled_layer(&mut cache, &blue, &images), 1);
+        assert_eq!(writes(&mut cache), [(1, [0, 0, 255, 255])]);
+    }
+
+    #[test]
+    fn layers_past_the_limit_are_left_out() {
+        let mut images = Assets::<Image>::default();
+        let format = TextureFormat::Rgba8Unorm;
+        let red = images.add(image(1, 1, vec![255, 0, 0, 255], format));
+        let blue = images.add(image(1, 1, vec![0, 0, 255, 255], format));
+        let mut cache = TextureCache::new(4, 1);
+
+        assert_eq!(resampled_layer(&mut cache, &red, &images), 0);
+        assert_eq!(resampled_layer(&mut cache, &blue, &images), -1);
+        assert_eq!(cache.layer_count(), 1);
+    }
+}
@@ -0,0 +1,284 @@
+//! A CPU port of the shader's torus intersection and polynomial solver, kept
+//! in step with `raytrace.wgsl` so the solver can be checked in tests
+
+use bevy::math::{Vec2, Vec3};
+
+const POLY_MAX_ITERATIONS: i32 = 32;
+const POLY_TOLERANCE: f32 = 1e-6;
+
+/// Distance along `dir` to the first hit in `(t_min, t_max)`, as `hit_torus`
+pub fn intersect_torus(
+    pos: Vec3,
+    dir: Vec3,
+    major: f32,
+    minor: f32,
+    t_min: f32,
+    t_max: f32,
+) -> Option<f32> {
+    let outer = major + minor;
+
+    let len = dir.length();
+    let unit = dir / len;
+    let b = pos.dot(unit);
+    let discriminant = b * b - (pos.length_squared() - outer * outer);
+    if discriminant < 0.0 || -b + discriminant.sqrt() <= 0.0 {
+        return None;
+    }
+
+    let t_start = (-b - discriminant.sqrt()).max(0.0);
+    let lo = (t_min * len - t_start).max(0.0) / outer;
+    let hi = ((-b + discriminant.sqrt()).min(t_max * len) - t_start) / outer;
+    if hi <= lo {
+        return None;
+    }
+
+    let o = (pos + t_start * unit) / outer;
+    let r2 = (major / outer) * (major / outer);
+    let m = o.dot(unit);
+    let k = o.length_squared() + r2 - (minor / outer) * (minor / outer);
+    let oxz = Vec2::new(o.x, o.z);
+    let dxz = Vec2::new(unit.x, unit.z);
+    let c = [
+        k * k - 4.0 * r2 * oxz.length_squared(),
+        4.0 * m * k - 8.0 * r2 * oxz.dot(dxz),
+        4.0 * m * m + 2.0 * k - 4.0 * r2 * dxz.length_squared(),
+        4.0 * m,
+        1.0,
+    ];
+
+    let s = poly_roots(c, 4, lo, hi)
+        .into_iter()
+        .find(|&s| lo < s && s < hi)?;
+    Some((t_start + s * outer) / len)
+}
+
+fn poly_eval(c: [f32; 5], degree: usize, t: f32) -> f32 {
+    c[..=degree]
+        .iter()
+        .rev()
+        .fold(0.0, |result, &c| result * t + c)
+}
+
+fn poly_derivative(c: [f32; 5], degree: usize) -> [f32; 5] {
+    let mut derivative = [0.0; 5];
+    for i in 1..=degree {
+        derivative[i - 1] = i as f32 * c[i];
+    }
+    derivative
+}
+
+fn poly_roots(c: [f32; 5], degree: usize, lo: f32, hi: f32) -> Vec<f32> {
+    let mut derivatives = vec![c];
+    for k in 1..=degree {
+        derivatives.push(poly_derivative(derivatives[k - 1], degree - k + 1));
+    }
+
+    let mut roots = Vec::new();
+    for k in (0..degree).rev() {
+        roots = poly_roots_between(
+            derivatives[k],
+            derivatives[k + 1],
+            degree - k,
+            lo,
+            hi,
+            &roots,
+        );
+    }
+    roots
+}
+
+fn poly_roots_between(
+    c: [f32; 5],
+    derivative: [f32; 5],
+    degree: usize,
+    lo: f32,
+    hi: f32,
+    critical: &[f32],
+) -> Vec<f32> {
+    let mut roots = Vec::new();
+
+    let mut a = lo;
+    let mut fa = poly_eval(c, degree, a);
+    for &b in critical.iter().chain([hi].iter()) {
+        if b <= a {
+            continue;
+        }
+
+        let fb = poly_eval(c, degree, b);
+        if fa == 0.0 {
+            roots.push(a);
+        } else if (fa < 0.0) != (fb < 0.0) && fb != 0.0 {
+            roots.push(poly_refine(c, derivative, degree, a, b, fa));
+        }
+
+        a = b;
+        fa = fb;
+    }
+
+    if fa == 0.0 {
+        roots.push(a);
+    }
+    roots
+}
+
+fn poly_refine(c: [f32; 5], derivative: [f32; 5], degree: usize, a: f32, b: f32, fa: f32) -> f32 {
+    let (mut lo, mut hi) = (a, b);
+    let mut t = 0.5 * (a + b);
+    for _ in 0..POLY_MAX_ITERATIONS {
+        let ft = poly_eval(c, degree, t);
+        if ft == 0.0 {
+            return t;
+        }
+
+        if (ft < 0.0) == (fa < 0.0) {
+            lo = t;
+        } else {
+            hi = t;
+        }
+
+        let mut next = t - ft / poly_eval(derivative, degree - 1, t);
+        if !(lo < next && next < hi) {
+            next = 0.5 * (lo + hi);
+        }
+
+        if (next - t).abs() <= POLY_TOLERANCE * t.abs().max(1.0) {
+            return next;
+        }
+        t = next;
+    }
+    t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_rng::Rng;
+
+    /// Signed distance to the torus, negative inside the tube
+    fn torus_distance(p: [f64; 3], major: f64, minor: f64) -> f64 {
+        let ring = (p[0] * p[0] + p[2] * p[2]).sqrt() - major;
+        (ring * ring + p[1] * p[1]).sqrt() - minor
+    }
+
+    /// Brute force in f64: march in small steps for a sign change of the
+    /// distance, then bisect it
+    fn reference(pos: Vec3, dir: Vec3, major: f32, minor: f32, t_max: f64) -> Option<f64> {
+        let (major, minor) = (major as f64, minor as f64);
+        let at = |t: f64| {
+            let p = [
+                pos.x as f64 + t * dir.x as f64,
+                pos.y as f64 + t * dir.y as f64,
+                pos.z as f64 + t * dir.z as f64,
+            ];
+            torus_distance(p, major, minor)
+        };
+
+        let steps = 200_000;
+        let mut a = 1e-3;
+        let mut fa = at(a);
+        for i in 1..=steps {
+            let b = 1e-3 + t_max * i as f64 / steps as f64;
+            let fb = at(b);
+            if (fa < 0.0) != (fb < 0.0) {
+                let (mut lo, mut hi) = (a, b);
+                for _ in 0..100 {
+                    let mid = 0.5 * (lo + hi);
+                    if (at(mid) < 0.0) == (fa < 0.0) {
+                        lo = mid;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                return Some(0.5 * (lo + hi));
+            }
+            a = b;
+            fa = fb;
+        }
+        None
+    }
+
+    #[test]
+    fn hits_outside_edge_along_x() {
+        let t = intersect_torus(Vec3::new(-5.0, 0.0, 0.0), Vec3::X, 1.0, 0.25, 0.001, 1e30);
+        assert!((t.unwrap() - 3.75).abs() < 1e-4);
+    }
+
+    #[test]
+    fn misses_through_hole() {
+        let t = intersect_torus(Vec3::new(0.0, 5.0, 0.0), -Vec3::Y, 1.0, 0.25, 0.001, 1e30);
+        assert_eq!(t, None);
+    }
+
+    #[test]
+    fn grazes_top_of_tube() {
+        // Tangent to the top of the tube, a double root
+        let t = intersect_torus(Vec3::new(-5.0, 0.25, 0.0), Vec3::X, 1.0, 0.25, 0.001, 1e30);
+        assert!((t.unwrap() - 4.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn respects_t_range() {
+        let pos = Vec3::new(-5.0, 0.0, 0.0);
+        // Skips the first two hits, landing on the far side's inner edge
+        let t = intersect_torus(pos, Vec3::X, 1.0, 0.25, 4.5, 1e30);
+        assert!((t.unwrap() - 5.75).abs() < 1e-4);
+        assert_eq!(intersect_torus(pos, Vec3::X, 1.0, 0.25, 0.001, 3.0), None);
+    }
+
+    #[test]
+    fn matches_reference() {
+        let mut rng = Rng(0x9e37_79b9);
+        let mut hits = 0;
+        for _ in 0..500 {
+            let major = 0.5 + rng.f32() * 2.0;
+            let minor = 0.05 + rng.f32() * 0.9 * major;
+            let pos = rng.centered_vec3(6.0);
+            let target = rng.centered_vec3(major + minor);
+            // Unnormalized directions, as rays are in object space
+            let dir = (target - pos) * (0.5 + rng.f32());
+
+            let t = intersect_torus(pos, dir, major, minor, 1e-3, 1e30);
+            let expected = reference(pos, dir, major, minor, 40.0);
+            match (t, expected) {
+                (Some(t), Some(expected)) => {
+                    let tolerance = 1e-3 * (1.0 + expected) / dir.length() as f64;
+                    assert!(
+                        (t as f64 - expected).abs() < tolerance.max(1e-3),
+                        "{pos} {dir} {major} {minor}: {t} != {expected}"
+                    );
+                    hits += 1;
+                }
+                (None, None) => {}
+                // Near-tangent rays can fall either way in f32
+                (t, expected) => {
+                    let grazing = t.or(expected.map(|t| t as f32)).unwrap();
+                    let p = pos + grazing * dir;
+                    let distance = torus_distance(
+                        [p.x as f64, p.y as f64, p.z as f64],
+                        major as f64,
+                        minor as f64,
+                    );
+                    assert!(
+                        distance.abs() < 1e-3,
+                        "{pos} {dir} {major} {minor}: {t:?} != {expected:?}"
+                    );
+                }
+            }
+        }
+        assert!(hits > 100);
+    }
+
+    #[test]
+    fn hits_lie_on_surface() {
+        let mut rng = Rng(0x1234_5678);
+        for _ in 0..500 {
+            let pos = rng.centered_vec3(4.0);
+            let dir = rng.centered_vec3(1.0);
+            if let Some(t) = intersect_torus(pos, dir, 1.5, 0.4, 1e-3, 1e30) {
+                let p = pos + t * dir;
+                let distance = torus_distance([p.x as f64, p.y as f64, p.z as f64], 1.5, 0.4);
+                assert!(distance.abs() < 1e-4, "{p} is {distance} from the surface");
+            }
+        }
+    }
+}
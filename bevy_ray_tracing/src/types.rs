@@ -1,14 +1,49 @@
+use crate::{arena::GpuArena, bvh::BvhNode};
+
 use bevy::{
     ecs::{
         system::Resource,
         world::{FromWorld, World},
     },
-    math::{Mat3, Vec3, Vec4},
+    math::{Affine3A, Mat3, Mat4, UVec4, Vec2, Vec3, Vec4},
     render::render_resource::{ShaderType, StorageBuffer},
 };
 
 pub const SHAPE_SPHERE: u32 = 0;
 pub const SHAPE_QUAD: u32 = 1;
+pub const SHAPE_MESH: u32 = 2;
+pub const SHAPE_CUBOID: u32 = 3;
+pub const SHAPE_DISK: u32 = 4;
+pub const SHAPE_TRIANGLE: u32 = 5;
+pub const SHAPE_PLANE: u32 = 6;
+pub const SHAPE_CYLINDER: u32 = 7;
+pub const SHAPE_CONE: u32 = 8;
+pub const SHAPE_CAPSULE: u32 = 9;
+pub const SHAPE_SDF: u32 = 10;
+pub const SHAPE_CSG: u32 = 11;
+pub const SHAPE_INSTANCES: u32 = 12;
+pub const SHAPE_HEIGHTFIELD: u32 = 13;
+pub const SHAPE_CURVES: u32 = 14;
+pub const SHAPE_TORUS: u32 = 15;
+pub const SHAPE_POINT_CLOUD: u32 = 16;
+
+pub const SKIN_WORKGROUP_SIZE: u32 = 64;
+pub const LBVH_WORKGROUP_SIZE: u32 = 256;
+pub const LBVH_RADIX_BITS: u32 = 4;
+pub const LBVH_RADIX_SIZE: u32 = 1 << LBVH_RADIX_BITS;
+
+pub const CURVE_RIBBON: u32 = 0;
+pub const CURVE_TUBE: u32 = 1;
+
+pub const POINT_SPHERE: u32 = 0;
+pub const POINT_DISC: u32 = 1;
+
+pub const NORMAL_MAP_FLIP_Y: u32 = 1;
+pub const NORMAL_MAP_TWO_COMPONENT: u32 = 2;
+
+pub const CSG_UNION: u32 = 0;
+pub const CSG_INTERSECTION: u32 = 1;
+pub const CSG_DIFFERENCE: u32 = 2;
 
 #[derive(Default, Clone, Copy, ShaderType)]
 pub struct RayTraceCamera {
@@ -16,6 +51,10 @@ pub struct RayTraceCamera {
     pub forward: Vec3,
     pub right: Vec3,
     pub up: Vec3,
+    pub previous_position: Vec3,
+    pub previous_forward: Vec3,
+    pub previous_right: Vec3,
+    pub previous_up: Vec3,
 }
 
 #[derive(Default, Clone, Copy, ShaderType)]
@@ -27,30 +66,431 @@ pub struct RayTraceMaterial {
     pub diffuse_transmission: f32,
     pub specular_transmission: f32,
     pub ior: f32,
+    pub reflectance: f32,
     pub double_sided: u32,
+    /// Layer of the texture array, `-1` for none
+    pub base_color_texture: i32,
+    pub normal_map_texture: i32,
+    pub normal_map_flags: u32,
+    pub metallic_roughness_texture: i32,
+    pub emissive_texture: i32,
+    pub occlusion_texture: i32,
+    pub uv_transform: Mat3,
 }
 
 #[derive(Default, Clone, Copy, ShaderType)]
 pub struct RayTraceObject {
     pub position: Vec3,
+    pub inverse_model: Mat3,
+    /// Interpolated towards `position` and `model` over the shutter when `moving`
+    pub previous_position: Vec3,
+    pub previous_model: Mat3,
+    pub model: Mat3,
+    pub moving: u32,
     pub shape_type: u32,
     pub shape_index: i32,
     pub material_index: i32,
 }
 
+impl RayTraceObject {
+    pub fn new(
+        transform: Affine3A,
+        previous: Affine3A,
+        shape_type: u32,
+        shape_index: i32,
+        material_index: i32,
+    ) -> Self {
+        Self {
+            position: transform.translation.into(),
+            inverse_model: Mat3::from(transform.matrix3).inverse(),
+            previous_position: previous.translation.into(),
+            previous_model: previous.matrix3.into(),
+            model: transform.matrix3.into(),
+            moving: (transform != previous) as u32,
+            shape_type,
+            shape_index,
+            material_index,
+        }
+    }
+}
+
+/// A shape's parameters as they're laid out in the shapes buffer, unpacked by
+/// its `load_` function in the shader
+pub trait PackedShape {
+    fn pack(&self, data: &mut Vec<UVec4>);
+}
+
+fn floats(v: Vec4) -> UVec4 {
+    UVec4::from_array(v.to_array().map(f32::to_bits))
+}
+
+/// `v`'s floats with the word at `index` replaced
+fn with_word(v: Vec3, index: usize, word: u32) -> UVec4 {
+    let mut words = floats(v.extend(0.0));
+    words[index] = word;
+    words
+}
+
 #[derive(Default, Clone, Copy, ShaderType)]
 pub struct RayTraceEmissive {
     pub index: i32,
 }
 
-#[derive(Default, Clone, Copy, ShaderType)]
+#[derive(Default, Clone, Copy)]
 pub struct RayTraceSphere {
     pub radius: f32,
 }
 
+impl PackedShape for RayTraceSphere {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.push(floats(Vec4::new(self.radius, 0.0, 0.0, 0.0)));
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct RayTraceCuboid {
+    pub half_size: Vec3,
+}
+
+impl PackedShape for RayTraceCuboid {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.push(floats(self.half_size.extend(0.0)));
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct RayTraceDisk {
+    pub radius: f32,
+}
+
+impl PackedShape for RayTraceDisk {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.push(floats(Vec4::new(self.radius, 0.0, 0.0, 0.0)));
+    }
+}
+
 #[derive(Default, Clone, Copy, ShaderType)]
-pub struct RayTraceQuad {
-    pub model: Mat3,
+pub struct RayTracePlane {
+    pub object_index: i32,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct RayTraceCylinder {
+    pub radius: f32,
+    pub half_height: f32,
+    pub capped: u32,
+}
+
+impl PackedShape for RayTraceCylinder {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.push(with_word(
+            Vec3::new(self.radius, self.half_height, 0.0),
+            2,
+            self.capped,
+        ));
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct RayTraceCone {
+    pub radius: f32,
+    pub height: f32,
+    pub capped: u32,
+}
+
+impl PackedShape for RayTraceCone {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.push(with_word(
+            Vec3::new(self.radius, self.height, 0.0),
+            2,
+            self.capped,
+        ));
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct RayTraceCapsule {
+    pub radius: f32,
+    pub half_length: f32,
+}
+
+impl PackedShape for RayTraceCapsule {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.push(floats(Vec4::new(self.radius, self.half_length, 0.0, 0.0)));
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct RayTraceTorus {
+    pub major_radius: f32,
+    pub minor_radius: f32,
+}
+
+impl PackedShape for RayTraceTorus {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.push(floats(Vec4::new(
+            self.major_radius,
+            self.minor_radius,
+            0.0,
+            0.0,
+        )));
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct RayTraceSdf {
+    pub half_size: Vec3,
+    pub function: u32,
+    pub params: [Vec4; 4],
+}
+
+impl PackedShape for RayTraceSdf {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.push(with_word(self.half_size, 3, self.function));
+        data.extend(self.params.map(floats));
+    }
+}
+
+/// Followed by its nodes in the shapes buffer
+#[derive(Default, Clone, Copy)]
+pub struct RayTraceCsg {
+    pub node_count: u32,
+}
+
+impl PackedShape for RayTraceCsg {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.push(UVec4::new(self.node_count, 0, 0, 0));
+    }
+}
+
+/// One of a CSG's shapes, `params` is the radius, half size or radius and half height
+#[derive(Default, Clone, Copy)]
+pub struct RayTraceCsgNode {
+    pub inverse_model: Mat3,
+    pub position: Vec3,
+    pub primitive_type: u32,
+    pub params: Vec4,
+    pub operation: u32,
+}
+
+impl PackedShape for RayTraceCsgNode {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        let [x, y, z] = self.inverse_model.to_cols_array_2d();
+        data.extend([
+            with_word(x.into(), 3, self.operation),
+            floats(Vec3::from(y).extend(0.0)),
+            floats(Vec3::from(z).extend(0.0)),
+            with_word(self.position, 3, self.primitive_type),
+            floats(self.params),
+        ]);
+    }
+}
+
+/// `width` by `depth` heights from `first_height` on, scaled by `size`
+#[derive(Default, Clone, Copy)]
+pub struct RayTraceHeightfield {
+    pub size: Vec3,
+    pub first_height: u32,
+    pub width: u32,
+    pub depth: u32,
+}
+
+impl PackedShape for RayTraceHeightfield {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.extend([
+            with_word(self.size, 3, self.first_height),
+            UVec4::new(self.width, self.depth, 0, 0),
+        ]);
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct RayTraceCurves {
+    pub first_node: u32,
+    pub first_segment: u32,
+    pub style: u32,
+}
+
+impl PackedShape for RayTraceCurves {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.push(UVec4::new(
+            self.first_node,
+            self.first_segment,
+            self.style,
+            0,
+        ));
+    }
+}
+
+/// End points with their radius in `w`
+#[derive(Default, Clone, Copy, ShaderType)]
+pub struct RayTraceCurveSegment {
+    pub a: Vec4,
+    pub b: Vec4,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct RayTracePointCloud {
+    pub first_node: u32,
+    pub first_point: u32,
+    pub style: u32,
+    pub point_count: u32,
+}
+
+impl PackedShape for RayTracePointCloud {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.push(UVec4::new(
+            self.first_node,
+            self.first_point,
+            self.style,
+            self.point_count,
+        ));
+    }
+}
+
+#[derive(Default, Clone, Copy, ShaderType)]
+pub struct RayTracePoint {
+    pub position: Vec3,
+    pub radius: f32,
+    pub color: Vec4,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct RayTraceMesh {
+    pub first_node: u32,
+    pub first_triangle: u32,
+    pub triangle_count: u32,
+}
+
+impl PackedShape for RayTraceMesh {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.push(UVec4::new(
+            self.first_node,
+            self.first_triangle,
+            self.triangle_count,
+            0,
+        ));
+    }
+}
+
+#[derive(Default, Clone, Copy, ShaderType)]
+pub struct RayTraceTriangle {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub c: Vec3,
+}
+
+impl PackedShape for RayTraceTriangle {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.extend([self.a, self.b, self.c].map(|corner| floats(corner.extend(0.0))));
+    }
+}
+
+/// A mesh triangle corner's attributes, three per entry of `triangles`
+#[derive(Default, Clone, Copy, ShaderType)]
+pub struct RayTraceVertex {
+    pub normal: Vec3,
+    pub uv: Vec2,
+    pub tangent: Vec4,
+    pub color: Vec4,
+}
+
+/// A mesh vertex in its bind pose, before morphing and skinning
+#[derive(Default, Clone, Copy, ShaderType)]
+pub struct RayTraceSkinVertex {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub tangent: Vec4,
+    pub joints: UVec4,
+    pub weights: Vec4,
+}
+
+/// One morph target's offsets for one vertex
+#[derive(Default, Clone, Copy, ShaderType)]
+pub struct RayTraceMorph {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub tangent: Vec3,
+}
+
+/// A mesh the skinning pass deforms into its own copy of a BLAS's
+/// triangles, then refits the BLAS's nodes over
+#[derive(Default, Clone, Copy, ShaderType)]
+pub struct RayTraceSkin {
+    pub first_vertex: u32,
+    pub vertex_count: u32,
+    pub first_corner: u32,
+    pub first_morph: u32,
+    pub morph_count: u32,
+    pub first_weight: u32,
+    /// Unskinned meshes have one joint, their transform
+    pub first_joint: u32,
+    pub skinned: u32,
+    pub first_refit: u32,
+    pub first_level: u32,
+    pub level_count: u32,
+    pub first_node: u32,
+    pub first_triangle: u32,
+    pub triangle_count: u32,
+}
+
+/// Copies of one shape, `shape_index` is the mesh and `params` the radius or half size otherwise
+#[derive(Default, Clone, Copy)]
+pub struct RayTraceInstanceSet {
+    pub first_node: u32,
+    pub first_instance: u32,
+    pub shape_type: u32,
+    pub shape_index: i32,
+    pub params: Vec4,
+}
+
+impl PackedShape for RayTraceInstanceSet {
+    fn pack(&self, data: &mut Vec<UVec4>) {
+        data.extend([
+            UVec4::new(
+                self.first_node,
+                self.first_instance,
+                self.shape_type,
+                self.shape_index as u32,
+            ),
+            floats(self.params),
+        ]);
+    }
+}
+
+#[derive(Default, Clone, Copy, ShaderType)]
+pub struct RayTraceInstance {
+    pub inverse_model: Mat3,
+    pub position: Vec3,
+}
+
+#[derive(Default, Clone, Copy, ShaderType)]
+pub struct RayTraceBvhNode {
+    pub min: Vec3,
+    pub first: u32,
+    pub max: Vec3,
+    pub count: u32,
+}
+
+impl From<&BvhNode> for RayTraceBvhNode {
+    fn from(node: &BvhNode) -> Self {
+        Self {
+            min: node.bounds.min,
+            first: node.first,
+            max: node.bounds.max,
+            count: node.count,
+        }
+    }
+}
+
+/// What the GPU builder needs besides the leaves, Morton codes are quantized
+/// within the bounds of the objects' centers
+#[derive(Default, Clone, Copy, ShaderType)]
+pub struct RayTraceLbvh {
+    pub centroid_min: Vec3,
+    /// Zero when the CPU builds the BVH
+    pub count: u32,
+    pub centroid_max: Vec3,
 }
 
 // Meta
@@ -67,15 +507,104 @@ pub struct RayTraceEmissives {
 }
 
 #[derive(ShaderType, Default)]
-pub struct RayTraceSpheres {
+pub struct RayTracePlanes {
+    #[size(runtime)]
+    pub data: Vec<RayTracePlane>,
+}
+
+/// Every shape's parameters, floats stored as their bits, so one buffer holds
+/// them all however many kinds of shape there are
+#[derive(ShaderType, Default)]
+pub struct RayTraceShapes {
+    #[size(runtime)]
+    pub data: Vec<UVec4>,
+}
+
+impl RayTraceShapes {
+    /// Appends the shape, returning the index its object's `shape_index` points at
+    pub fn push(&mut self, shape: &impl PackedShape) -> usize {
+        let index = self.data.len();
+        shape.pack(&mut self.data);
+        index
+    }
+}
+
+#[derive(ShaderType, Default)]
+pub struct RayTraceHeights {
+    #[size(runtime)]
+    pub data: Vec<f32>,
+}
+
+#[derive(ShaderType, Default)]
+pub struct RayTraceCurveSegments {
+    #[size(runtime)]
+    pub data: Vec<RayTraceCurveSegment>,
+}
+
+#[derive(ShaderType, Default)]
+pub struct RayTracePoints {
+    #[size(runtime)]
+    pub data: Vec<RayTracePoint>,
+}
+
+#[derive(ShaderType, Default)]
+pub struct RayTraceTriangles {
     #[size(runtime)]
-    pub data: Vec<RayTraceSphere>,
+    pub data: Vec<RayTraceTriangle>,
 }
 
 #[derive(ShaderType, Default)]
-pub struct RayTraceQuads {
+pub struct RayTraceVertices {
     #[size(runtime)]
-    pub data: Vec<RayTraceQuad>,
+    pub data: Vec<RayTraceVertex>,
+}
+
+#[derive(ShaderType, Default)]
+pub struct RayTraceSkins {
+    #[size(runtime)]
+    pub data: Vec<RayTraceSkin>,
+}
+
+#[derive(ShaderType, Default)]
+pub struct RayTraceSkinVertices {
+    #[size(runtime)]
+    pub data: Vec<RayTraceSkinVertex>,
+}
+
+#[derive(ShaderType, Default)]
+pub struct RayTraceMorphs {
+    #[size(runtime)]
+    pub data: Vec<RayTraceMorph>,
+}
+
+#[derive(ShaderType, Default)]
+pub struct RayTraceJoints {
+    #[size(runtime)]
+    pub data: Vec<Mat4>,
+}
+
+#[derive(ShaderType, Default)]
+pub struct RayTraceMorphWeights {
+    #[size(runtime)]
+    pub data: Vec<f32>,
+}
+
+#[derive(ShaderType, Default)]
+pub struct RayTraceSkinIndices {
+    #[size(runtime)]
+    pub data: Vec<u32>,
+}
+
+#[derive(ShaderType, Default)]
+pub struct RayTraceInstances {
+    #[size(runtime)]
+    pub data: Vec<RayTraceInstance>,
+}
+
+#[derive(ShaderType, Default)]
+pub struct RayTraceBvhNodes {
+    #[size(runtime)]
+    pub data: Vec<RayTraceBvhNode>,
 }
 
 #[derive(ShaderType, Default)]
@@ -84,14 +613,42 @@ pub struct RayTraceMaterials {
     pub data: Vec<RayTraceMaterial>,
 }
 
+/// Whether each layer of the texture array holds sRGB colors
+#[derive(ShaderType, Default)]
+pub struct RayTraceTextureLayers {
+    #[size(runtime)]
+    pub data: Vec<u32>,
+}
+
 #[derive(Resource)]
 pub struct GlobalRayTraceMeta {
     pub camera: StorageBuffer<RayTraceCamera>,
     pub objects: StorageBuffer<RayTraceObjects>,
+    pub bvh: StorageBuffer<RayTraceBvhNodes>,
+    pub lbvh: StorageBuffer<RayTraceLbvh>,
+    /// One per object, in object order
+    pub lbvh_leaves: StorageBuffer<RayTraceBvhNodes>,
     pub emissives: StorageBuffer<RayTraceEmissives>,
-    pub spheres: StorageBuffer<RayTraceSpheres>,
-    pub quads: StorageBuffer<RayTraceQuads>,
+    pub shapes: StorageBuffer<RayTraceShapes>,
+    pub planes: StorageBuffer<RayTracePlanes>,
+    /// Cached BLASes, written when they're built
+    pub blas_nodes: GpuArena<RayTraceBvhNode>,
+    pub triangles: GpuArena<RayTraceTriangle>,
+    pub vertices: GpuArena<RayTraceVertex>,
+    pub skins: StorageBuffer<RayTraceSkins>,
+    pub skin_vertices: StorageBuffer<RayTraceSkinVertices>,
+    pub skin_corners: StorageBuffer<RayTraceSkinIndices>,
+    pub morphs: StorageBuffer<RayTraceMorphs>,
+    pub joints: StorageBuffer<RayTraceJoints>,
+    pub morph_weights: StorageBuffer<RayTraceMorphWeights>,
+    pub skin_refit: StorageBuffer<RayTraceSkinIndices>,
+    pub skin_levels: StorageBuffer<RayTraceSkinIndices>,
+    pub instances: GpuArena<RayTraceInstance>,
+    pub heights: GpuArena<f32>,
+    pub curve_segments: GpuArena<RayTraceCurveSegment>,
+    pub points: GpuArena<RayTracePoint>,
     pub materials: StorageBuffer<RayTraceMaterials>,
+    pub texture_layers: StorageBuffer<RayTraceTextureLayers>,
 }
 
 impl FromWorld for GlobalRayTraceMeta {
@@ -99,10 +656,29 @@ impl FromWorld for GlobalRayTraceMeta {
         Self {
             camera: StorageBuffer::default(),
             objects: StorageBuffer::default(),
+            bvh: StorageBuffer::default(),
+            lbvh: StorageBuffer::default(),
+            lbvh_leaves: StorageBuffer::default(),
             emissives: StorageBuffer::default(),
-            spheres: StorageBuffer::default(),
-            quads: StorageBuffer::default(),
+            shapes: StorageBuffer::default(),
+            planes: StorageBuffer::default(),
+            blas_nodes: GpuArena::new("blas_nodes"),
+            triangles: GpuArena::new("triangles"),
+            vertices: GpuArena::new("vertices"),
+            skins: StorageBuffer::default(),
+            skin_vertices: StorageBuffer::default(),
+            skin_corners: StorageBuffer::default(),
+            morphs: StorageBuffer::default(),
+            joints: StorageBuffer::default(),
+            morph_weights: StorageBuffer::default(),
+            skin_refit: StorageBuffer::default(),
+            skin_levels: StorageBuffer::default(),
+            instances: GpuArena::new("instances"),
+            heights: GpuArena::new("heights"),
+            curve_segments: GpuArena::new("curve_segments"),
+            points: GpuArena::new("points"),
             materials: StorageBuffer::default(),
+            texture_layers: StorageBuffer::default(),
         }
     }
 }
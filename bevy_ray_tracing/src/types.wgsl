@@ -2,13 +2,71 @@
 
 const PI: f32 = 3.14159265359;
 const EPSILON: f32 = 1e-6;
+const T_MAX: f32 = 1e30;
 const SHAPE_SPHERE: u32 = 0;
 const SHAPE_QUAD: u32 = 1;
+const SHAPE_MESH: u32 = 2;
+const SHAPE_CUBOID: u32 = 3;
+const SHAPE_DISK: u32 = 4;
+const SHAPE_TRIANGLE: u32 = 5;
+const SHAPE_PLANE: u32 = 6;
+const SHAPE_CYLINDER: u32 = 7;
+const SHAPE_CONE: u32 = 8;
+const SHAPE_CAPSULE: u32 = 9;
+const SHAPE_SDF: u32 = 10;
+const SHAPE_CSG: u32 = 11;
+const SHAPE_INSTANCES: u32 = 12;
+const SHAPE_HEIGHTFIELD: u32 = 13;
+const SHAPE_CURVES: u32 = 14;
+const SHAPE_TORUS: u32 = 15;
+const SHAPE_POINT_CLOUD: u32 = 16;
+
+const CURVE_RIBBON: u32 = 0;
+const CURVE_TUBE: u32 = 1;
+const HAIR_R_PROBABILITY: f32 = 0.2;
+const HAIR_TT_PROBABILITY: f32 = 0.4;
+
+const POINT_SPHERE: u32 = 0;
+const POINT_DISC: u32 = 1;
+
+const NORMAL_MAP_FLIP_Y: u32 = 1;
+const NORMAL_MAP_TWO_COMPONENT: u32 = 2;
+
+const LOBE_DIFFUSE: u32 = 0;
+const LOBE_DIFFUSE_TRANSMISSION: u32 = 1;
+const LOBE_SPECULAR: u32 = 2;
+const LOBE_SPECULAR_TRANSMISSION: u32 = 3;
+
+const CSG_UNION: u32 = 0;
+const CSG_INTERSECTION: u32 = 1;
+const CSG_DIFFERENCE: u32 = 2;
+const CSG_MAX_SHAPES: u32 = 8;
+// Entries of the shapes buffer each node takes
+const CSG_NODE_SIZE: u32 = 5;
+const SDF_MAX_STEPS: i32 = 128;
+const SDF_HIT_DISTANCE: f32 = 1e-4;
+const POLY_MAX_ITERATIONS: i32 = 32;
+const POLY_TOLERANCE: f32 = 1e-6;
+const BVH_STACK_SIZE: u32 = 32;
+// Morton code trees have no depth limit, nesting up to 30 plus log2 of the
+// object count deep, so the top level gets room for any count that fits a u32
+const TOP_LEVEL_STACK_SIZE: u32 = 64;
+// Infinite planes are sampled as lights within this many times the shading
+// point's height above them
+const PLANE_LIGHT_FOOTPRINT: f32 = 8.0;
+// How far short of a sampled point on a light a shadow ray may stop
+const LIGHT_HIT_TOLERANCE: f32 = 1e-3;
+const SKIN_WORKGROUP_SIZE: u32 = 64;
+const LBVH_WORKGROUP_SIZE: u32 = 256;
+const LBVH_RADIX_BITS: u32 = 4;
+const LBVH_RADIX_SIZE: u32 = 16;
 
 struct RTSettings {
     bounces: i32,
     samples: i32,
     sky: vec3<f32>,
+    shutter: f32,
+    occlusion: f32,
 }
 
 struct Camera {
@@ -16,21 +74,193 @@ struct Camera {
     forward: vec3<f32>,
     right: vec3<f32>,
     up: vec3<f32>,
+    previous_position: vec3<f32>,
+    previous_forward: vec3<f32>,
+    previous_right: vec3<f32>,
+    previous_up: vec3<f32>,
 }
 
 struct Object {
     position: vec3<f32>,
+    inverse_model: mat3x3<f32>,
+    previous_position: vec3<f32>,
+    previous_model: mat3x3<f32>,
+    model: mat3x3<f32>,
+    moving: u32,
     shape_type: u32,
     shape_index: i32,
     material_index: i32,
 }
 
+// Where an object is at the ray's time
+struct ObjectTransform {
+    position: vec3<f32>,
+    model: mat3x3<f32>,
+    inverse_model: mat3x3<f32>,
+}
+
+struct BvhNode {
+    min: vec3<f32>,
+    first: u32,
+    max: vec3<f32>,
+    count: u32,
+}
+
+struct Lbvh {
+    centroid_min: vec3<f32>,
+    count: u32,
+    centroid_max: vec3<f32>,
+}
+
 struct Sphere {
     radius: f32,
 }
 
-struct Quad {
-    model: mat3x3<f32>,
+struct Cuboid {
+    half_size: vec3<f32>,
+}
+
+struct Disk {
+    radius: f32,
+}
+
+struct Plane {
+    object_index: i32,
+}
+
+struct Cylinder {
+    radius: f32,
+    half_height: f32,
+    capped: u32,
+}
+
+struct Cone {
+    radius: f32,
+    height: f32,
+    capped: u32,
+}
+
+struct Capsule {
+    radius: f32,
+    half_length: f32,
+}
+
+struct Torus {
+    major_radius: f32,
+    minor_radius: f32,
+}
+
+struct Sdf {
+    half_size: vec3<f32>,
+    function: u32,
+    params: array<vec4<f32>, 4>,
+}
+
+struct Csg {
+    first_node: u32,
+    node_count: u32,
+}
+
+struct CsgNode {
+    inverse_model: mat3x3<f32>,
+    position: vec3<f32>,
+    primitive_type: u32,
+    params: vec4<f32>,
+    operation: u32,
+}
+
+struct Heightfield {
+    size: vec3<f32>,
+    first_height: u32,
+    width: u32,
+    depth: u32,
+}
+
+struct Curves {
+    first_node: u32,
+    first_segment: u32,
+    style: u32,
+}
+
+struct CurveSegment {
+    a: vec4<f32>,
+    b: vec4<f32>,
+}
+
+struct PointCloud {
+    first_node: u32,
+    first_point: u32,
+    style: u32,
+    point_count: u32,
+}
+
+struct Point {
+    position: vec3<f32>,
+    radius: f32,
+    color: vec4<f32>,
+}
+
+struct Mesh {
+    first_node: u32,
+    first_triangle: u32,
+    triangle_count: u32,
+}
+
+struct Triangle {
+    a: vec3<f32>,
+    b: vec3<f32>,
+    c: vec3<f32>,
+}
+
+struct Vertex {
+    normal: vec3<f32>,
+    uv: vec2<f32>,
+    tangent: vec4<f32>,
+    color: vec4<f32>,
+}
+
+struct SkinVertex {
+    position: vec3<f32>,
+    normal: vec3<f32>,
+    tangent: vec4<f32>,
+    joints: vec4<u32>,
+    weights: vec4<f32>,
+}
+
+struct Morph {
+    position: vec3<f32>,
+    normal: vec3<f32>,
+    tangent: vec3<f32>,
+}
+
+struct Skin {
+    first_vertex: u32,
+    vertex_count: u32,
+    first_corner: u32,
+    first_morph: u32,
+    morph_count: u32,
+    first_weight: u32,
+    first_joint: u32,
+    skinned: u32,
+    first_refit: u32,
+    first_level: u32,
+    level_count: u32,
+    first_node: u32,
+    first_triangle: u32,
+    triangle_count: u32,
+}
+
+struct InstanceSet {
+    first_node: u32,
+    first_instance: u32,
+    shape_type: u32,
+    shape_index: i32,
+    params: vec4<f32>,
+}
+
+struct Instance {
+    inverse_model: mat3x3<f32>,
+    position: vec3<f32>,
 }
 
 struct Material {
@@ -41,24 +271,85 @@ struct Material {
     diffuse_transmission: f32,
     specular_transmission: f32,
     ior: f32,
+    reflectance: f32,
     double_sided: u32,
+    base_color_texture: i32,
+    normal_map_texture: i32,
+    normal_map_flags: u32,
+    metallic_roughness_texture: i32,
+    emissive_texture: i32,
+    occlusion_texture: i32,
+    uv_transform: mat3x3<f32>,
 }
 
 // ---- variables ----
 var<private> hit_record: HitRecord;
 var<private> rng_state: u32;
+// Direction along the strand at the last curve hit, for the hair BSDF
+var<private> hit_tangent: vec3<f32>;
+// The triangle or point of a mesh or point cloud last hit
+var<private> hit_primitive: u32;
+// Where in the shutter interval the current camera ray is, 1 is the end of the frame
+var<private> ray_time: f32;
 
 struct HitRecord {
     t: f32,
     p: vec3<f32>,
     n: vec3<f32>,
     uv: vec2<f32>,
+    // Zero when the surface has no tangents
+    tangent: vec4<f32>,
+    color: vec4<f32>,
     front_face: bool,
 
     object_index: i32,
     material_index: i32,
 }
 
+// A sampled direction with the BSDF times cosine towards it
+struct BsdfSample {
+    dir: vec3<f32>,
+    value: vec3<f32>,
+    pdf: f32,
+    lobe: u32,
+}
+
+struct BsdfLobes {
+    // Reflected head on
+    specular_color: vec3<f32>,
+    diffuse: vec3<f32>,
+    diffuse_transmission: vec3<f32>,
+    specular_transmission: vec3<f32>,
+    // Diffuse, diffuse transmission, specular then specular transmission
+    probabilities: vec4<f32>,
+}
+
+// A point on an emitter, with the density of picking it over area
+struct LightSample {
+    p: vec3<f32>,
+    n: vec3<f32>,
+    pdf: f32,
+}
+
+struct HairSample {
+    dir: vec3<f32>,
+    weight: vec3<f32>,
+}
+
+// Where a ray enters and leaves a solid, empty when `t_out <= t_in`
+struct CsgInterval {
+    t_in: f32,
+    t_out: f32,
+    n_in: vec3<f32>,
+    n_out: vec3<f32>,
+}
+
+// Sorted real roots of a polynomial of at most degree 4
+struct PolyRoots {
+    roots: array<f32, 4>,
+    count: i32,
+}
+
 struct Ray {
     pos: vec3<f32>,
     dir: vec3<f32>,
@@ -6,7 +6,7 @@ use bevy::{
         RenderPlugin,
     },
 };
-use bevy_ray_tracing::{RTQuad, RTSphere, RayTracingPlugin, RayTracingSettings};
+use bevy_ray_tracing::{RTMesh, RTQuad, RTSphere, RayTracingPlugin, RayTracingSettings};
 use shared::{DebugText, FreeCam, SharedPlugin};
 
 fn main() {
@@ -21,7 +21,7 @@ fn main() {
             }),
             ..default()
         }),
-        RayTracingPlugin,
+        RayTracingPlugin::default(),
         SharedPlugin,
     ));
 
@@ -29,7 +29,11 @@ fn main() {
     app.run();
 }
 
-fn setup(mut materials: ResMut<Assets<StandardMaterial>>, mut commands: Commands) {
+fn setup(
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut commands: Commands,
+) {
     commands.spawn((
         TextBundle {
             style: Style::default(),
@@ -55,6 +59,8 @@ fn setup(mut materials: ResMut<Assets<StandardMaterial>>, mut commands: Commands
             bounces: 10,
             samples: 1,
             sky: Vec3::ZERO,
+            shutter: 0.0,
+            occlusion: 0.0,
         },
         BloomSettings::default(),
         FreeCam::default(),
@@ -98,6 +104,17 @@ fn setup(mut materials: ResMut<Assets<StandardMaterial>>, mut commands: Commands
         },
     ));
 
+    commands.spawn((
+        RTMesh,
+        meshes.add(Cuboid::new(0.4, 0.8, 0.4)),
+        white.clone(),
+        TransformBundle {
+            local: Transform::from_xyz(-0.5, -0.6, -0.4)
+                .with_rotation(Quat::from_rotation_y(30f32.to_radians())),
+            ..default()
+        },
+    ));
+
     let scale = Vec3::ONE * 2.0;
     commands.spawn_batch([
         (
@@ -21,7 +21,7 @@ fn main() {
             }),
             ..default()
         }),
-        RayTracingPlugin,
+        RayTracingPlugin::default(),
         SharedPlugin,
     ));
 
@@ -55,6 +55,8 @@ fn setup(mut materials: ResMut<Assets<StandardMaterial>>, mut commands: Commands
             bounces: 10,
             samples: 2,
             sky: Vec3::splat(0.5),
+            shutter: 0.0,
+            occlusion: 0.0,
         },
         BloomSettings::default(),
         FreeCam::default(),